@@ -0,0 +1,114 @@
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use mercury_protocol::{internals::Segment, ReliableConnection, WireFormat};
+use std::{
+    hint::black_box,
+    io::{self, Write},
+};
+
+const PAYLOAD_SIZE: usize = 1_000;
+// Default send and receive window, in segments
+const WINDOW_SIZE: usize = 32;
+const MAX_SEGMENT_SIZE: usize = 1_376;
+const FRAGMENTS: usize = 16;
+
+// Collects every packet written by a connection.
+#[derive(Default)]
+struct PacketSink(Vec<Vec<u8>>);
+
+impl Write for PacketSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Returns the packets of a full window of messages of `size` bytes.
+fn window_of_packets(messages: usize, size: usize) -> Vec<Vec<u8>> {
+    let mut sender = ReliableConnection::new(1, PacketSink::default());
+    for _ in 0..messages {
+        sender.send(&vec![7; size]).unwrap();
+    }
+    sender.update(0).unwrap();
+    sender.output_mut().0.split_off(0)
+}
+
+fn segment_encode(c: &mut Criterion) {
+    let segment = Segment::new(BytesMut::from(vec![7; PAYLOAD_SIZE]));
+    let mut buffer = BytesMut::with_capacity(2 * PAYLOAD_SIZE);
+    let mut group = c.benchmark_group("segment");
+    group.throughput(Throughput::Bytes(PAYLOAD_SIZE as u64));
+    group.bench_function("encode", |b| {
+        b.iter(|| {
+            buffer.clear();
+            segment.encode(&mut buffer, WireFormat::Mercury);
+            black_box(&buffer);
+        })
+    });
+    group.finish();
+}
+
+fn connection(c: &mut Criterion) {
+    let mut group = c.benchmark_group("connection");
+    group.throughput(Throughput::Elements(WINDOW_SIZE as u64));
+
+    let packets = window_of_packets(WINDOW_SIZE, PAYLOAD_SIZE);
+    group.bench_function("input_full_window", |b| {
+        b.iter_batched(
+            || ReliableConnection::new(1, io::sink()),
+            |mut receiver| {
+                for packet in packets.iter() {
+                    receiver.input(packet).unwrap();
+                }
+                receiver
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.bench_function("flush_full_window", |b| {
+        b.iter_batched(
+            || {
+                let mut sender = ReliableConnection::new(1, io::sink());
+                for _ in 0..WINDOW_SIZE {
+                    sender.send(&[7; PAYLOAD_SIZE]).unwrap();
+                }
+                sender
+            },
+            |mut sender| {
+                sender.update(0).unwrap();
+                sender
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    let message_size = FRAGMENTS * MAX_SEGMENT_SIZE;
+    let packets = window_of_packets(1, message_size);
+    let mut buffer = vec![0; message_size];
+    group.throughput(Throughput::Bytes(message_size as u64));
+    group.bench_function("recv_reassembly", |b| {
+        b.iter_batched(
+            || {
+                let mut receiver = ReliableConnection::new(1, io::sink());
+                for packet in packets.iter() {
+                    receiver.input(packet).unwrap();
+                }
+                receiver
+            },
+            |mut receiver| {
+                assert_eq!(receiver.recv(&mut buffer).unwrap(), message_size);
+                receiver
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, segment_encode, connection);
+criterion_main!(benches);
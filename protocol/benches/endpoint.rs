@@ -0,0 +1,61 @@
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use mercury_protocol::{Datagram, Endpoint};
+
+const MESSAGES: usize = 64;
+const PAYLOAD_SIZE: usize = 64;
+
+fn send_messages(endpoint: &mut Endpoint) {
+    let payload = [7; PAYLOAD_SIZE];
+    for i in 0..MESSAGES {
+        let datagram = match i % 3 {
+            0 => Datagram::reliable_ordered(&payload, 0),
+            1 => Datagram::sequenced(&payload, 0),
+            _ => Datagram::unreliable(&payload),
+        };
+        endpoint.send(datagram).unwrap();
+    }
+}
+
+fn endpoint(c: &mut Criterion) {
+    let mut group = c.benchmark_group("endpoint");
+    group.throughput(Throughput::Elements(MESSAGES as u64));
+
+    group.bench_function("send_update", |b| {
+        b.iter_batched(
+            Endpoint::new,
+            |mut endpoint| {
+                send_messages(&mut endpoint);
+                endpoint.update(0).unwrap();
+                while endpoint.poll_packet().is_some() {}
+                endpoint
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    let mut sender = Endpoint::new();
+    send_messages(&mut sender);
+    sender.update(0).unwrap();
+    let mut packets: Vec<Bytes> = Vec::new();
+    while let Some(packet) = sender.poll_packet() {
+        packets.push(packet);
+    }
+    group.bench_function("receive", |b| {
+        b.iter_batched(
+            Endpoint::new,
+            |mut endpoint| {
+                for packet in packets.iter() {
+                    endpoint.receive(packet).unwrap();
+                }
+                while endpoint.poll_datagram().is_some() {}
+                endpoint
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, endpoint);
+criterion_main!(benches);
@@ -0,0 +1,60 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use mercury_protocol::internals::SequenceBuffer;
+
+const SIZE: u16 = 256;
+const ENTRIES: u16 = 1_024;
+
+fn sequence_buffer(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sequence_buffer");
+    group.throughput(Throughput::Elements(u64::from(ENTRIES)));
+
+    group.bench_function("insert_in_order", |b| {
+        b.iter_batched(
+            || SequenceBuffer::<u64>::new(SIZE),
+            |mut buffer| {
+                for sequence_num in 0..ENTRIES {
+                    buffer.insert(sequence_num, u64::from(sequence_num));
+                }
+                buffer
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    // Every insert skips ahead, clearing the entries in between with `remove_range`
+    group.bench_function("insert_with_gaps", |b| {
+        b.iter_batched(
+            || SequenceBuffer::<u64>::new(SIZE),
+            |mut buffer| {
+                for sequence_num in (0..ENTRIES).map(|i| i.wrapping_mul(37)) {
+                    buffer.insert(sequence_num, u64::from(sequence_num));
+                }
+                buffer
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.bench_function("remove", |b| {
+        b.iter_batched(
+            || {
+                let mut buffer = SequenceBuffer::<u64>::new(SIZE);
+                for sequence_num in 0..SIZE {
+                    buffer.insert(sequence_num, u64::from(sequence_num));
+                }
+                buffer
+            },
+            |mut buffer| {
+                for sequence_num in 0..SIZE {
+                    buffer.remove(sequence_num);
+                }
+                buffer
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, sequence_buffer);
+criterion_main!(benches);
@@ -0,0 +1,520 @@
+//! Load test: a server endpoint per simulated client exchanging a configurable mix of messages.
+//!
+//! Usage: mercury-bench [--clients N] [--duration SECS] [--transport loopback|udp]
+//!                      [--latency MS] [--loss PERCENT] [--mix KIND:SIZE:RATE,...]
+//!
+//! Every client and its server endpoint send the mix to each other, RATE messages of SIZE bytes
+//! per second for each entry. KIND is `reliable` (reliable ordered), `sequenced` or `unreliable`.
+//! The loopback transport runs on a simulated clock, delaying packets by the latency and dropping
+//! the given percentage of them. The udp transport goes through real localhost sockets in real
+//! time.
+
+use bytes::Bytes;
+use mercury_protocol::{DataPoint, Datagram, Endpoint, ReceivedDatagram, RttHistogram};
+use std::{
+    collections::{HashMap, VecDeque},
+    env,
+    io::ErrorKind,
+    net::{SocketAddr, UdpSocket},
+    process, thread,
+    time::{Duration, Instant},
+};
+
+const USAGE: &str = "Usage: mercury-bench [--clients N] [--duration SECS] \
+                     [--transport loopback|udp] [--latency MS] [--loss PERCENT] \
+                     [--mix KIND:SIZE:RATE,...]";
+const DEFAULT_MIX: &str = "reliable:256:30,sequenced:64:60,unreliable:32:60";
+const TICK_MS: u32 = 10;
+// kind: 1 byte, send time: 4 bytes
+const MESSAGE_HEADER_SIZE: usize = 5;
+const MAX_PACKET_SIZE: usize = 65_536;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Kind {
+    Reliable = 0,
+    Sequenced = 1,
+    Unreliable = 2,
+}
+
+const KINDS: [Kind; 3] = [Kind::Reliable, Kind::Sequenced, Kind::Unreliable];
+
+impl Kind {
+    fn parse(name: &str) -> Option<Kind> {
+        match name {
+            "reliable" => Some(Kind::Reliable),
+            "sequenced" => Some(Kind::Sequenced),
+            "unreliable" => Some(Kind::Unreliable),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Reliable => "reliable",
+            Kind::Sequenced => "sequenced",
+            Kind::Unreliable => "unreliable",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Flow {
+    kind: Kind,
+    size: usize,
+    rate: f64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Transport {
+    Loopback,
+    Udp,
+}
+
+struct Options {
+    clients: usize,
+    duration_ms: u32,
+    transport: Transport,
+    latency_ms: u32,
+    loss_percent: f64,
+    mix: Vec<Flow>,
+}
+
+// Where a packet is headed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Destination {
+    Server(usize),
+    Client(usize),
+}
+
+// One side of a connection along with the traffic it generates.
+struct Peer {
+    endpoint: Endpoint,
+    // Fractional number of messages of each flow due to be sent
+    credit: Vec<f64>,
+    payload: Vec<u8>,
+}
+
+struct Stats {
+    sent: [u64; 3],
+    received: [u64; 3],
+    bytes_received: u64,
+    latency: [RttHistogram; 3],
+    busy: Duration,
+}
+
+fn main() {
+    let options = parse_options().unwrap_or_else(|message| fail(&message));
+    println!(
+        "mercury-bench: {} clients over {:?} for {}s",
+        options.clients,
+        options.transport,
+        options.duration_ms / 1000
+    );
+    if options.transport == Transport::Loopback {
+        println!(
+            "  {}ms latency, {}% loss",
+            options.latency_ms, options.loss_percent
+        );
+    }
+
+    let mut network = match options.transport {
+        Transport::Loopback => Network::Loopback(Loopback::new(&options)),
+        Transport::Udp => Network::Udp(
+            Udp::new(options.clients)
+                .unwrap_or_else(|error| fail(&format!("Failed to open sockets: {}", error))),
+        ),
+    };
+    let mut clients: Vec<Peer> = (0..options.clients).map(|_| Peer::new(&options)).collect();
+    let mut servers: Vec<Peer> = (0..options.clients).map(|_| Peer::new(&options)).collect();
+    let mut stats = Stats::new();
+    // Processing time spent on each connection, on both of its sides
+    let mut busy = vec![Duration::from_secs(0); options.clients];
+
+    let start = Instant::now();
+    let mut now = 0;
+    while now < options.duration_ms {
+        for (destination, packet) in network.receive(now) {
+            let (peer, index) = match destination {
+                Destination::Server(index) => (&mut servers[index], index),
+                Destination::Client(index) => (&mut clients[index], index),
+            };
+            let started = Instant::now();
+            // Malformed packets can't come out of our own endpoints, lost ones just go missing
+            let _ = peer.endpoint.receive(&packet);
+            busy[index] += started.elapsed();
+        }
+
+        for index in 0..options.clients {
+            let started = Instant::now();
+            for (peer, destination) in [
+                (&mut clients[index], Destination::Server(index)),
+                (&mut servers[index], Destination::Client(index)),
+            ] {
+                peer.generate(&options.mix, now, &mut stats);
+                peer.endpoint
+                    .update(now)
+                    .unwrap_or_else(|error| fail(&format!("Update failed: {}", error)));
+                while let Some(packet) = peer.endpoint.poll_packet() {
+                    network.send(destination, now, packet);
+                }
+                peer.consume(now, &mut stats);
+            }
+            busy[index] += started.elapsed();
+        }
+
+        match options.transport {
+            Transport::Loopback => now += TICK_MS,
+            Transport::Udp => {
+                let elapsed = start.elapsed().as_millis() as u32;
+                if elapsed < now + TICK_MS {
+                    thread::sleep(Duration::from_millis(u64::from(now + TICK_MS - elapsed)));
+                }
+                now = start.elapsed().as_millis() as u32;
+            }
+        }
+    }
+
+    stats.busy = busy.iter().sum();
+    report(&options, &stats, now, &clients, &servers);
+}
+
+impl Peer {
+    fn new(options: &Options) -> Self {
+        let largest = options.mix.iter().map(|flow| flow.size).max().unwrap_or(0);
+        Self {
+            endpoint: Endpoint::new(),
+            credit: vec![0.0; options.mix.len()],
+            payload: vec![0; largest.max(MESSAGE_HEADER_SIZE)],
+        }
+    }
+
+    // Sends the messages of every flow which are due in this tick.
+    fn generate(&mut self, mix: &[Flow], now: u32, stats: &mut Stats) {
+        for (flow, credit) in mix.iter().zip(self.credit.iter_mut()) {
+            *credit += flow.rate * f64::from(TICK_MS) / 1000.0;
+            while *credit >= 1.0 {
+                *credit -= 1.0;
+                let payload = &mut self.payload[..flow.size.max(MESSAGE_HEADER_SIZE)];
+                payload[0] = flow.kind as u8;
+                payload[1..5].copy_from_slice(&now.to_be_bytes());
+                let datagram = match flow.kind {
+                    Kind::Reliable => Datagram::reliable_ordered(payload, 0),
+                    Kind::Sequenced => Datagram::sequenced(payload, 0),
+                    Kind::Unreliable => Datagram::unreliable(payload),
+                };
+                self.endpoint
+                    .send(datagram)
+                    .unwrap_or_else(|error| fail(&format!("Send failed: {}", error)));
+                stats.sent[flow.kind as usize] += 1;
+            }
+        }
+    }
+
+    // Records the latency of every message received.
+    fn consume(&mut self, now: u32, stats: &mut Stats) {
+        while let Some(datagram) = self.endpoint.poll_datagram() {
+            let payload = match datagram {
+                ReceivedDatagram::Full { payload } | ReceivedDatagram::Fragment { payload } => {
+                    payload
+                }
+            };
+            if payload.len() < MESSAGE_HEADER_SIZE {
+                continue;
+            }
+            let kind = KINDS[usize::from(payload[0]).min(KINDS.len() - 1)] as usize;
+            let sent_at = u32::from_be_bytes([payload[1], payload[2], payload[3], payload[4]]);
+            stats.received[kind] += 1;
+            stats.bytes_received += payload.len() as u64;
+            stats.latency[kind].record(now.saturating_sub(sent_at));
+        }
+    }
+}
+
+impl Stats {
+    fn new() -> Self {
+        Self {
+            sent: [0; 3],
+            received: [0; 3],
+            bytes_received: 0,
+            latency: [
+                RttHistogram::new(),
+                RttHistogram::new(),
+                RttHistogram::new(),
+            ],
+            busy: Duration::from_secs(0),
+        }
+    }
+}
+
+enum Network {
+    Loopback(Loopback),
+    Udp(Udp),
+}
+
+impl Network {
+    fn send(&mut self, destination: Destination, now: u32, packet: Bytes) {
+        match self {
+            Network::Loopback(loopback) => loopback.send(destination, now, packet),
+            Network::Udp(udp) => udp.send(destination, &packet),
+        }
+    }
+
+    fn receive(&mut self, now: u32) -> Vec<(Destination, Bytes)> {
+        match self {
+            Network::Loopback(loopback) => loopback.receive(now),
+            Network::Udp(udp) => udp.receive(),
+        }
+    }
+}
+
+// In-process link conditioner delaying and dropping packets.
+struct Loopback {
+    latency_ms: u32,
+    loss: f64,
+    rng: u64,
+    in_flight: VecDeque<(u32, Destination, Bytes)>,
+}
+
+impl Loopback {
+    fn new(options: &Options) -> Self {
+        Self {
+            latency_ms: options.latency_ms,
+            loss: options.loss_percent / 100.0,
+            rng: 0x2545_f491_4f6c_dd1d,
+            in_flight: VecDeque::new(),
+        }
+    }
+
+    fn send(&mut self, destination: Destination, now: u32, packet: Bytes) {
+        if self.random() < self.loss {
+            return;
+        }
+        self.in_flight
+            .push_back((now + self.latency_ms, destination, packet));
+    }
+
+    fn receive(&mut self, now: u32) -> Vec<(Destination, Bytes)> {
+        let mut delivered = Vec::new();
+        while let Some((arrival, _, _)) = self.in_flight.front() {
+            if *arrival > now {
+                break;
+            }
+            if let Some((_, destination, packet)) = self.in_flight.pop_front() {
+                delivered.push((destination, packet));
+            }
+        }
+        delivered
+    }
+
+    // xorshift64*, uniform in [0, 1)
+    fn random(&mut self) -> f64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        (self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+// Real sockets on localhost, a server socket shared by every connection and one per client.
+struct Udp {
+    server: UdpSocket,
+    clients: Vec<UdpSocket>,
+    client_indexes: HashMap<SocketAddr, usize>,
+    buffer: Vec<u8>,
+}
+
+impl Udp {
+    fn new(clients: usize) -> std::io::Result<Self> {
+        let server = UdpSocket::bind("127.0.0.1:0")?;
+        server.set_nonblocking(true)?;
+        let mut sockets = Vec::with_capacity(clients);
+        let mut client_indexes = HashMap::new();
+        for index in 0..clients {
+            let socket = UdpSocket::bind("127.0.0.1:0")?;
+            socket.set_nonblocking(true)?;
+            socket.connect(server.local_addr()?)?;
+            client_indexes.insert(socket.local_addr()?, index);
+            sockets.push(socket);
+        }
+        Ok(Self {
+            server,
+            clients: sockets,
+            client_indexes,
+            buffer: vec![0; MAX_PACKET_SIZE],
+        })
+    }
+
+    fn send(&mut self, destination: Destination, packet: &[u8]) {
+        // A full socket buffer is just another lost packet
+        let _ = match destination {
+            Destination::Server(index) => self.clients[index].send(packet),
+            Destination::Client(index) => match self.clients[index].local_addr() {
+                Ok(address) => self.server.send_to(packet, address),
+                Err(error) => Err(error),
+            },
+        };
+    }
+
+    fn receive(&mut self) -> Vec<(Destination, Bytes)> {
+        let mut received = Vec::new();
+        loop {
+            match self.server.recv_from(&mut self.buffer) {
+                Ok((len, address)) => {
+                    if let Some(&index) = self.client_indexes.get(&address) {
+                        let packet = Bytes::from(&self.buffer[..len]);
+                        received.push((Destination::Server(index), packet));
+                    }
+                }
+                Err(ref error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(error) => fail(&format!("Receive failed: {}", error)),
+            }
+        }
+        for (index, socket) in self.clients.iter().enumerate() {
+            loop {
+                match socket.recv(&mut self.buffer) {
+                    Ok(len) => {
+                        let packet = Bytes::from(&self.buffer[..len]);
+                        received.push((Destination::Client(index), packet));
+                    }
+                    Err(ref error) if error.kind() == ErrorKind::WouldBlock => break,
+                    Err(error) => fail(&format!("Receive failed: {}", error)),
+                }
+            }
+        }
+        received
+    }
+}
+
+fn report(options: &Options, stats: &Stats, elapsed_ms: u32, clients: &[Peer], servers: &[Peer]) {
+    let seconds = f64::from(elapsed_ms.max(1)) / 1000.0;
+    println!();
+    println!(
+        "{:<12}{:>10}{:>10}{:>10}{:>8}{:>8}{:>8}{:>8}",
+        "kind", "sent", "received", "delivery", "p50", "p95", "p99", "max"
+    );
+    for kind in KINDS.iter() {
+        let index = *kind as usize;
+        if stats.sent[index] == 0 {
+            continue;
+        }
+        let delivery = stats.received[index] as f64 / stats.sent[index] as f64 * 100.0;
+        let latency = stats.latency[index].statistics();
+        let (p50, p95, p99, max) = latency
+            .map(|latency| (latency.p50, latency.p95, latency.p99, latency.max))
+            .unwrap_or_default();
+        println!(
+            "{:<12}{:>10}{:>10}{:>9.1}%{:>6}ms{:>6}ms{:>6}ms{:>6}ms",
+            kind.name(),
+            stats.sent[index],
+            stats.received[index],
+            delivery,
+            p50,
+            p95,
+            p99,
+            max
+        );
+    }
+    println!();
+
+    let received: u64 = stats.received.iter().sum();
+    println!(
+        "throughput: {:.0} messages/s, {:.1} kbps of payload",
+        received as f64 / seconds,
+        stats.bytes_received as f64 * 8.0 / 1000.0 / seconds
+    );
+
+    let (sent, retransmitted) = clients
+        .iter()
+        .chain(servers.iter())
+        .map(|peer| peer.endpoint.metrics())
+        .fold((0, 0), |(sent, retransmitted), metrics| {
+            (
+                sent + metrics.get_count(DataPoint::SegmentsSent),
+                retransmitted + metrics.get_count(DataPoint::SegmentsRetransmitted),
+            )
+        });
+    if sent > 0 {
+        println!(
+            "retransmission ratio: {:.2}% of {} reliable segments",
+            retransmitted as f64 / sent as f64 * 100.0,
+            sent
+        );
+    }
+
+    // Processing time per second of the run, so loopback runs faster than real time still tell
+    // how much of a core a connection needs.
+    let per_connection = stats.busy.as_secs_f64() / options.clients as f64 / seconds;
+    println!(
+        "cpu per connection: {:.1}us per second ({:.3}% of a core)",
+        per_connection * 1_000_000.0,
+        per_connection * 100.0
+    );
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut options = Options {
+        clients: 8,
+        duration_ms: 5_000,
+        transport: Transport::Loopback,
+        latency_ms: 20,
+        loss_percent: 0.0,
+        mix: parse_mix(DEFAULT_MIX)?,
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            println!("{}", USAGE);
+            process::exit(0);
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("Missing value for {}\n{}", arg, USAGE))?;
+        let invalid = || format!("Invalid value for {}: {}", arg, value);
+        match arg.as_str() {
+            "--clients" => options.clients = value.parse().map_err(|_| invalid())?,
+            "--duration" => {
+                let seconds: u32 = value.parse().map_err(|_| invalid())?;
+                options.duration_ms = seconds * 1000;
+            }
+            "--transport" => {
+                options.transport = match value.as_str() {
+                    "loopback" => Transport::Loopback,
+                    "udp" => Transport::Udp,
+                    _ => return Err(invalid()),
+                }
+            }
+            "--latency" => options.latency_ms = value.parse().map_err(|_| invalid())?,
+            "--loss" => options.loss_percent = value.parse().map_err(|_| invalid())?,
+            "--mix" => options.mix = parse_mix(&value)?,
+            _ => return Err(format!("Unknown option {}\n{}", arg, USAGE)),
+        }
+    }
+    Ok(options)
+}
+
+fn parse_mix(mix: &str) -> Result<Vec<Flow>, String> {
+    mix.split(',')
+        .map(|entry| {
+            let invalid = || format!("Invalid mix entry {}, expected KIND:SIZE:RATE", entry);
+            let mut parts = entry.split(':');
+            let kind = parts.next().and_then(Kind::parse).ok_or_else(invalid)?;
+            let size = parts
+                .next()
+                .and_then(|size| size.parse().ok())
+                .ok_or_else(invalid)?;
+            let rate = parts
+                .next()
+                .and_then(|rate| rate.parse().ok())
+                .ok_or_else(invalid)?;
+            if parts.next().is_some() {
+                return Err(invalid());
+            }
+            Ok(Flow { kind, size, rate })
+        })
+        .collect()
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(2);
+}
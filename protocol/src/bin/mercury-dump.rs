@@ -0,0 +1,149 @@
+//! Pretty-prints the headers of raw packets or of a capture file.
+//!
+//! Usage: mercury-dump [--endpoint] [--kcp] [--hex] [FILE]
+//!
+//! Reads FILE, or stdin when it's missing or `-`. A capture written by `CaptureWriter` is
+//! recognized by its header, which also tells its wire format. Anything else is a single binary
+//! packet, or one packet per line of hex with `--hex`. Raw packets are decoded as segments of a
+//! `ReliableConnection` unless `--endpoint` says they were sent by an `Endpoint`, in the mercury
+//! wire format unless `--kcp` says they use the one of the C implementation of KCP.
+
+use mercury_protocol::{dump_packet, CaptureKind, CaptureLayer, CaptureReader, WireFormat};
+use std::{
+    env, fs,
+    io::{self, Cursor, Read},
+    process,
+};
+
+const USAGE: &str = "Usage: mercury-dump [--endpoint] [--kcp] [--hex] [FILE]";
+
+fn main() {
+    let mut layer = CaptureLayer::Connection;
+    let mut format = WireFormat::Mercury;
+    let mut hex = false;
+    let mut path = None;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--endpoint" => layer = CaptureLayer::Endpoint,
+            "--kcp" => format = WireFormat::Kcp,
+            "--hex" => hex = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ if path.is_none() => path = Some(arg),
+            _ => fail(USAGE),
+        }
+    }
+
+    let input = match path.as_deref() {
+        None | Some("-") => {
+            let mut input = Vec::new();
+            io::stdin().read_to_end(&mut input).map(|_| input)
+        }
+        Some(path) => fs::read(path),
+    };
+    let input = input.unwrap_or_else(|error| fail(&format!("Failed to read input: {}", error)));
+
+    if let Ok(reader) = CaptureReader::new(Cursor::new(&input)) {
+        dump_capture(reader);
+    } else if hex {
+        for (line, text) in String::from_utf8_lossy(&input).lines().enumerate() {
+            if text.trim().is_empty() {
+                continue;
+            }
+            match parse_hex(text) {
+                Some(packet) => {
+                    println!("packet {} ({} bytes)", line + 1, packet.len());
+                    print_packet(&packet, layer, format);
+                }
+                None => println!("! line {} isn't valid hex", line + 1),
+            }
+        }
+    } else {
+        println!("packet ({} bytes)", input.len());
+        print_packet(&input, layer, format);
+    }
+}
+
+fn dump_capture<R: Read>(reader: CaptureReader<R>) {
+    let layer = reader.layer();
+    let format = reader.config().wire_format();
+    println!(
+        "capture of {:?} packets in the {:?} wire format",
+        layer, format
+    );
+    for record in reader {
+        let record = match record {
+            Ok(record) => record,
+            Err(error) => {
+                println!("! capture ends with a broken record: {}", error);
+                return;
+            }
+        };
+        let timestamp = record.timestamp;
+        match record.kind {
+            CaptureKind::Update => println!("{:>10}ms update", timestamp),
+            CaptureKind::Send | CaptureKind::SendWithTtl => {
+                println!("{:>10}ms send ({} bytes)", timestamp, record.data.len())
+            }
+            CaptureKind::SendLarge => {
+                println!(
+                    "{:>10}ms send large ({} bytes)",
+                    timestamp,
+                    record.data.len()
+                )
+            }
+            CaptureKind::Recv => println!("{:>10}ms recv", timestamp),
+            CaptureKind::Read => {
+                println!("{:>10}ms read ({} bytes)", timestamp, record.data.len())
+            }
+            CaptureKind::Inbound | CaptureKind::Outbound => {
+                let direction = if record.kind == CaptureKind::Inbound {
+                    "in"
+                } else {
+                    "out"
+                };
+                println!(
+                    "{:>10}ms {} ({} bytes)",
+                    timestamp,
+                    direction,
+                    record.data.len()
+                );
+                print_packet(&record.data, layer, format);
+            }
+            // Changes of the settings only matter to a replay
+            kind => println!("{:>10}ms {:?}", timestamp, kind),
+        }
+    }
+}
+
+fn print_packet(packet: &[u8], layer: CaptureLayer, format: WireFormat) {
+    let mut out = String::new();
+    dump_packet(&mut out, packet, layer, format).expect("writing to a String can't fail");
+    for line in out.lines() {
+        println!("  {}", line);
+    }
+}
+
+fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    let digits = text
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| c.to_digit(16).map(|digit| digit as u8))
+        .collect::<Option<Vec<u8>>>()?;
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    Some(
+        digits
+            .chunks(2)
+            .map(|pair| pair[0] << 4 | pair[1])
+            .collect(),
+    )
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(2);
+}
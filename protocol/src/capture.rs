@@ -0,0 +1,439 @@
+use crate::{
+    config::{Config, WireFormat},
+    MAX_TRANSFER_SIZE,
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use log::debug;
+use std::io::{self, Cursor, Read, Write};
+
+const MAGIC: &[u8; 4] = b"MRCP";
+const VERSION: u8 = 2;
+// mtu, send and recv window: 4 bytes each, max recv window: 1 + 4 bytes, nodelay: 1 byte,
+// interval, fast resend: 4 bytes each, congestion control: 1 byte, min and max rto, dead link,
+// probe init and limit, keepalive interval, idle timeout: 4 bytes each, wire format: 1 byte,
+// max transfer size: 8 bytes, bandwidth smoothing factor: 4 bytes
+const CONFIG_SIZE: usize = 68;
+// magic: 4 bytes, version: 1 byte, layer: 1 byte, followed by the config
+const FILE_HEADER_SIZE: usize = 6;
+// kind: 1 byte, timestamp: 4 bytes, len: 4 bytes
+const RECORD_HEADER_SIZE: usize = 9;
+// Largest record data, which holds the payload of a transfer up to the default max_transfer_size
+const MAX_RECORD_SIZE: usize = MAX_TRANSFER_SIZE;
+
+/// The layer whose packets a capture holds, which tells how to decode them.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CaptureLayer {
+    /// Segments read and written by a `ReliableConnection`.
+    Connection = 0,
+    /// Packets passed to `Endpoint::receive` and returned by `Endpoint::poll_packet`.
+    Endpoint = 1,
+}
+
+/// What a record of a capture stands for.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CaptureKind {
+    /// `update` was called, the record has no data.
+    Update = 0,
+    /// A packet was received.
+    Inbound = 1,
+    /// A packet was sent.
+    Outbound = 2,
+    /// A payload was passed to `send`.
+    Send = 3,
+    /// A payload was passed to `send_with_ttl`, the data starts with the ttl (u32).
+    SendWithTtl = 4,
+    /// The next message was taken by `recv`, `recv_bytes` or `recv_fragments`, the record has no
+    /// data.
+    Recv = 5,
+    /// Bytes were taken by `Read::read` in streaming mode, the data holds them.
+    Read = 6,
+    /// A payload was passed to `send_large` or `send_large_with_progress`.
+    SendLarge = 7,
+    /// A transfer was cancelled, the data is its id (u32).
+    CancelTransfer = 8,
+    /// The MTU was changed by `set_mtu`, the data is the new one (u32).
+    SetMtu = 9,
+    /// The window sizes were changed by `set_window_sizes`, the data is the send and the receive
+    /// window (u32 each).
+    SetWindowSizes = 10,
+    /// Auto-tuning of the receive window was changed, the data is a flag telling whether it's
+    /// enabled (u8) followed by the maximum size (u32).
+    SetRecvWindowAutoTuning = 11,
+    /// The keepalive interval was changed, the data is the new one (u32).
+    SetKeepalive = 12,
+    /// The idle timeout was changed, the data is the new one (u32).
+    SetIdleTimeout = 13,
+    /// Streaming mode was switched on or off, the data is a flag (u8).
+    SetStreamingMode = 14,
+    /// The deprecated `nodelay` was called, the data is its nodelay, interval and resend
+    /// arguments (i32 each) and a congestion control flag (u8).
+    Nodelay = 15,
+    /// The connection was reconfigured, the data holds the settings of the reliability layer in
+    /// the layout of the file header.
+    Reconfigure = 16,
+}
+
+impl CaptureKind {
+    fn from_u8(kind: u8) -> Option<Self> {
+        let kind = match kind {
+            0 => CaptureKind::Update,
+            1 => CaptureKind::Inbound,
+            2 => CaptureKind::Outbound,
+            3 => CaptureKind::Send,
+            4 => CaptureKind::SendWithTtl,
+            5 => CaptureKind::Recv,
+            6 => CaptureKind::Read,
+            7 => CaptureKind::SendLarge,
+            8 => CaptureKind::CancelTransfer,
+            9 => CaptureKind::SetMtu,
+            10 => CaptureKind::SetWindowSizes,
+            11 => CaptureKind::SetRecvWindowAutoTuning,
+            12 => CaptureKind::SetKeepalive,
+            13 => CaptureKind::SetIdleTimeout,
+            14 => CaptureKind::SetStreamingMode,
+            15 => CaptureKind::Nodelay,
+            16 => CaptureKind::Reconfigure,
+            _ => return None,
+        };
+        Some(kind)
+    }
+}
+
+/// A single record of a capture. The timestamp is the time of the last `update` when the record
+/// was taken.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CaptureRecord {
+    pub timestamp: u32,
+    pub kind: CaptureKind,
+    pub data: Bytes,
+}
+
+/// Receives everything a connection or endpoint does which is needed to replay it, see
+/// `ReliableConnection::set_recorder`.
+pub trait PacketRecorder: Send {
+    fn record(&mut self, timestamp: u32, kind: CaptureKind, data: &[u8]) -> io::Result<()>;
+}
+
+/// Writes a capture file, which can be read back with `CaptureReader`.
+///
+/// The file header holds the settings of the reliability layer from `config`, the ones the
+/// captured connection (or the connection of the captured endpoint) starts with, so that `Replay`
+/// can start from them too. Settings changed later on are captured as records.
+pub struct CaptureWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    /// Writes the file header for packets of `layer` sent with `config` to `writer`.
+    pub fn new(mut writer: W, layer: CaptureLayer, config: &Config) -> io::Result<Self> {
+        let mut header = BytesMut::with_capacity(FILE_HEADER_SIZE + CONFIG_SIZE);
+        header.put_slice(MAGIC);
+        header.put_u8(VERSION);
+        header.put_u8(layer as u8);
+        encode_config(&mut header, config);
+        writer.write_all(&header)?;
+        Ok(Self { writer })
+    }
+
+    /// Flushes and returns the underlying writer.
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write + Send> PacketRecorder for CaptureWriter<W> {
+    /// Fails with `InvalidInput` if `data` is larger than 64 MiB, which `CaptureReader` wouldn't
+    /// read back.
+    fn record(&mut self, timestamp: u32, kind: CaptureKind, data: &[u8]) -> io::Result<()> {
+        if data.len() > MAX_RECORD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "capture record too large",
+            ));
+        }
+        let mut header = BytesMut::with_capacity(RECORD_HEADER_SIZE);
+        header.put_u8(kind as u8);
+        header.put_u32_be(timestamp);
+        header.put_u32_be(data.len() as u32);
+        self.writer.write_all(&header)?;
+        self.writer.write_all(data)
+    }
+}
+
+/// Reads the records of a capture file written by `CaptureWriter`.
+pub struct CaptureReader<R: Read> {
+    reader: R,
+    layer: CaptureLayer,
+    config: Config,
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Reads the file header, failing with `InvalidData` if `reader` doesn't hold a capture.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0; FILE_HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != MAGIC {
+            return Err(invalid_data("not a capture file"));
+        }
+        if header[4] != VERSION {
+            return Err(invalid_data("unsupported capture version"));
+        }
+        let layer = match header[5] {
+            0 => CaptureLayer::Connection,
+            1 => CaptureLayer::Endpoint,
+            _ => return Err(invalid_data("unknown capture layer")),
+        };
+        let mut config = [0; CONFIG_SIZE];
+        reader.read_exact(&mut config)?;
+        let config = decode_config(&config)
+            .ok_or_else(|| invalid_data("invalid settings in capture header"))?;
+        Ok(Self {
+            reader,
+            layer,
+            config,
+        })
+    }
+
+    pub fn layer(&self) -> CaptureLayer {
+        self.layer
+    }
+
+    /// Returns the settings of the reliability layer stored in the file header. Settings which
+    /// only matter to an `Endpoint`, such as its streams, aren't stored and keep their default.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<CaptureRecord>> {
+        let mut header = [0; RECORD_HEADER_SIZE];
+        // A capture may end at any record boundary
+        let read = self.reader.read(&mut header[..1])?;
+        if read == 0 {
+            return Ok(None);
+        }
+        self.reader.read_exact(&mut header[1..])?;
+
+        let kind = CaptureKind::from_u8(header[0])
+            .ok_or_else(|| invalid_data("unknown capture record kind"))?;
+        let timestamp = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+        let len = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) as usize;
+        if len > MAX_RECORD_SIZE {
+            return Err(invalid_data("capture record too large"));
+        }
+        // The buffer grows as the data is read, so a broken length doesn't allocate it up front
+        let mut data = Vec::new();
+        (&mut self.reader).take(len as u64).read_to_end(&mut data)?;
+        if data.len() < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(Some(CaptureRecord {
+            timestamp,
+            kind,
+            data: Bytes::from(data),
+        }))
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = io::Result<CaptureRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+// Hands records to the recorder of a connection or endpoint, if one is set. A recorder which fails
+// is dropped so that a full disk doesn't take the connection down with it.
+pub(crate) struct Recorder {
+    recorder: Option<Box<dyn PacketRecorder>>,
+}
+
+impl Recorder {
+    pub(crate) fn new() -> Self {
+        Self { recorder: None }
+    }
+
+    pub(crate) fn set(&mut self, recorder: Option<Box<dyn PacketRecorder>>) {
+        self.recorder = recorder;
+    }
+
+    pub(crate) fn record(&mut self, timestamp: u32, kind: CaptureKind, data: &[u8]) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(error) = recorder.record(timestamp, kind, data) {
+                debug!("Stopped recording after error: {}", error);
+                self.recorder = None;
+            }
+        }
+    }
+}
+
+// Writes the settings of the reliability layer, `CONFIG_SIZE` bytes.
+pub(crate) fn encode_config(buf: &mut BytesMut, config: &Config) {
+    buf.reserve(CONFIG_SIZE);
+    buf.put_u32_be(config.mtu() as u32);
+    buf.put_u32_be(config.send_window_size() as u32);
+    buf.put_u32_be(config.recv_window_size() as u32);
+    buf.put_u8(config.max_recv_window_size().is_some() as u8);
+    buf.put_u32_be(config.max_recv_window_size().unwrap_or(0) as u32);
+    buf.put_u8(config.nodelay() as u8);
+    buf.put_u32_be(config.interval());
+    buf.put_u32_be(config.fast_resend());
+    buf.put_u8(config.congestion_control() as u8);
+    buf.put_u32_be(config.min_rto());
+    buf.put_u32_be(config.max_rto());
+    buf.put_u32_be(config.dead_link());
+    buf.put_u32_be(config.probe_init());
+    buf.put_u32_be(config.probe_limit());
+    buf.put_u32_be(config.keepalive_interval());
+    buf.put_u32_be(config.idle_timeout());
+    buf.put_u8(config.wire_format() as u8);
+    buf.put_u64_be(config.max_transfer_size() as u64);
+    buf.put_u32_be(config.bandwidth_smoothing_factor().to_bits());
+}
+
+// Reads the settings written by `encode_config`, without validating them.
+pub(crate) fn decode_config(buf: &[u8]) -> Option<Config> {
+    if buf.len() < CONFIG_SIZE {
+        return None;
+    }
+    let mut cursor = Cursor::new(buf);
+    let mtu = cursor.get_u32_be() as usize;
+    let send_window_size = cursor.get_u32_be() as usize;
+    let recv_window_size = cursor.get_u32_be() as usize;
+    let auto_tuning = cursor.get_u8() != 0;
+    let max_recv_window_size = cursor.get_u32_be() as usize;
+    let nodelay = cursor.get_u8() != 0;
+    let interval = cursor.get_u32_be();
+    let fast_resend = cursor.get_u32_be();
+    let congestion_control = cursor.get_u8() != 0;
+    let min_rto = cursor.get_u32_be();
+    let max_rto = cursor.get_u32_be();
+    let dead_link = cursor.get_u32_be();
+    let probe_init = cursor.get_u32_be();
+    let probe_limit = cursor.get_u32_be();
+    let keepalive_interval = cursor.get_u32_be();
+    let idle_timeout = cursor.get_u32_be();
+    let wire_format = match cursor.get_u8() {
+        0 => WireFormat::Mercury,
+        1 => WireFormat::Kcp,
+        _ => return None,
+    };
+    let max_transfer_size = cursor.get_u64_be() as usize;
+    let bandwidth_smoothing_factor = f32::from_bits(cursor.get_u32_be());
+
+    Some(
+        Config::default()
+            .with_mtu(mtu)
+            .with_window_sizes(send_window_size, recv_window_size)
+            .with_recv_window_auto_tuning(Some(max_recv_window_size).filter(|_| auto_tuning))
+            .with_nodelay(nodelay)
+            .with_interval(interval)
+            .with_fast_resend(fast_resend)
+            .with_congestion_control(congestion_control)
+            .with_rto_bounds(min_rto, max_rto)
+            .with_dead_link(dead_link)
+            .with_window_probe(probe_init, probe_limit)
+            .with_keepalive_interval(keepalive_interval)
+            .with_idle_timeout(idle_timeout)
+            .with_wire_format(wire_format)
+            .with_max_transfer_size(max_transfer_size)
+            .with_bandwidth_smoothing_factor(bandwidth_smoothing_factor),
+    )
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        CaptureKind, CaptureLayer, CaptureReader, CaptureRecord, CaptureWriter, MAX_RECORD_SIZE,
+    };
+    use crate::{capture::PacketRecorder, Config, Preset, WireFormat};
+    use bytes::Bytes;
+    use std::io::{self, Cursor};
+
+    #[test]
+    fn test_round_trip() {
+        let config = Config::default()
+            .with_preset(Preset::Turbo)
+            .with_mtu(500)
+            .with_window_sizes(64, 128)
+            .with_recv_window_auto_tuning(Some(1024))
+            .with_wire_format(WireFormat::Kcp)
+            .with_keepalive_interval(0)
+            .with_idle_timeout(0);
+        let mut writer = CaptureWriter::new(Vec::new(), CaptureLayer::Endpoint, &config).unwrap();
+        writer.record(10, CaptureKind::Update, &[]).unwrap();
+        writer.record(10, CaptureKind::Inbound, b"packet").unwrap();
+        let file = writer.into_inner().unwrap();
+
+        let reader = CaptureReader::new(Cursor::new(file)).unwrap();
+        assert_eq!(reader.layer(), CaptureLayer::Endpoint);
+        let stored = reader.config();
+        assert!(stored.validate().is_ok());
+        assert_eq!(stored.mtu(), 500);
+        assert_eq!(stored.send_window_size(), 64);
+        assert_eq!(stored.recv_window_size(), 128);
+        assert_eq!(stored.max_recv_window_size(), Some(1024));
+        assert!(stored.nodelay());
+        assert_eq!(stored.interval(), 10);
+        assert_eq!(stored.min_rto(), config.min_rto());
+        assert_eq!(stored.idle_timeout(), 0);
+        assert_eq!(stored.wire_format(), WireFormat::Kcp);
+        let records: Vec<CaptureRecord> = reader.map(Result::unwrap).collect();
+        assert_eq!(
+            records,
+            vec![
+                CaptureRecord {
+                    timestamp: 10,
+                    kind: CaptureKind::Update,
+                    data: Bytes::new(),
+                },
+                CaptureRecord {
+                    timestamp: 10,
+                    kind: CaptureKind::Inbound,
+                    data: Bytes::from_static(b"packet"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rejects_bad_files() {
+        let error = CaptureReader::new(Cursor::new(b"PCAP\x01\x00".to_vec()))
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let mut writer =
+            CaptureWriter::new(Vec::new(), CaptureLayer::Connection, &Config::default()).unwrap();
+        writer.record(0, CaptureKind::Outbound, b"packet").unwrap();
+        let mut file = writer.into_inner().unwrap();
+        file.pop();
+        let mut reader = CaptureReader::new(Cursor::new(file)).unwrap();
+        let error = reader.next().unwrap().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn test_rejects_records_too_large() {
+        let mut writer =
+            CaptureWriter::new(Vec::new(), CaptureLayer::Connection, &Config::default()).unwrap();
+        let error = writer
+            .record(0, CaptureKind::SendLarge, &vec![0; MAX_RECORD_SIZE + 1])
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        let mut file = writer.into_inner().unwrap();
+        file.push(CaptureKind::Inbound as u8);
+        file.extend_from_slice(&0u32.to_be_bytes());
+        file.extend_from_slice(&(MAX_RECORD_SIZE as u32 + 1).to_be_bytes());
+        let mut reader = CaptureReader::new(Cursor::new(file)).unwrap();
+        let error = reader.next().unwrap().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,4 +1,115 @@
+use crate::{
+    handshake::Features, ProtocolError, ProtocolResult, BANDWIDTH_SMOOTHING_FACTOR, DEADLINK,
+    DEFAULT_MTU, IDLE_TIMEOUT, INTERVAL, KEEPALIVE_INTERVAL, MAX_STREAM_ID, MAX_TRANSFER_SIZE,
+    MAX_WINDOW_SIZE, PROBE_INIT, PROBE_LIMIT, PROTOCOL_OVERHEAD, RECV_WINDOW_SIZE,
+    REDUNDANT_HISTORY, RTO_MAX, RTO_MIN, RTO_NDL, SEND_WINDOW_SIZE, UNORDERED_STREAM_ID,
+};
+#[cfg(feature = "serde")]
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{collections::HashMap, str::FromStr};
+
+// KCP's lower bound on the MTU
+const MIN_MTU: usize = 50;
+const MIN_INTERVAL: u32 = 10;
+const MAX_INTERVAL: u32 = 5_000;
+
+/// Trade-offs between latency and bandwidth for the reliability layer, after the nodelay modes
+/// of KCP.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Preset {
+    /// Congestion control and a 40ms update interval, for bulk transfers sharing a link.
+    Normal,
+    /// Fast retransmission after 2 duplicate acks, no congestion control and a 30ms update
+    /// interval.
+    Fast,
+    /// `Fast` with nodelay, which lowers the minimum RTO and slows its backoff, and a 10ms update
+    /// interval. Trades the most bandwidth for the lowest latency.
+    Turbo,
+}
+
+impl Preset {
+    pub fn name(self) -> &'static str {
+        match self {
+            Preset::Normal => "normal",
+            Preset::Fast => "fast",
+            Preset::Turbo => "turbo",
+        }
+    }
+}
+
+impl FromStr for Preset {
+    type Err = ProtocolError;
+
+    fn from_str(name: &str) -> ProtocolResult<Self> {
+        match name {
+            "normal" => Ok(Preset::Normal),
+            "fast" => Ok(Preset::Fast),
+            "turbo" => Ok(Preset::Turbo),
+            _ => Err(ProtocolError::InvalidConfiguration(
+                "preset: must be normal, fast or turbo.",
+            )),
+        }
+    }
+}
+
+/// Byte layout of the segments sent by a `ReliableConnection`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum WireFormat {
+    /// Big-endian segment headers and the commands added on top of KCP: keepalives, skipped
+    /// messages and large message chunks.
+    Mercury,
+    /// The segments of the C implementation of KCP: the same header fields in little-endian and
+    /// only its push, ack and window probe commands. Keepalives, message expiry and large
+    /// messages aren't available.
+    Kcp,
+}
+
+/// Scheduling settings for a single stream.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub(crate) struct StreamSettings {
+    /// Streams with a higher priority are always scheduled before streams with a lower one.
+    pub(crate) priority: u8,
+    /// The maximum number of bytes per second the stream is allowed to send.
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub(crate) bandwidth_budget: Option<u32>,
+    /// Ratio of parity packets to packets carrying the stream's messages. 0 disables FEC.
+    pub(crate) redundancy: f32,
+    /// Number of the newest unacknowledged messages resent with every redundant message.
+    pub(crate) redundant_history: usize,
+}
+
+impl Default for StreamSettings {
+    fn default() -> Self {
+        Self {
+            priority: 0,
+            bandwidth_budget: None,
+            redundancy: 0.0,
+            redundant_history: REDUNDANT_HISTORY,
+        }
+    }
+}
+
+/// Settings of an `Endpoint` and its reliability layer.
+///
+/// With the `serde` feature it can be loaded from a file, e.g. a TOML one. Missing keys keep their
+/// default, durations are either a number of millisec or a string such as `"250ms"` or `"1m 30s"`,
+/// and the settings are validated as they're loaded.
 #[derive(Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(remote = "Self", default, deny_unknown_fields)
+)]
 pub struct Config {
     bandwidth_smoothing_factor: f32,
     /// Number of ordered streams available
@@ -14,6 +125,94 @@ pub struct Config {
     /// over the wire.
     /// default: 1450
     fragment_size_bytes: usize,
+    /// Priority and bandwidth budget of each stream, keyed by stream id. Streams without an entry
+    /// have a priority of 0 and an unlimited budget.
+    #[cfg_attr(feature = "serde", serde(rename = "streams", with = "stream_ids"))]
+    stream_settings: HashMap<usize, StreamSettings>,
+
+    /// Largest packet sent by the reliability layer, headers included.
+    /// default: 1400
+    mtu: usize,
+    /// Maximum number of segments in flight.
+    /// default: 32
+    send_window_size: usize,
+    /// Number of received segments buffered until they're read. It's advertised to the remote in
+    /// a u16 and messages have to fit in fewer segments.
+    /// default: 32
+    recv_window_size: usize,
+    /// Largest receive window auto-tuning may grow to, `None` disables auto-tuning.
+    /// default: None
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    max_recv_window_size: Option<usize>,
+    /// Lowers the minimum RTO and slows its backoff on retransmissions.
+    /// default: false
+    nodelay: bool,
+    /// Millisec between flushes of the reliability layer, 10 to 5000.
+    /// default: 100
+    #[cfg_attr(feature = "serde", serde(with = "millis"))]
+    interval: u32,
+    /// Number of acks of later segments after which a segment is retransmitted, 0 disables fast
+    /// retransmissions.
+    /// default: 0
+    fast_resend: u32,
+    /// default: false
+    congestion_control: bool,
+    /// Bounds of the retransmission timeout in millisec. The minimum depends on nodelay unless
+    /// it's set.
+    /// default: 100 (30 with nodelay) and 60000
+    #[cfg_attr(
+        feature = "serde",
+        serde(with = "optional_millis", skip_serializing_if = "Option::is_none")
+    )]
+    min_rto: Option<u32>,
+    #[cfg_attr(feature = "serde", serde(with = "millis"))]
+    max_rto: u32,
+    /// Number of times a segment is sent without being acknowledged before the link is
+    /// considered dead.
+    /// default: 20
+    dead_link: u32,
+    /// Millisec before the remote is first asked for its window while it's full, and the limit
+    /// the wait backs off to.
+    /// default: 7000 and 120000
+    #[cfg_attr(feature = "serde", serde(with = "millis"))]
+    probe_init: u32,
+    #[cfg_attr(feature = "serde", serde(with = "millis"))]
+    probe_limit: u32,
+    /// Millisec without sending anything after which a keepalive is sent, 0 disables keepalives.
+    /// default: 1000
+    #[cfg_attr(feature = "serde", serde(with = "millis"))]
+    keepalive_interval: u32,
+    /// Millisec without receiving anything after which the connection times out, 0 disables the
+    /// timeout.
+    /// default: 10000
+    #[cfg_attr(feature = "serde", serde(with = "millis"))]
+    idle_timeout: u32,
+    /// default: Mercury
+    wire_format: WireFormat,
+    /// Largest message in bytes accepted from `send_large` on the remote. Chunks of larger
+    /// transfers are dropped.
+    /// default: 64 MiB
+    max_transfer_size: usize,
+    /// Optional features offered to the remote endpoint, which uses the ones it offers too.
+    /// default: fec
+    #[cfg_attr(feature = "serde", serde(with = "feature_names"))]
+    features: Features,
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for Config {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Config::serialize(self, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Config {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let config = Config::deserialize(deserializer)?;
+        config.validate().map_err(de::Error::custom)?;
+        Ok(config)
+    }
 }
 
 impl Config {
@@ -35,7 +234,189 @@ impl Config {
     /// Calculated value based on the maximum number of fragments and the fragment size.
     #[inline]
     pub const fn max_payload_size_bytes(&self) -> usize {
-        self.max_fragments as usize + self.fragment_size_bytes
+        (self.max_fragments as usize).saturating_mul(self.fragment_size_bytes)
+    }
+
+    #[inline]
+    pub const fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    #[inline]
+    pub const fn send_window_size(&self) -> usize {
+        self.send_window_size
+    }
+
+    #[inline]
+    pub const fn recv_window_size(&self) -> usize {
+        self.recv_window_size
+    }
+
+    #[inline]
+    pub const fn max_recv_window_size(&self) -> Option<usize> {
+        self.max_recv_window_size
+    }
+
+    #[inline]
+    pub const fn nodelay(&self) -> bool {
+        self.nodelay
+    }
+
+    #[inline]
+    pub const fn interval(&self) -> u32 {
+        self.interval
+    }
+
+    #[inline]
+    pub const fn fast_resend(&self) -> u32 {
+        self.fast_resend
+    }
+
+    #[inline]
+    pub const fn congestion_control(&self) -> bool {
+        self.congestion_control
+    }
+
+    #[inline]
+    pub fn min_rto(&self) -> u32 {
+        match self.min_rto {
+            Some(min_rto) => min_rto,
+            None if self.nodelay => RTO_NDL,
+            None => RTO_MIN,
+        }
+    }
+
+    #[inline]
+    pub const fn max_rto(&self) -> u32 {
+        self.max_rto
+    }
+
+    #[inline]
+    pub const fn dead_link(&self) -> u32 {
+        self.dead_link
+    }
+
+    #[inline]
+    pub const fn probe_init(&self) -> u32 {
+        self.probe_init
+    }
+
+    #[inline]
+    pub const fn probe_limit(&self) -> u32 {
+        self.probe_limit
+    }
+
+    #[inline]
+    pub const fn keepalive_interval(&self) -> u32 {
+        self.keepalive_interval
+    }
+
+    #[inline]
+    pub const fn idle_timeout(&self) -> u32 {
+        self.idle_timeout
+    }
+
+    #[inline]
+    pub const fn wire_format(&self) -> WireFormat {
+        self.wire_format
+    }
+
+    #[inline]
+    pub const fn max_transfer_size(&self) -> usize {
+        self.max_transfer_size
+    }
+
+    #[inline]
+    pub const fn features(&self) -> Features {
+        self.features
+    }
+
+    /// Checks that the settings make sense together. `Endpoint::with_config` and
+    /// `ReliableConnection::with_config` refuse configurations which don't.
+    pub fn validate(&self) -> ProtocolResult<()> {
+        if !(0.0..=1.0).contains(&self.bandwidth_smoothing_factor) {
+            return Err(ProtocolError::InvalidConfiguration(
+                "bandwidth_smoothing_factor: must be between 0 and 1.",
+            ));
+        }
+        if self.ordered_streams_size > MAX_STREAM_ID + 1 {
+            return Err(ProtocolError::InvalidConfiguration(
+                "ordered_streams_size: must be at most 255, stream ids go up to 254.",
+            ));
+        }
+        if self.sequenced_streams_size > MAX_STREAM_ID + 1 {
+            return Err(ProtocolError::InvalidConfiguration(
+                "sequenced_streams_size: must be at most 255, stream ids go up to 254.",
+            ));
+        }
+        if self
+            .stream_settings
+            .keys()
+            .any(|&stream_id| stream_id > MAX_STREAM_ID && stream_id != UNORDERED_STREAM_ID)
+        {
+            return Err(ProtocolError::InvalidConfiguration(
+                "streams: stream ids must be at most 254, or 255 for datagrams without ordering.",
+            ));
+        }
+        if self.max_fragments == 0 {
+            return Err(ProtocolError::InvalidConfiguration(
+                "max_fragments: must be at least 1.",
+            ));
+        }
+        if self.fragment_size_bytes == 0 {
+            return Err(ProtocolError::InvalidConfiguration(
+                "fragment_size_bytes: must be at least 1 byte.",
+            ));
+        }
+        // The length of a message is sent in two bytes
+        if self.max_payload_size_bytes() > usize::from(u16::MAX) {
+            return Err(ProtocolError::InvalidConfiguration(
+                "fragment_size_bytes: max_fragments fragments must add up to at most 65535 bytes.",
+            ));
+        }
+        validate_mtu(self.mtu)?;
+        validate_window_sizes(self.send_window_size, self.recv_window_size)?;
+        validate_max_recv_window_size(self.max_recv_window_size, self.recv_window_size)?;
+        if !(MIN_INTERVAL..=MAX_INTERVAL).contains(&self.interval) {
+            return Err(ProtocolError::InvalidConfiguration(
+                "interval: must be between 10 and 5000 millisec.",
+            ));
+        }
+        if self.min_rto() == 0 || self.min_rto() > self.max_rto {
+            return Err(ProtocolError::InvalidConfiguration(
+                "min_rto: must be between 1 millisec and max_rto.",
+            ));
+        }
+        if self.dead_link == 0 {
+            return Err(ProtocolError::InvalidConfiguration(
+                "dead_link: must be at least 1 transmission.",
+            ));
+        }
+        if self.probe_init == 0 || self.probe_init > self.probe_limit {
+            return Err(ProtocolError::InvalidConfiguration(
+                "probe_init: must be between 1 millisec and probe_limit.",
+            ));
+        }
+        validate_keepalive(self.keepalive_interval, self.idle_timeout, self.wire_format)?;
+        if self.max_transfer_size == 0 {
+            return Err(ProtocolError::InvalidConfiguration(
+                "max_transfer_size: must be at least 1 byte.",
+            ));
+        }
+        if !Features::SUPPORTED.contains(self.features) {
+            return Err(ProtocolError::InvalidConfiguration(
+                "features: only fec is implemented.",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the scheduling settings of a particular stream.
+    pub(crate) fn stream_settings(&self, stream_id: usize) -> StreamSettings {
+        self.stream_settings
+            .get(&stream_id)
+            .cloned()
+            .unwrap_or_default()
     }
 
     pub fn with_max_fragments(mut self, max_fragments: u8) -> Self {
@@ -56,16 +437,560 @@ impl Config {
         self.sequenced_streams_size = sequenced_streams_size;
         self
     }
+
+    /// Sets the priority of a stream. Pending messages on streams with a higher priority are
+    /// always packed into outgoing packets first.
+    pub fn with_stream_priority(mut self, stream_id: usize, priority: u8) -> Self {
+        self.stream_settings.entry(stream_id).or_default().priority = priority;
+        self
+    }
+
+    /// Limits the number of bytes per second a stream is allowed to send. Reliable messages over
+    /// the budget are deferred while unreliable ones are dropped.
+    pub fn with_stream_bandwidth_budget(mut self, stream_id: usize, bytes_per_second: u32) -> Self {
+        self.stream_settings
+            .entry(stream_id)
+            .or_default()
+            .bandwidth_budget = Some(bytes_per_second);
+        self
+    }
+
+    /// Protects the packets carrying a stream's messages with forward error correction, sending
+    /// `ratio` parity packets per packet (e.g. 0.25 sends one parity packet for every four). A lost
+    /// packet can then be rebuilt by the receiver without waiting for a retransmission, at the cost
+    /// of the extra bandwidth. Ratios are clamped to 0..=1, 0 disables FEC.
+    pub fn with_stream_redundancy(mut self, stream_id: usize, ratio: f32) -> Self {
+        self.stream_settings
+            .entry(stream_id)
+            .or_default()
+            .redundancy = ratio.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Sets how many of the newest unacknowledged messages every packet of a redundant stream
+    /// carries (see `Datagram::redundant`).
+    /// default: 4
+    pub fn with_stream_redundant_history(mut self, stream_id: usize, messages: usize) -> Self {
+        self.stream_settings
+            .entry(stream_id)
+            .or_default()
+            .redundant_history = messages;
+        self
+    }
+
+    pub fn with_bandwidth_smoothing_factor(mut self, bandwidth_smoothing_factor: f32) -> Self {
+        self.bandwidth_smoothing_factor = bandwidth_smoothing_factor;
+        self
+    }
+
+    /// Applies the nodelay, interval, fast resend and congestion control settings of a preset.
+    pub fn with_preset(mut self, preset: Preset) -> Self {
+        let (nodelay, interval, fast_resend, congestion_control) = match preset {
+            Preset::Normal => (false, 40, 0, true),
+            Preset::Fast => (false, 30, 2, false),
+            Preset::Turbo => (true, 10, 2, false),
+        };
+        self.nodelay = nodelay;
+        self.interval = interval;
+        self.fast_resend = fast_resend;
+        self.congestion_control = congestion_control;
+        self.min_rto = None;
+        self
+    }
+
+    pub fn with_mtu(mut self, mtu: usize) -> Self {
+        self.mtu = mtu;
+        self
+    }
+
+    pub fn with_window_sizes(mut self, send_window_size: usize, recv_window_size: usize) -> Self {
+        self.send_window_size = send_window_size;
+        self.recv_window_size = recv_window_size;
+        self
+    }
+
+    /// Lets the receive window grow up to `max_size` segments with the bandwidth-delay product of
+    /// the data received (see `ReliableConnection::set_recv_window_auto_tuning`).
+    pub fn with_recv_window_auto_tuning(mut self, max_size: Option<usize>) -> Self {
+        self.max_recv_window_size = max_size;
+        self
+    }
+
+    pub fn with_nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    pub fn with_interval(mut self, interval: u32) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn with_fast_resend(mut self, fast_resend: u32) -> Self {
+        self.fast_resend = fast_resend;
+        self
+    }
+
+    pub fn with_congestion_control(mut self, congestion_control: bool) -> Self {
+        self.congestion_control = congestion_control;
+        self
+    }
+
+    pub fn with_rto_bounds(mut self, min_rto: u32, max_rto: u32) -> Self {
+        self.min_rto = Some(min_rto);
+        self.max_rto = max_rto;
+        self
+    }
+
+    pub fn with_dead_link(mut self, dead_link: u32) -> Self {
+        self.dead_link = dead_link;
+        self
+    }
+
+    pub fn with_window_probe(mut self, probe_init: u32, probe_limit: u32) -> Self {
+        self.probe_init = probe_init;
+        self.probe_limit = probe_limit;
+        self
+    }
+
+    pub fn with_keepalive_interval(mut self, keepalive_interval: u32) -> Self {
+        self.keepalive_interval = keepalive_interval;
+        self
+    }
+
+    pub fn with_idle_timeout(mut self, idle_timeout: u32) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Picks the byte layout of segments. The kcp format talks to peers running the C
+    /// implementation of KCP and turns keepalives off since it has none.
+    pub fn with_wire_format(mut self, wire_format: WireFormat) -> Self {
+        self.wire_format = wire_format;
+        if wire_format == WireFormat::Kcp {
+            self.keepalive_interval = 0;
+        }
+        self
+    }
+
+    pub fn with_max_transfer_size(mut self, max_transfer_size: usize) -> Self {
+        self.max_transfer_size = max_transfer_size;
+        self
+    }
+
+    /// Sets the optional features offered during the handshake, e.g. `Features::empty()` to never
+    /// send FEC parity packets.
+    pub fn with_features(mut self, features: Features) -> Self {
+        self.features = features;
+        self
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
-            bandwidth_smoothing_factor: 0.1,
+            bandwidth_smoothing_factor: BANDWIDTH_SMOOTHING_FACTOR,
             ordered_streams_size: 1,
             sequenced_streams_size: 1,
             max_fragments: 16,
             fragment_size_bytes: 1450,
+            stream_settings: HashMap::new(),
+            mtu: DEFAULT_MTU,
+            send_window_size: SEND_WINDOW_SIZE,
+            recv_window_size: RECV_WINDOW_SIZE,
+            max_recv_window_size: None,
+            nodelay: false,
+            interval: INTERVAL,
+            fast_resend: 0,
+            congestion_control: false,
+            min_rto: None,
+            max_rto: RTO_MAX,
+            dead_link: DEADLINK,
+            probe_init: PROBE_INIT,
+            probe_limit: PROBE_LIMIT,
+            keepalive_interval: KEEPALIVE_INTERVAL,
+            idle_timeout: IDLE_TIMEOUT,
+            wire_format: WireFormat::Mercury,
+            max_transfer_size: MAX_TRANSFER_SIZE,
+            features: Features::FEC,
+        }
+    }
+}
+
+pub(crate) fn validate_mtu(mtu: usize) -> ProtocolResult<()> {
+    if mtu < MIN_MTU || mtu <= PROTOCOL_OVERHEAD {
+        return Err(ProtocolError::InvalidConfiguration(
+            "mtu: must be at least 50 bytes.",
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn validate_window_sizes(send_size: usize, recv_size: usize) -> ProtocolResult<()> {
+    if send_size == 0 {
+        return Err(ProtocolError::InvalidConfiguration(
+            "send_window_size: must be at least 1 segment.",
+        ));
+    }
+    if recv_size == 0 || recv_size > MAX_WINDOW_SIZE {
+        return Err(ProtocolError::InvalidConfiguration(
+            "recv_window_size: must be between 1 and 65535 segments.",
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn validate_max_recv_window_size(
+    max_size: Option<usize>,
+    recv_window_size: usize,
+) -> ProtocolResult<()> {
+    if max_size.is_some_and(|max_size| max_size > MAX_WINDOW_SIZE) {
+        return Err(ProtocolError::InvalidConfiguration(
+            "max_recv_window_size: must be at most 65535 segments.",
+        ));
+    }
+    if max_size.is_some_and(|max_size| max_size < recv_window_size) {
+        return Err(ProtocolError::InvalidConfiguration(
+            "max_recv_window_size: must not be smaller than recv_window_size.",
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn validate_keepalive(
+    keepalive_interval: u32,
+    idle_timeout: u32,
+    wire_format: WireFormat,
+) -> ProtocolResult<()> {
+    // The remote would time out between keepalives
+    if keepalive_interval > 0 && idle_timeout > 0 && keepalive_interval >= idle_timeout {
+        return Err(ProtocolError::InvalidConfiguration(
+            "keepalive_interval: must be shorter than idle_timeout.",
+        ));
+    }
+    if wire_format == WireFormat::Kcp && keepalive_interval > 0 {
+        return Err(ProtocolError::InvalidConfiguration(
+            "keepalive_interval: must be 0 with the kcp wire format, which has no keepalives.",
+        ));
+    }
+    Ok(())
+}
+
+// Durations are written as "250ms" or "10s" and read from a number of millisec or a string of
+// numbers with a unit, e.g. "1.5s" or "1m 30s".
+#[cfg(feature = "serde")]
+mod millis {
+    use serde::{
+        de::{self, Unexpected, Visitor},
+        Deserializer, Serializer,
+    };
+    use std::{convert::TryFrom, fmt};
+
+    pub fn serialize<S: Serializer>(millis: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format(*millis))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+        deserializer.deserialize_any(MillisVisitor)
+    }
+
+    pub(super) fn format(millis: u32) -> String {
+        if millis > 0 && millis.is_multiple_of(1_000) {
+            format!("{}s", millis / 1_000)
+        } else {
+            format!("{}ms", millis)
+        }
+    }
+
+    pub(super) fn parse(text: &str) -> Option<u32> {
+        let mut total = 0.0;
+        let mut parts = text.split_whitespace().peekable();
+        parts.peek()?;
+        for part in parts {
+            let (number, unit) = part.split_at(part.find(|c: char| c.is_ascii_alphabetic())?);
+            let scale = match unit {
+                "ms" => 1.0,
+                "s" => 1_000.0,
+                "m" | "min" => 60_000.0,
+                "h" => 3_600_000.0,
+                _ => return None,
+            };
+            total += number.parse::<f64>().ok()? * scale;
+        }
+        if total < 0.0 || total > f64::from(u32::MAX) {
+            return None;
+        }
+        Some(total.round() as u32)
+    }
+
+    struct MillisVisitor;
+
+    impl<'de> Visitor<'de> for MillisVisitor {
+        type Value = u32;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a duration such as \"250ms\" or \"10s\", or a number of millisec")
+        }
+
+        fn visit_u64<E: de::Error>(self, value: u64) -> Result<u32, E> {
+            u32::try_from(value).map_err(|_| E::invalid_value(Unexpected::Unsigned(value), &self))
+        }
+
+        fn visit_i64<E: de::Error>(self, value: i64) -> Result<u32, E> {
+            u32::try_from(value).map_err(|_| E::invalid_value(Unexpected::Signed(value), &self))
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<u32, E> {
+            parse(value).ok_or_else(|| E::invalid_value(Unexpected::Str(value), &self))
         }
     }
 }
+
+#[cfg(feature = "serde")]
+mod optional_millis {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    #[derive(Deserialize)]
+    struct Millis(#[serde(with = "super::millis")] u32);
+
+    pub fn serialize<S: Serializer>(
+        millis: &Option<u32>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match millis {
+            Some(millis) => serializer.serialize_some(&super::millis::format(*millis)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<u32>, D::Error> {
+        Ok(Option::<Millis>::deserialize(deserializer)?.map(|Millis(millis)| millis))
+    }
+}
+
+// Formats like TOML only allow strings as keys, so the stream ids are written as strings.
+#[cfg(feature = "serde")]
+mod stream_ids {
+    use super::StreamSettings;
+    use serde::{
+        de::{self, Unexpected},
+        Deserialize, Deserializer, Serializer,
+    };
+    use std::collections::{BTreeMap, HashMap};
+
+    pub fn serialize<S: Serializer>(
+        settings: &HashMap<usize, StreamSettings>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let sorted: BTreeMap<_, _> = settings.iter().collect();
+        serializer.collect_map(
+            sorted
+                .into_iter()
+                .map(|(id, settings)| (id.to_string(), settings)),
+        )
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<usize, StreamSettings>, D::Error> {
+        HashMap::<String, StreamSettings>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(id, settings)| match id.parse() {
+                Ok(stream_id) => Ok((stream_id, settings)),
+                Err(_) => Err(de::Error::invalid_value(
+                    Unexpected::Str(&id),
+                    &"a stream id",
+                )),
+            })
+            .collect()
+    }
+}
+
+// Features are written as a list of names, e.g. ["fec"].
+#[cfg(feature = "serde")]
+mod feature_names {
+    use crate::handshake::Features;
+    use serde::{
+        de::{self, Unexpected},
+        Deserialize, Deserializer, Serializer,
+    };
+
+    pub fn serialize<S: Serializer>(features: &Features, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(features.names())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Features, D::Error> {
+        Vec::<String>::deserialize(deserializer)?.iter().try_fold(
+            Features::empty(),
+            |features, name| match Features::from_name(name) {
+                Some(feature) => Ok(features | feature),
+                None => Err(de::Error::invalid_value(
+                    Unexpected::Str(name),
+                    &"sack, encryption, compression or fec",
+                )),
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Config, Features, Preset, WireFormat};
+    use crate::ProtocolError;
+
+    fn error(config: Config) -> &'static str {
+        match config.validate() {
+            Err(ProtocolError::InvalidConfiguration(message)) => message,
+            result => panic!("expected an invalid configuration, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_default_and_presets_are_valid() {
+        assert!(Config::default().validate().is_ok());
+        for name in ["normal", "fast", "turbo"].iter() {
+            let preset = name.parse::<Preset>().unwrap();
+            assert_eq!(preset.name(), *name);
+            assert!(Config::default().with_preset(preset).validate().is_ok());
+        }
+        assert!("ludicrous".parse::<Preset>().is_err());
+    }
+
+    #[test]
+    fn test_presets_follow_kcp_modes() {
+        let turbo = Config::default().with_preset(Preset::Turbo);
+        assert!(turbo.nodelay());
+        assert_eq!(turbo.interval(), 10);
+        assert_eq!(turbo.fast_resend(), 2);
+        assert_eq!(turbo.min_rto(), 30);
+
+        let normal = turbo.with_preset(Preset::Normal);
+        assert!(!normal.nodelay());
+        assert!(normal.congestion_control());
+        assert_eq!(normal.min_rto(), 100);
+    }
+
+    #[test]
+    fn test_inconsistent_settings_are_rejected() {
+        assert!(
+            error(Config::default().with_ordered_streams_size(256)).starts_with("ordered_streams")
+        );
+        assert!(error(Config::default().with_sequenced_streams_size(256)).starts_with("sequenced"));
+        assert!(Config::default()
+            .with_sequenced_streams_size(255)
+            .with_stream_priority(254, 1)
+            .with_stream_priority(255, 1)
+            .validate()
+            .is_ok());
+        assert!(error(Config::default().with_stream_priority(256, 1)).starts_with("streams:"));
+        assert!(error(Config::default().with_max_fragments(0)).starts_with("max_fragments:"));
+        assert!(error(Config::default().with_fragment_size_bytes(0)).starts_with("fragment_size"));
+        assert!(
+            error(Config::default().with_fragment_size_bytes(5_000)).starts_with("fragment_size")
+        );
+        assert!(error(Config::default().with_mtu(40)).starts_with("mtu:"));
+        assert!(error(Config::default().with_window_sizes(32, 70_000)).starts_with("recv_window"));
+        assert!(
+            error(Config::default().with_recv_window_auto_tuning(Some(16)))
+                .starts_with("max_recv_window_size:")
+        );
+        assert!(error(Config::default().with_interval(1)).starts_with("interval:"));
+        assert!(error(Config::default().with_rto_bounds(500, 200)).starts_with("min_rto:"));
+        assert!(error(Config::default().with_window_probe(10_000, 5_000)).starts_with("probe"));
+        assert!(error(
+            Config::default()
+                .with_keepalive_interval(5_000)
+                .with_idle_timeout(5_000)
+        )
+        .starts_with("keepalive_interval:"));
+        assert!(Config::default()
+            .with_keepalive_interval(5_000)
+            .with_idle_timeout(0)
+            .validate()
+            .is_ok());
+
+        let kcp = Config::default().with_wire_format(WireFormat::Kcp);
+        assert!(kcp.validate().is_ok());
+        assert!(error(kcp.with_keepalive_interval(1_000)).starts_with("keepalive_interval:"));
+        assert!(error(Config::default().with_max_transfer_size(0)).starts_with("max_transfer"));
+        assert!(
+            error(Config::default().with_features(Features::FEC | Features::SACK))
+                .starts_with("features:")
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_durations_parsed_with_units() {
+        use super::millis;
+
+        assert_eq!(millis::parse("250ms"), Some(250));
+        assert_eq!(millis::parse("1.5s"), Some(1_500));
+        assert_eq!(millis::parse("1m 30s"), Some(90_000));
+        assert_eq!(millis::parse("250"), None);
+        assert_eq!(millis::parse("-1s"), None);
+        assert_eq!(millis::parse("2 weeks"), None);
+        assert_eq!(millis::format(10_000), "10s");
+        assert_eq!(millis::format(250), "250ms");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_loaded_from_toml_with_defaults() {
+        let config: Config = toml::from_str(
+            r#"
+            mtu = 1200
+            nodelay = true
+            interval = "20ms"
+            idle_timeout = "1m"
+            keepalive_interval = 2500
+            features = []
+
+            [streams.3]
+            priority = 10
+            bandwidth_budget = 64000
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.mtu(), 1_200);
+        assert_eq!(config.min_rto(), 30);
+        assert_eq!(config.interval(), 20);
+        assert_eq!(config.idle_timeout(), 60_000);
+        assert_eq!(config.keepalive_interval(), 2_500);
+        assert_eq!(config.features(), Features::empty());
+        assert_eq!(config.stream_settings(3).priority, 10);
+        assert_eq!(config.stream_settings(3).bandwidth_budget, Some(64_000));
+        // Missing keys keep their default
+        assert_eq!(config.send_window_size(), 32);
+        assert_eq!(config.stream_settings(3).redundant_history, 4);
+
+        let round_trip: Config = toml::from_str(&toml::to_string(&config).unwrap()).unwrap();
+        assert_eq!(round_trip.interval(), 20);
+        assert_eq!(round_trip.features(), Features::empty());
+        assert_eq!(round_trip.stream_settings(3), config.stream_settings(3));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_load_errors_name_the_key() {
+        let invalid = toml::from_str::<Config>("mtu = 1200\ninterval = \"1ms\"").err();
+        assert!(invalid
+            .unwrap()
+            .to_string()
+            .contains("interval: must be between"));
+
+        let unknown = toml::from_str::<Config>("mut = 1200").err();
+        assert!(unknown.unwrap().to_string().contains("mut"));
+
+        let json = serde_json::from_str::<Config>(r#"{"probe_init": "soon"}"#).err();
+        assert!(json.unwrap().to_string().contains("a duration"));
+
+        let stream = serde_json::from_str::<Config>(r#"{"streams": {"first": {}}}"#).err();
+        assert!(stream.unwrap().to_string().contains("stream id"));
+
+        let feature = toml::from_str::<Config>(r#"features = ["zip"]"#).err();
+        assert!(feature.unwrap().to_string().contains("zip"));
+    }
+}
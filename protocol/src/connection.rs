@@ -1,24 +1,54 @@
 use crate::{
-    segment::Segment, ProtocolError, ProtocolResult, CMD_ACK, DEADLINK, DEFAULT_MTU, INTERVAL,
-    PROTOCOL_OVERHEAD, RECV_WINDOW_SIZE, RTO_DEF, RTO_MIN, SEND_WINDOW_SIZE, THRESH_INIT, RTO_MAX,
-    RTO_NDL, ASK_SEND, ASK_TELL, CMD_PUSH, CMD_WASK, CMD_WINS, PROBE_INIT, PROBE_LIMIT, THRESH_MIN
+    capture::{self, CaptureKind, PacketRecorder, Recorder},
+    config::{self, Config, WireFormat},
+    events::{Event, EventListener, Events},
+    histogram::RttHistogram,
+    metrics::{DataPoint, Metrics},
+    pool::BufferPool,
+    segment::{Segment, SegmentHeader},
+    transfer::{
+        cancel_chunk, receive_chunk, Chunk, IncomingTransfer, OutgoingTransfer, TransferId,
+        TransferProgress, CHUNK_HEADER_SIZE,
+    },
+    window::SegmentWindow,
+    ProtocolError, ProtocolResult, ASK_SEND, ASK_TELL, CMD_ACK, CMD_CHUNK, CMD_PING, CMD_PONG,
+    CMD_PUSH, CMD_SKIP, CMD_WASK, CMD_WINS, MAX_FRAGMENTS, PROTOCOL_OVERHEAD, RECV_WINDOW_SIZE,
+    RTO_DEF, RTO_MIN, RTO_NDL, THRESH_INIT, THRESH_MIN,
 };
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+#[cfg(feature = "futures-io")]
+use futures_io::{AsyncRead, AsyncWrite};
 use log::debug;
 use std::{
     cmp,
     collections::VecDeque,
-    io::{Cursor, Read, Write},
-    ops::Add,
-    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    io::{self, Cursor, Read, Write},
+    mem,
 };
+#[cfg(feature = "futures-io")]
+use std::{
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+/// The lifecycle state of a `ReliableConnection`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ConnectionState {
+    Connected,
+    /// Nothing was received from the remote within the idle timeout. This state is final.
+    TimedOut,
+    /// A segment was sent `Config::dead_link` times without being acknowledged. This state is
+    /// final.
+    DeadLink,
+    /// The remote endpoint speaks another version of the wire protocol. This state is final.
+    VersionMismatch,
+}
 
-pub struct ReliableConnection {
+pub struct ReliableConnection<W: Write> {
     session_id: u32,
     max_transmission_unit: usize,
     max_segment_size: usize,
-    // TODO: This should probably be an enum..
-    connection_state: u32,
+    connection_state: ConnectionState,
 
     unacked_send_sequence_num: u32,
     next_send_sequence_num: u32,
@@ -30,10 +60,17 @@ pub struct ReliableConnection {
     static_rtt: u32,
     calculated_rto: u32,
     minimum_rto: u32,
+    maximum_rto: u32,
 
     send_window_size: usize,
     recv_window_size: usize,
+    // The recv window set by `set_window_sizes`, which auto-tuning never goes below
+    min_recv_window_size: usize,
+    // Upper limit of the recv window while it's auto-tuned
+    max_recv_window_size: Option<usize>,
     remote_window_size: usize,
+    // Largest window advertised by the remote, which its recv window is at least as large as
+    remote_recv_window_size: Option<usize>,
     congestion_window_size: usize,
 
     probe: u32,
@@ -49,34 +86,84 @@ pub struct ReliableConnection {
 
     next_probe_time: u32,
     probe_wait: u32,
+    probe_init: u32,
+    probe_limit: u32,
 
-    // Maximum number of retransmissions
+    // Maximum number of transmissions of a segment before the link is considered dead
     dead_link: u32,
     incr: u32,
 
+    // Send a keepalive after this many millisec without sending anything (0 disables)
+    keepalive_interval: u32,
+    // Time out after this many millisec without receiving anything (0 disables)
+    idle_timeout: u32,
+    last_send_time: u32,
+    last_recv_time: u32,
+    // Timestamp of the last keepalive received that still needs to be answered
+    pending_pong: Option<u32>,
+
     send_queue: VecDeque<Segment>,
     recv_queue: VecDeque<Segment>,
-    send_buffer: VecDeque<Segment>,
-    recv_buffer: VecDeque<Segment>,
+    send_buffer: SegmentWindow,
+    recv_buffer: SegmentWindow,
+    // Whether the last segment moved into the send_buffer wasn't the final fragment of its message
+    mid_message: bool,
+
+    // Large messages waiting to be sent. Only the front one is fed into the send_queue.
+    outgoing_transfers: VecDeque<OutgoingTransfer>,
+    next_transfer_id: u32,
+    incoming_transfer: Option<IncomingTransfer>,
+    completed_transfers: VecDeque<Bytes>,
+    // Largest transfer accepted from the remote, in bytes
+    max_transfer_size: usize,
 
     ack_list: Vec<(u32, u32)>,
     payload_buffer: BytesMut,
+    // Data buffers of the segments which are done with, reused by new segments
+    pool: BufferPool,
 
     // Number of repeated acks to trigger fast retransmissions
     fast_resend: u32,
 
     use_congestion_control: bool,
     in_streaming_mode: bool,
-    //    output: W,
+    wire_format: WireFormat,
+    output: W,
+
+    metrics: Metrics,
+    rtt_histogram: RttHistogram,
+    events: Events,
+    recorder: Recorder,
+
+    // Tasks waiting for data to read or for room to write in streaming mode
+    #[cfg(feature = "futures-io")]
+    read_waker: Option<Waker>,
+    #[cfg(feature = "futures-io")]
+    write_waker: Option<Waker>,
 }
 
-impl ReliableConnection {
-    pub fn new(session_id: u32) -> Self {
+impl<W: Write> ReliableConnection<W> {
+    /// Creates a new connection with the default `Config`. Every packet produced by `update` is
+    /// written to `output` with a single `write_all` call.
+    pub fn new(session_id: u32, output: W) -> Self {
+        Self::from_config(session_id, output, &Config::default())
+    }
+
+    /// Creates a new connection tuned by `config`, or fails if the config doesn't validate.
+    pub fn with_config(session_id: u32, output: W, config: &Config) -> ProtocolResult<Self> {
+        config.validate()?;
+        Ok(Self::from_config(session_id, output, config))
+    }
+
+    fn from_config(session_id: u32, output: W, config: &Config) -> Self {
+        let mtu = config.mtu();
+        let send_window_size = config.send_window_size();
+        let recv_window_size = config.recv_window_size();
         Self {
             session_id,
-            max_transmission_unit: DEFAULT_MTU,
-            max_segment_size: DEFAULT_MTU - PROTOCOL_OVERHEAD,
-            connection_state: 0,
+            max_transmission_unit: mtu,
+            max_segment_size: mtu - PROTOCOL_OVERHEAD,
+            connection_state: ConnectionState::Connected,
 
             unacked_send_sequence_num: 0,
             next_send_sequence_num: 0,
@@ -87,45 +174,106 @@ impl ReliableConnection {
             floating_rtt: 0,
             static_rtt: 0,
             calculated_rto: RTO_DEF,
-            minimum_rto: RTO_MIN,
+            minimum_rto: config.min_rto(),
+            maximum_rto: config.max_rto(),
 
-            send_window_size: SEND_WINDOW_SIZE,
-            recv_window_size: RECV_WINDOW_SIZE,
+            send_window_size,
+            recv_window_size,
+            min_recv_window_size: recv_window_size,
+            max_recv_window_size: config.max_recv_window_size(),
             remote_window_size: RECV_WINDOW_SIZE,
+            remote_recv_window_size: None,
             congestion_window_size: 0,
             probe: 0,
 
             current_time: 0,
-            interval: 0,
+            interval: config.interval(),
             next_flush_time: 0,
             update_called: false,
 
             xmit: 0,
 
-            nodelay: 0,
+            nodelay: config.nodelay() as u32,
 
             next_probe_time: 0,
             probe_wait: 0,
+            probe_init: config.probe_init(),
+            probe_limit: config.probe_limit(),
 
-            dead_link: DEADLINK,
+            dead_link: config.dead_link(),
             incr: 0,
 
-            send_queue: VecDeque::with_capacity(SEND_WINDOW_SIZE),
-            recv_queue: VecDeque::with_capacity(RECV_WINDOW_SIZE),
-            send_buffer: VecDeque::new(),
-            recv_buffer: VecDeque::new(),
+            keepalive_interval: config.keepalive_interval(),
+            idle_timeout: config.idle_timeout(),
+            last_send_time: 0,
+            last_recv_time: 0,
+            pending_pong: None,
+
+            send_queue: VecDeque::with_capacity(send_window_size),
+            recv_queue: VecDeque::with_capacity(recv_window_size),
+            send_buffer: SegmentWindow::new(send_window_size),
+            recv_buffer: SegmentWindow::new(recv_window_size),
+            mid_message: false,
 
-            // TODO: Need to allocate with capacity
-            ack_list: Vec::new(),
-            payload_buffer: BytesMut::with_capacity((DEFAULT_MTU + PROTOCOL_OVERHEAD) * 3),
+            outgoing_transfers: VecDeque::new(),
+            next_transfer_id: 0,
+            incoming_transfer: None,
+            completed_transfers: VecDeque::new(),
+            max_transfer_size: config.max_transfer_size(),
 
-            fast_resend: 0,
+            ack_list: Vec::with_capacity(recv_window_size),
+            payload_buffer: BytesMut::with_capacity((mtu + PROTOCOL_OVERHEAD) * 3),
+            pool: BufferPool::new(mtu - PROTOCOL_OVERHEAD, send_window_size + recv_window_size),
 
-            use_congestion_control: false,
+            fast_resend: config.fast_resend(),
+
+            use_congestion_control: config.congestion_control(),
             in_streaming_mode: false,
+            wire_format: config.wire_format(),
+            metrics: Metrics::new(config.bandwidth_smoothing_factor()),
+            rtt_histogram: RttHistogram::new(),
+            events: Events::new(session_id),
+            recorder: Recorder::new(),
+            output,
+            #[cfg(feature = "futures-io")]
+            read_waker: None,
+            #[cfg(feature = "futures-io")]
+            write_waker: None,
+        }
+    }
+
+    /// Returns the current state of the connection.
+    pub fn state(&self) -> ConnectionState {
+        self.connection_state
+    }
+
+    /// Ends the connection for a reason found by the layer above, e.g. the version mismatch an
+    /// `Endpoint` detects during its handshake. Nothing is sent from then on.
+    pub(crate) fn disconnect(&mut self, reason: ConnectionState) {
+        if self.connection_state == ConnectionState::Connected {
+            self.change_state(reason);
         }
     }
 
+    /// Returns a mutable reference to the output packets are written to.
+    pub fn output_mut(&mut self) -> &mut W {
+        &mut self.output
+    }
+
+    /// Estimates the number of bytes per second the connection is able to send, based on the
+    /// send and remote windows and the smoothed rtt (or RTO_DEF until the first rtt sample). The
+    /// congestion window is left out, it already holds back the reliable segments and is never
+    /// opened by traffic which isn't acknowledged.
+    pub fn estimated_bandwidth(&self) -> usize {
+        let rtt = if self.static_rtt > 0 {
+            self.static_rtt
+        } else {
+            RTO_DEF
+        };
+        let window_size = cmp::min(self.send_window_size, self.remote_window_size);
+        window_size * self.max_transmission_unit * 1000 / rtt as usize
+    }
+
     pub fn recv(&mut self, buffer: &mut [u8]) -> ProtocolResult<usize> {
         if self.recv_queue.is_empty() {
             return Err(ProtocolError::EmptyRecvQueue);
@@ -137,31 +285,79 @@ impl ReliableConnection {
             return Err(ProtocolError::BufferTooSmall);
         }
 
-        let fast_recover = self.recv_queue.len() >= self.recv_window_size;
+        // Write the full message data into the buffer.
+        let mut len = 0;
+        self.pop_message(|data| {
+            buffer[len..len + data.len()].copy_from_slice(data);
+            len += data.len();
+        });
+        assert_eq!(len, peek_size);
+
+        Ok(len)
+    }
 
-        let mut cursor = Cursor::new(buffer);
+    /// Like `recv`, but hands out the next message without copying it. The payload of a message
+    /// split into several segments is only copied when joining them if they didn't arrive next to
+    /// each other in a packet given to `input_bytes`.
+    pub fn recv_bytes(&mut self) -> ProtocolResult<Bytes> {
+        if self.recv_queue.is_empty() {
+            return Err(ProtocolError::EmptyRecvQueue);
+        }
+        self.peek_size()?;
+
+        let mut message: Option<BytesMut> = None;
+        self.pop_message(|data| {
+            let data = mem::take(data);
+            match message {
+                Some(ref mut message) => message.unsplit(data),
+                None => message = Some(data),
+            }
+        });
+        Ok(message.unwrap_or_default().freeze())
+    }
 
-        // Write the full message data into the buffer.
-        while let Some(segment) = self.recv_queue.pop_front() {
-            cursor.write_all(&segment.data)?;
+    /// Like `recv_bytes`, but returns the payload of every segment of the next message separately
+    /// so that they're never copied.
+    pub fn recv_fragments(&mut self) -> ProtocolResult<Vec<Bytes>> {
+        if self.recv_queue.is_empty() {
+            return Err(ProtocolError::EmptyRecvQueue);
+        }
+        self.peek_size()?;
+
+        let mut fragments = Vec::new();
+        self.pop_message(|data| fragments.push(mem::take(data).freeze()));
+        Ok(fragments)
+    }
+
+    // Removes the segments of the next message from the recv_queue and hands their data to
+    // `consume` in order. The message must be complete, as checked by `peek_size`. The buffers
+    // left in place by `consume` go back to the pool.
+    fn pop_message<F>(&mut self, mut consume: F)
+    where
+        F: FnMut(&mut BytesMut),
+    {
+        self.recorder
+            .record(self.current_time, CaptureKind::Recv, &[]);
+        let fast_recover = self.recv_queue.len() >= self.recv_window_size;
+
+        while let Some(mut segment) = self.recv_queue.pop_front() {
             debug!("Received sequence_num: {}", segment.sequence_num);
+            consume(&mut segment.data);
+            self.pool.put(segment.data);
             if segment.fragment_id == 0 {
                 break;
             }
         }
-        assert_eq!(cursor.position() as usize, peek_size);
 
-        // Move available data from recv_buffer -> recv_queue
-        while let Some(segment) = self.recv_buffer.pop_front() {
-            if segment.sequence_num == self.next_recv_sequence_num
-                && self.recv_queue.len() < self.recv_window_size
-            {
-                self.recv_queue.push_back(segment);
-                self.next_recv_sequence_num += 1;
-            } else {
-                break;
-            }
-        }
+        self.refill_recv_queue(fast_recover);
+    }
+
+    // Moves the segments which arrived in order from the recv_buffer into the freed up room in
+    // the recv_queue.
+    fn refill_recv_queue(&mut self, fast_recover: bool) {
+        self.move_to_recv_queue();
+        self.take_chunks();
+        self.drop_skipped_messages();
 
         // fast recover
         if self.recv_queue.len() < self.recv_window_size && fast_recover {
@@ -169,46 +365,71 @@ impl ReliableConnection {
             // tell remote my window size
             self.probe |= ASK_TELL;
         }
-
-        Ok(cursor.position() as usize)
     }
 
     /// when you received a low level packet (eg. UDP packet), call it
     pub fn input(&mut self, buffer: &[u8]) -> ProtocolResult<usize> {
-        let n = buffer.len();
-        let mut cursor = Cursor::new(buffer);
+        self.input_packet(buffer)
+    }
+
+    /// Like `input`, but takes ownership of the packet so that the segments received keep their
+    /// payload in place instead of copying it out. The packet is copied once if other `Bytes`
+    /// still share it.
+    pub fn input_bytes(&mut self, packet: Bytes) -> ProtocolResult<usize> {
+        self.input_packet(BytesMut::from(packet))
+    }
+
+    fn input_packet<P: InputPacket>(&mut self, mut packet: P) -> ProtocolResult<usize> {
+        let n = packet.remaining().len();
+        self.metrics.record_received(n);
+        self.recorder
+            .record(self.current_time, CaptureKind::Inbound, packet.remaining());
 
-        if cursor.remaining() < PROTOCOL_OVERHEAD {
+        if packet.remaining().len() < PROTOCOL_OVERHEAD {
             return Err(ProtocolError::BufferTooSmall);
         }
         let old_unacked = self.unacked_send_sequence_num;
+        let old_congestion_window = self.congestion_window_size;
         let mut flag = false;
         let mut maxack: u32 = 0;
-        while cursor.remaining() >= PROTOCOL_OVERHEAD {
-            let session_id = cursor.get_u32_be();
+        while packet.remaining().len() >= PROTOCOL_OVERHEAD {
+            let SegmentHeader {
+                session_id,
+                command,
+                fragment_id,
+                window_size,
+                timestamp,
+                sequence_num,
+                unacked_sequence_num,
+                len,
+            } = SegmentHeader::decode(&packet.remaining()[..PROTOCOL_OVERHEAD], self.wire_format);
             if session_id != self.session_id {
                 return Err(ProtocolError::InvalidSessionId);
             }
+            packet.advance(PROTOCOL_OVERHEAD);
 
-            let command = cursor.get_u8();
-            let fragment_id = cursor.get_u8();
-            let window_size = cursor.get_u16_be();
-            let timestamp = cursor.get_u32_be();
-            let sequence_num = cursor.get_u32_be();
-            let unacked_sequence_num = cursor.get_u32_be();
-            let len = cursor.get_u32_be() as usize;
-
-            if cursor.remaining() < len {
+            if packet.remaining().len() < len {
                 return Err(ProtocolError::IncompleteMessage);
             }
 
-            if command != CMD_PUSH && command != CMD_ACK && command != CMD_WASK &&
-                command != CMD_WINS
-            {
+            let kcp_command = command == CMD_PUSH
+                || command == CMD_ACK
+                || command == CMD_WASK
+                || command == CMD_WINS;
+            let mercury_command = command == CMD_PING
+                || command == CMD_PONG
+                || command == CMD_SKIP
+                || command == CMD_CHUNK;
+            if !(kcp_command || mercury_command && self.wire_format == WireFormat::Mercury) {
                 return Err(ProtocolError::InvalidCommand);
             }
 
+            self.last_recv_time = self.current_time;
             self.remote_window_size = window_size as usize;
+            self.remote_recv_window_size = Some(cmp::max(
+                self.remote_recv_window_size.unwrap_or(0),
+                window_size as usize,
+            ));
             self.parse_unacked(unacked_sequence_num);
             self.shrink_buffer();
             if command == CMD_ACK {
@@ -221,35 +442,52 @@ impl ReliableConnection {
                 if !flag {
                     flag = true;
                     maxack = sequence_num;
-                } else {
-                    if sequence_num > maxack {
-                        maxack = sequence_num;
-                    }
+                } else if sequence_num > maxack {
+                    maxack = sequence_num;
                 }
-            } else if command == CMD_PUSH {
+            } else if command == CMD_PUSH || command == CMD_SKIP || command == CMD_CHUNK {
                 if sequence_num < self.next_recv_sequence_num + self.recv_window_size as u32 {
                     self.ack_list.push((sequence_num, timestamp));
                     if sequence_num >= self.next_recv_sequence_num {
-                        let mut segment = Segment::default();
-                        segment.session_id = session_id;
-                        segment.command = command;
-                        segment.fragment_id = fragment_id;
-                        segment.window_size = window_size as u16;
-                        segment.timestamp = timestamp;
-                        segment.sequence_num = sequence_num;
-                        segment.unacked_sequence_num = unacked_sequence_num;
-                        segment.data.resize(len, 0);
-                        cursor.read_exact(&mut segment.data)?;
+                        self.events.emit(
+                            self.current_time,
+                            Event::SegmentReceived { sequence_num, len },
+                        );
+                        let segment = Segment {
+                            session_id,
+                            command,
+                            fragment_id,
+                            window_size,
+                            timestamp,
+                            sequence_num,
+                            unacked_sequence_num,
+                            ..Segment::new(packet.split_to(len, &mut self.pool))
+                        };
                         self.parse_data(segment);
+                        continue;
                     }
                 }
             } else if command == CMD_WASK {
                 // ready to send back KCP_CMD_WINS in `flush`
                 // tell remote my window size
                 self.probe |= ASK_TELL;
+                self.events
+                    .emit(self.current_time, Event::WindowProbeReceived);
             } else if command == CMD_WINS {
                 // do nothing
+            } else if command == CMD_PING {
+                // ready to send back CMD_PONG in `flush`
+                self.pending_pong = Some(timestamp);
+            } else if command == CMD_PONG {
+                // the pong echoes the timestamp of our ping so it doubles as an rtt sample
+                let rtt = time_diff(self.current_time, timestamp);
+                if rtt >= 0 {
+                    self.update_ack(rtt as u32);
+                }
             }
+
+            // Skip over any payload that wasn't consumed above
+            packet.advance(len);
         }
 
         if flag {
@@ -277,7 +515,11 @@ impl ReliableConnection {
                 }
             }
         }
-        Ok(n - cursor.remaining())
+        self.report_congestion_window(old_congestion_window);
+        self.report_transfer_progress();
+        self.wake_stream_tasks();
+
+        Ok(n - packet.remaining().len())
     }
 
     /// Returns the size of the next message in the recv_queue.
@@ -311,6 +553,25 @@ impl ReliableConnection {
 
     /// Appends a payload to the send queue
     pub fn send(&mut self, payload: &[u8]) -> ProtocolResult<()> {
+        self.recorder
+            .record(self.current_time, CaptureKind::Send, payload);
+        self.push_payload(payload, None)
+    }
+
+    /// Appends a payload to the send queue which is given up on if it hasn't been acknowledged
+    /// within `ttl` millisec of the last `update`. If it was already sent the remote is told to
+    /// skip it so the messages sent after it aren't held up waiting for its delivery.
+    pub fn send_with_ttl(&mut self, payload: &[u8], ttl: u32) -> ProtocolResult<()> {
+        let mut record = Vec::with_capacity(4 + payload.len());
+        record.extend_from_slice(&ttl.to_be_bytes());
+        record.extend_from_slice(payload);
+        self.recorder
+            .record(self.current_time, CaptureKind::SendWithTtl, &record);
+        let expire_time = self.current_time.wrapping_add(ttl);
+        self.push_payload(payload, Some(expire_time))
+    }
+
+    fn push_payload(&mut self, payload: &[u8], expire_time: Option<u32>) -> ProtocolResult<()> {
         if payload.is_empty() {
             return Err(ProtocolError::EmptyPayload);
         }
@@ -321,7 +582,10 @@ impl ReliableConnection {
         if self.in_streaming_mode {
             if let Some(segment) = self.send_queue.back_mut() {
                 let old_len = segment.data.len();
-                if old_len < self.max_segment_size {
+                if old_len < self.max_segment_size
+                    && segment.command == CMD_PUSH
+                    && segment.expire_time == expire_time
+                {
                     let new_len = cmp::min(old_len + payload.len(), self.max_segment_size);
                     // TODO: Maybe this should be handled by a method on segment
                     segment.data.resize(new_len, 0);
@@ -339,10 +603,11 @@ impl ReliableConnection {
         } else {
             (cursor.remaining() + self.max_segment_size - 1) / self.max_segment_size
         };
-
-        if num_fragments >= RECV_WINDOW_SIZE {
-            return Err(ProtocolError::FragmentsGreaterThanWindowSize);
-        }
+        self.check_message_size(
+            cursor.remaining(),
+            self.max_segment_size,
+            self.min_recv_window_size,
+        )?;
 
         if num_fragments == 0 {
             num_fragments = 1
@@ -351,7 +616,11 @@ impl ReliableConnection {
         // Handle fragmentation if we're not in streaming mode.
         for i in 0..num_fragments {
             let new_size = cmp::min(self.max_segment_size as usize, cursor.remaining());
-            let mut segment = Segment::default();
+            let mut segment = Segment {
+                command: CMD_PUSH,
+                expire_time,
+                ..Segment::new(self.pool.take())
+            };
             segment.data.resize(new_size, 0);
             cursor.read_exact(&mut segment.data)?;
             segment.fragment_id = (if !self.in_streaming_mode {
@@ -365,13 +634,247 @@ impl ReliableConnection {
         Ok(())
     }
 
+    // Fails if a message of `len` bytes couldn't be delivered with the given segment size and recv
+    // window. The remote needs every fragment in its recv queue at once to deliver a message, so
+    // the largest window it advertised (or the default one until it's heard from) limits the
+    // fragments too. The recv window is the one set by `set_window_sizes`, auto-tuning doesn't
+    // count as the remote may size its window the same way.
+    fn check_message_size(
+        &self,
+        len: usize,
+        max_segment_size: usize,
+        recv_window_size: usize,
+    ) -> ProtocolResult<()> {
+        if self.in_streaming_mode {
+            return Ok(());
+        }
+        let window_size = cmp::min(
+            recv_window_size,
+            self.remote_recv_window_size.unwrap_or(RECV_WINDOW_SIZE),
+        );
+        let num_fragments = cmp::max(len.div_ceil(max_segment_size), 1);
+        if num_fragments >= window_size {
+            return Err(ProtocolError::FragmentsGreaterThanWindowSize);
+        }
+        if num_fragments > MAX_FRAGMENTS {
+            return Err(ProtocolError::PayloadTooLarge(
+                len,
+                MAX_FRAGMENTS * max_segment_size,
+            ));
+        }
+        Ok(())
+    }
+
+    // Checks that the messages waiting in the send_queue would still fit after changing the segment
+    // size or recv window. The rest of a message which is partially in flight is left out, its
+    // fragments are never split again.
+    fn check_queued_messages(
+        &self,
+        max_segment_size: usize,
+        recv_window_size: usize,
+    ) -> ProtocolResult<()> {
+        let mut in_flight = self.mid_message;
+        let mut len = 0;
+        for segment in self.send_queue.iter() {
+            let last_fragment = segment.fragment_id == 0;
+            if in_flight {
+                in_flight = !last_fragment;
+            } else if segment.command == CMD_PUSH {
+                len += segment.data.len();
+                if last_fragment {
+                    self.check_message_size(len, max_segment_size, recv_window_size)?;
+                    len = 0;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Splits the messages waiting in the send_queue again with the current segment size. Segments
+    // which were handed a sequence number are left alone, as is the rest of a message which is
+    // partially in flight since the remote was already told how many fragments it has. The
+    // chunks of the current transfer are dropped and queued again from its payload.
+    fn resegment_send_queue(&mut self) {
+        let queued = mem::replace(
+            &mut self.send_queue,
+            VecDeque::with_capacity(self.send_window_size),
+        );
+        let mut in_flight = self.mid_message;
+        let mut message = Vec::new();
+        for segment in queued {
+            let last_fragment = segment.fragment_id == 0;
+            if in_flight {
+                in_flight = !last_fragment;
+                self.send_queue.push_back(segment);
+            } else if segment.command == CMD_PUSH {
+                message.extend_from_slice(&segment.data);
+                if last_fragment {
+                    // `check_queued_messages` made sure it still fits
+                    let pushed = self.push_payload(&message, segment.expire_time).is_ok();
+                    debug_assert!(pushed);
+                    message.clear();
+                }
+                self.pool.put(segment.data);
+            } else if segment.command == CMD_CHUNK && segment.data.len() > CHUNK_HEADER_SIZE {
+                if let Some(transfer) = self.outgoing_transfers.front_mut() {
+                    transfer.requeue(segment.data.len() - CHUNK_HEADER_SIZE);
+                }
+                self.pool.put(segment.data);
+            } else {
+                self.send_queue.push_back(segment);
+            }
+        }
+    }
+
+    /// Switches streaming mode on or off. In streaming mode message boundaries aren't kept: sends
+    /// are merged into as few segments as possible and the connection is used as a byte stream
+    /// through its `Read` and `Write` implementations.
+    pub fn set_streaming_mode(&mut self, enabled: bool) {
+        self.recorder.record(
+            self.current_time,
+            CaptureKind::SetStreamingMode,
+            &[enabled as u8],
+        );
+        self.in_streaming_mode = enabled;
+    }
+
+    pub fn is_streaming_mode(&self) -> bool {
+        self.in_streaming_mode
+    }
+
+    /// Queues a message of any size for sending. Unlike `send` it isn't limited by the receive
+    /// window: the payload is split into chunks which are fed into the send queue a window at a
+    /// time, so other messages keep flowing while it is transferred. Large messages are sent one
+    /// after another and the remote gets each of them from `recv_large` once it's complete.
+    pub fn send_large(&mut self, payload: Bytes) -> ProtocolResult<TransferId> {
+        self.push_transfer(payload, None)
+    }
+
+    /// Same as `send_large` but calls `on_progress` every time more of the payload has been
+    /// acknowledged by the remote.
+    pub fn send_large_with_progress<F>(
+        &mut self,
+        payload: Bytes,
+        on_progress: F,
+    ) -> ProtocolResult<TransferId>
+    where
+        F: FnMut(TransferProgress) + Send + 'static,
+    {
+        self.push_transfer(payload, Some(Box::new(on_progress)))
+    }
+
+    /// Returns the progress of an outgoing transfer which hasn't completed yet.
+    pub fn transfer_progress(&self, id: TransferId) -> Option<TransferProgress> {
+        self.outgoing_transfers
+            .iter()
+            .find(|transfer| transfer.id == id)
+            .map(|transfer| transfer.progress())
+    }
+
+    /// Cancels an outgoing transfer. If part of it was already sent the remote is told to throw
+    /// away what it received. Returns false if the transfer already completed.
+    pub fn cancel_transfer(&mut self, id: TransferId) -> bool {
+        self.recorder.record(
+            self.current_time,
+            CaptureKind::CancelTransfer,
+            &id.0.to_be_bytes(),
+        );
+        let index = match self
+            .outgoing_transfers
+            .iter()
+            .position(|transfer| transfer.id == id)
+        {
+            Some(index) => index,
+            None => return false,
+        };
+
+        if let Some(transfer) = self.outgoing_transfers.remove(index) {
+            // Only the front transfer is ever started
+            if transfer.is_started() {
+                self.send_queue
+                    .retain(|segment| segment.command != CMD_CHUNK);
+                for segment in self.send_buffer.iter_mut() {
+                    if segment.command == CMD_CHUNK {
+                        segment.command = CMD_SKIP;
+                        segment.data.clear();
+                    }
+                }
+                self.send_queue.push_back(Segment {
+                    command: CMD_CHUNK,
+                    ..Segment::new(cancel_chunk(transfer.id))
+                });
+            }
+        }
+        true
+    }
+
+    /// Returns the next large message which was received in full.
+    pub fn recv_large(&mut self) -> Option<Bytes> {
+        self.completed_transfers.pop_front()
+    }
+
+    /// Returns the progress of the large message currently being received.
+    pub fn incoming_transfer_progress(&self) -> Option<TransferProgress> {
+        self.incoming_transfer
+            .as_ref()
+            .map(|transfer| transfer.progress())
+    }
+
+    fn push_transfer(
+        &mut self,
+        payload: Bytes,
+        on_progress: Option<crate::ProgressCallback>,
+    ) -> ProtocolResult<TransferId> {
+        self.recorder
+            .record(self.current_time, CaptureKind::SendLarge, &payload);
+        if self.wire_format == WireFormat::Kcp {
+            return Err(ProtocolError::InvalidConfiguration(
+                "wire_format: large messages need the mercury wire format.",
+            ));
+        }
+        if payload.is_empty() {
+            return Err(ProtocolError::EmptyPayload);
+        }
+        if payload.len() > u32::MAX as usize {
+            return Err(ProtocolError::PayloadTooLarge(
+                payload.len(),
+                u32::MAX as usize,
+            ));
+        }
+
+        let id = TransferId(self.next_transfer_id);
+        self.next_transfer_id = self.next_transfer_id.wrapping_add(1);
+        self.outgoing_transfers
+            .push_back(OutgoingTransfer::new(id, payload, on_progress));
+        Ok(id)
+    }
+
     /// Updates state (call it repeatedly, every 10ms-100ms), or you can ask
     /// `check` when to call it again (without `input`/`send` calling).
-    pub fn update(&mut self, current: u32) {
+    pub fn update(&mut self, current: u32) -> ProtocolResult<()> {
+        if self.update_called {
+            let elapsed = time_diff(current, self.current_time).max(0) as u32;
+            self.metrics.tick(elapsed);
+        }
         self.current_time = current;
+        self.recorder.record(current, CaptureKind::Update, &[]);
         if !self.update_called {
             self.update_called = true;
             self.next_flush_time = self.current_time;
+            self.last_send_time = self.current_time;
+            self.last_recv_time = self.current_time;
+        }
+
+        if self.connection_state != ConnectionState::Connected {
+            return Ok(());
+        }
+
+        if self.idle_timeout > 0
+            && time_diff(self.current_time, self.last_recv_time) >= self.idle_timeout as i32
+        {
+            debug!("Session {} timed out", self.session_id);
+            self.change_state(ConnectionState::TimedOut);
+            return Ok(());
         }
 
         let mut time_since = time_diff(self.current_time, self.next_flush_time);
@@ -386,8 +889,10 @@ impl ReliableConnection {
             if time_diff(self.current_time, self.next_flush_time) >= 0 {
                 self.next_flush_time = self.current_time + self.interval;
             }
-            self.flush();
+            self.flush()?;
         }
+
+        Ok(())
     }
 
     /// Determines the time when you should next call `update()`
@@ -424,19 +929,81 @@ impl ReliableConnection {
         return current + minimal;
     }
 
-    /// Change MTU size, default is DEFAULT_MTU. This method will also resize the payload_buffer
-    /// to 3 times the MTU.
+    /// Change MTU size, default is DEFAULT_MTU. It can be changed while data is being sent, e.g.
+    /// after packets were found to be fragmented: messages which haven't been sent yet are split
+    /// again, while segments already in flight keep their size and are resent in packets of their
+    /// own if they no longer fit. Fails without changing anything if a queued message would need
+    /// more fragments than the receive window holds.
     pub fn set_mtu(&mut self, mtu: usize) -> ProtocolResult<()> {
-        // TODO: KCP has this check. Why the 50?
-        if mtu < 50 || mtu < PROTOCOL_OVERHEAD {
-            return Err(ProtocolError::InvalidConfiguration("MTU too small."));
-        }
+        config::validate_mtu(mtu)?;
+        self.check_queued_messages(mtu - PROTOCOL_OVERHEAD, self.min_recv_window_size)?;
+        self.change_mtu(mtu);
+        self.recorder.record(
+            self.current_time,
+            CaptureKind::SetMtu,
+            &(mtu as u32).to_be_bytes(),
+        );
+        Ok(())
+    }
 
-        self.max_transmission_unit = mtu;
-        self.max_segment_size = self.max_transmission_unit - PROTOCOL_OVERHEAD;
-        let new_size = (mtu + PROTOCOL_OVERHEAD) * 3;
-        self.payload_buffer.resize(new_size, 0);
+    // Switches to the segment size of `mtu`. The queued messages must have been checked to fit in
+    // the receive window with it.
+    fn change_mtu(&mut self, mtu: usize) {
+        if mtu != self.max_transmission_unit {
+            self.max_transmission_unit = mtu;
+            self.max_segment_size = self.max_transmission_unit - PROTOCOL_OVERHEAD;
+            self.pool.set_buffer_size(self.max_segment_size);
+            // The payload_buffer is empty between flushes, and never shrinks so that it still
+            // holds the larger segments in flight
+            self.payload_buffer.reserve((mtu + PROTOCOL_OVERHEAD) * 3);
+            self.resegment_send_queue();
+        }
+    }
 
+    /// Applies new tuning to the running connection, e.g. switching to `Preset::Turbo` during a
+    /// match. Data which hasn't been sent yet is split again if the MTU changes (see `set_mtu`).
+    /// Fails without changing anything if the config doesn't validate or a queued message
+    /// wouldn't fit anymore.
+    pub fn reconfigure(&mut self, config: &Config) -> ProtocolResult<()> {
+        config.validate()?;
+        if config.wire_format() != self.wire_format {
+            return Err(ProtocolError::InvalidConfiguration(
+                "wire_format: can't change on a live connection.",
+            ));
+        }
+        self.check_queued_messages(config.mtu() - PROTOCOL_OVERHEAD, config.recv_window_size())?;
+
+        // Both were checked together, the MTU alone may not fit the current receive window
+        self.change_window_sizes(config.send_window_size(), config.recv_window_size());
+        self.change_mtu(config.mtu());
+        self.change_recv_window_auto_tuning(config.max_recv_window_size());
+
+        self.nodelay = config.nodelay() as u32;
+        self.minimum_rto = config.min_rto();
+        self.maximum_rto = config.max_rto();
+        self.calculated_rto = bound(self.minimum_rto, self.calculated_rto, self.maximum_rto);
+        self.interval = config.interval();
+        // Don't wait out the rest of a longer interval
+        if self.update_called
+            && time_diff(self.next_flush_time, self.current_time) > self.interval as i32
+        {
+            self.next_flush_time = self.current_time + self.interval;
+        }
+        self.fast_resend = config.fast_resend();
+        self.use_congestion_control = config.congestion_control();
+        self.dead_link = config.dead_link();
+        self.probe_init = config.probe_init();
+        self.probe_limit = config.probe_limit();
+        self.keepalive_interval = config.keepalive_interval();
+        self.idle_timeout = config.idle_timeout();
+        self.max_transfer_size = config.max_transfer_size();
+        self.metrics
+            .set_bandwidth_smoothing_factor(config.bandwidth_smoothing_factor());
+
+        let mut record = BytesMut::new();
+        capture::encode_config(&mut record, config);
+        self.recorder
+            .record(self.current_time, CaptureKind::Reconfigure, &record);
         Ok(())
     }
 
@@ -445,7 +1012,15 @@ impl ReliableConnection {
     /// `interval`: internal update timer interval in millisec, default is 100ms
     /// `resend`: 0:disable fast resend(default), 1:enable fast resend
     /// `use_congestion_control`: true: normal congestion control(default), false: disable congestion control
+    #[deprecated(note = "tune the connection with a `Config`, see `reconfigure`")]
     pub fn nodelay(&mut self, nodelay: i32, interval: i32, resend: i32, use_congestion_control: bool) {
+        let mut record = BytesMut::with_capacity(13);
+        record.put_i32_be(nodelay);
+        record.put_i32_be(interval);
+        record.put_i32_be(resend);
+        record.put_u8(use_congestion_control as u8);
+        self.recorder
+            .record(self.current_time, CaptureKind::Nodelay, &record);
         if nodelay >= 0 {
             let nodelay = nodelay as u32;
             self.nodelay = nodelay;
@@ -470,10 +1045,90 @@ impl ReliableConnection {
         self.use_congestion_control = use_congestion_control;
     }
 
-    // Sets maximum window sizes: send_window_size=32, recv_window_size=32 by default
-    pub fn set_window_sizes(&mut self, send_size: usize, recv_size: usize) {
+    /// Sets the keepalive interval in millisec, default is KEEPALIVE_INTERVAL. A keepalive is sent
+    /// whenever nothing else has been sent for this long and its reply is used as an rtt sample.
+    /// 0 disables keepalives. Fails if it isn't shorter than the idle timeout, or isn't 0 with the
+    /// kcp wire format; use `reconfigure` to change both at once.
+    pub fn set_keepalive(&mut self, interval: u32) -> ProtocolResult<()> {
+        config::validate_keepalive(interval, self.idle_timeout, self.wire_format)?;
+        self.recorder.record(
+            self.current_time,
+            CaptureKind::SetKeepalive,
+            &interval.to_be_bytes(),
+        );
+        self.keepalive_interval = interval;
+        Ok(())
+    }
+
+    /// Sets the idle timeout in millisec, default is IDLE_TIMEOUT. The connection moves to
+    /// `ConnectionState::TimedOut` when nothing has been received for this long. 0 disables the
+    /// timeout. Fails if it isn't longer than the keepalive interval.
+    pub fn set_idle_timeout(&mut self, timeout: u32) -> ProtocolResult<()> {
+        config::validate_keepalive(self.keepalive_interval, timeout, self.wire_format)?;
+        self.recorder.record(
+            self.current_time,
+            CaptureKind::SetIdleTimeout,
+            &timeout.to_be_bytes(),
+        );
+        self.idle_timeout = timeout;
+        Ok(())
+    }
+
+    /// Sets the maximum number of segments in flight and the number of segments the receive
+    /// queue holds, 32 and 32 by default. The receive window is advertised to the remote in a
+    /// u16, so it can't be larger than 65535. Messages have to fit in fewer segments than both
+    /// this receive window and the remote's, and the receive window can't be larger than the limit
+    /// of auto-tuning.
+    pub fn set_window_sizes(&mut self, send_size: usize, recv_size: usize) -> ProtocolResult<()> {
+        config::validate_window_sizes(send_size, recv_size)?;
+        config::validate_max_recv_window_size(self.max_recv_window_size, recv_size)?;
+        self.check_queued_messages(self.max_segment_size, recv_size)?;
+        self.change_window_sizes(send_size, recv_size);
+
+        let mut record = BytesMut::with_capacity(8);
+        record.put_u32_be(send_size as u32);
+        record.put_u32_be(recv_size as u32);
+        self.recorder
+            .record(self.current_time, CaptureKind::SetWindowSizes, &record);
+        Ok(())
+    }
+
+    fn change_window_sizes(&mut self, send_size: usize, recv_size: usize) {
         self.send_window_size = send_size;
         self.recv_window_size = recv_size;
+        self.min_recv_window_size = recv_size;
+        self.pool.set_limit(send_size + recv_size);
+    }
+
+    /// Lets the receive window grow up to `max_size` segments to keep up with the bandwidth-delay
+    /// product of the data received, so that a remote on a fast but distant link isn't held back
+    /// by it. It never shrinks below the size set by `set_window_sizes`, which `max_size` can't be
+    /// smaller than. `None` disables auto-tuning, which is the default.
+    pub fn set_recv_window_auto_tuning(&mut self, max_size: Option<usize>) -> ProtocolResult<()> {
+        config::validate_max_recv_window_size(max_size, self.min_recv_window_size)?;
+        self.change_recv_window_auto_tuning(max_size);
+
+        let mut record = BytesMut::with_capacity(5);
+        record.put_u8(max_size.is_some() as u8);
+        record.put_u32_be(max_size.unwrap_or(0) as u32);
+        self.recorder.record(
+            self.current_time,
+            CaptureKind::SetRecvWindowAutoTuning,
+            &record,
+        );
+        Ok(())
+    }
+
+    fn change_recv_window_auto_tuning(&mut self, max_size: Option<usize>) {
+        if max_size.is_none() {
+            self.recv_window_size = self.min_recv_window_size;
+        }
+        self.max_recv_window_size = max_size;
+    }
+
+    /// Returns the current size of the receive window, in segments.
+    pub fn recv_window_size(&self) -> usize {
+        self.recv_window_size
     }
 
     // Number of segments waiting to be sent.
@@ -481,48 +1136,101 @@ impl ReliableConnection {
         self.send_buffer.len() + self.send_queue.len()
     }
 
+    /// Returns the metrics of this connection.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Returns the histogram of every round trip time measured from the acks received.
+    pub fn rtt_histogram(&self) -> &RttHistogram {
+        &self.rtt_histogram
+    }
+
+    /// Forgets the round trip times measured so far, e.g. at the start of a match.
+    pub fn reset_rtt_histogram(&mut self) {
+        self.rtt_histogram.reset();
+    }
+
+    /// Sets the listener notified of segments being sent, received, acked and retransmitted,
+    /// window probes, congestion window changes and state transitions.
+    pub fn set_event_listener<L>(&mut self, listener: L)
+    where
+        L: EventListener + 'static,
+    {
+        self.events.set_listener(Some(Box::new(listener)));
+    }
+
+    pub fn clear_event_listener(&mut self) {
+        self.events.set_listener(None);
+    }
+
+    /// Sets the recorder which captures every `update`, packet passed to `input` or written to the
+    /// output, message sent or received and change of the settings, so the session can be
+    /// reproduced with `Replay`. It should be set right after creating the connection, whose
+    /// settings the capture starts from (see `CaptureWriter`). A recorder which fails is dropped.
+    pub fn set_recorder<R>(&mut self, recorder: R)
+    where
+        R: PacketRecorder + 'static,
+    {
+        self.recorder.set(Some(Box::new(recorder)));
+    }
+
+    pub fn clear_recorder(&mut self) {
+        self.recorder.set(None);
+    }
+
+    pub(crate) fn send_window_size(&self) -> usize {
+        self.send_window_size
+    }
+
+    // Number of segments which may be in flight at once given the local, remote and congestion
+    // windows.
+    fn effective_window_size(&self) -> usize {
+        let window_size = cmp::min(self.send_window_size, self.remote_window_size);
+        if self.use_congestion_control {
+            cmp::min(self.congestion_window_size, window_size)
+        } else {
+            window_size
+        }
+    }
+
     fn parse_data(&mut self, segment: Segment) {
         let sn = segment.sequence_num;
         if sn >= self.next_recv_sequence_num + self.recv_window_size as u32 || sn < self.next_recv_sequence_num {
+            self.pool.put(segment.data);
             return;
         }
 
-        let mut repeat = false;
-        let mut index: usize = self.recv_buffer.len();
-        for seg in self.recv_buffer.iter().rev() {
-            if sn == seg.sequence_num {
-                repeat = true;
-                break;
-            } else if sn > seg.sequence_num {
-                break;
-            }
-            index -= 1;
+        if let Err(repeat) = self.recv_buffer.insert(segment) {
+            self.pool.put(repeat.data);
         }
 
-        if !repeat {
-            self.recv_buffer.insert(index, segment);
+        // move available data from rcv_buf -> rcv_queue
+        if self.move_to_recv_queue() {
+            self.take_chunks();
+            self.drop_skipped_messages();
         }
+    }
 
-        // move available data from rcv_buf -> rcv_queue
-        index = 0;
-        let mut queue_len = self.recv_queue.len();
-        for seg in self.recv_buffer.iter() {
-            if seg.sequence_num == self.next_recv_sequence_num && queue_len < self.recv_window_size as usize {
-                queue_len += 1;
-                self.next_recv_sequence_num += 1;
-                index += 1;
-            } else {
-                break;
+    // Moves the segments which arrived in order from the recv_buffer into the room left in the
+    // recv_queue. Returns whether any were moved.
+    fn move_to_recv_queue(&mut self) -> bool {
+        let mut moved = false;
+        while self.recv_queue.len() < self.recv_window_size {
+            match self.recv_buffer.remove(self.next_recv_sequence_num) {
+                Some(segment) => {
+                    self.recv_queue.push_back(segment);
+                    self.next_recv_sequence_num += 1;
+                    moved = true;
+                }
+                None => break,
             }
         }
-        if index > 0 {
-            let new_rcv_buf = self.recv_buffer.split_off(index);
-            self.recv_queue.append(&mut self.recv_buffer);
-            self.recv_buffer = new_rcv_buf;
-        }
+        moved
     }
 
     fn update_ack(&mut self, rtt: u32) {
+        self.rtt_histogram.record(rtt);
         if self.static_rtt == 0 {
             self.static_rtt = rtt;
             self.floating_rtt = rtt >> 1;
@@ -539,7 +1247,9 @@ impl ReliableConnection {
             }
         }
         let rto = self.static_rtt + cmp::max(self.interval, 4 * self.floating_rtt);
-        self.calculated_rto = bound(self.minimum_rto, rto, RTO_MAX);
+        self.calculated_rto = bound(self.minimum_rto, rto, self.maximum_rto);
+        self.metrics
+            .set_rtt(self.static_rtt, self.floating_rtt, self.calculated_rto);
     }
 
     #[inline]
@@ -556,22 +1266,21 @@ impl ReliableConnection {
         {
             return;
         }
-        for i in 0..self.send_buffer.len() {
-            let segment = &self.send_buffer[i];
-            if sequence_num == segment.sequence_num {
-                self.send_buffer.remove(i);
-                break;
-            } else if sequence_num < segment.sequence_num {
-                break;
-            }
+        if let Some(segment) = self.send_buffer.remove(sequence_num) {
+            self.segment_acked(&segment);
+            self.pool.put(segment.data);
         }
     }
 
     fn parse_unacked(&mut self, unacked_sequence_num: u32) {
-        while let Some(segment) = self.send_buffer.pop_front() {
+        while let Some(segment) = self.send_buffer.front() {
             if unacked_sequence_num <= segment.sequence_num {
                 break;
             }
+            if let Some(segment) = self.send_buffer.pop_front() {
+                self.segment_acked(&segment);
+                self.pool.put(segment.data);
+            }
         }
     }
 
@@ -581,7 +1290,7 @@ impl ReliableConnection {
         {
             return;
         }
-        for segment in &mut self.send_buffer {
+        for segment in self.send_buffer.iter_mut() {
             if sequence_num < segment.sequence_num {
                 break;
             } else if sequence_num != segment.sequence_num {
@@ -592,51 +1301,60 @@ impl ReliableConnection {
 
     // Flushes pending data.
     // TODO: Go over how this works again and refactor if necessary.
-    fn flush(&mut self) {
+    fn flush(&mut self) -> ProtocolResult<()> {
         if !self.update_called {
-            return;
+            return Ok(());
         }
 
         let current = self.current_time;
+        let old_congestion_window = self.congestion_window_size;
         let mut lost = false;
         let mut change = false;
+        let mut dead_link = false;
 
-        let mut segment = Segment::default();
-        segment.session_id = self.session_id;
-        segment.command = CMD_ACK;
-        segment.window_size = self.num_open_slots_in_recv_queue() as u16;
-        segment.unacked_sequence_num = self.next_recv_sequence_num;
+        self.tune_recv_window();
+
+        let mut segment = Segment {
+            session_id: self.session_id,
+            command: CMD_ACK,
+            window_size: self.num_open_slots_in_recv_queue() as u16,
+            unacked_sequence_num: self.next_recv_sequence_num,
+            ..Segment::default()
+        };
 
         // flush acknowledges
         for (sequence_num, timestamp) in self.ack_list.iter() {
-            if self.payload_buffer.remaining_mut() + PROTOCOL_OVERHEAD > self.max_transmission_unit
-            {
-                // TODO: Write out bytes
-                self.payload_buffer.clear();
+            if self.payload_buffer.len() + PROTOCOL_OVERHEAD > self.max_transmission_unit {
+                write_packet(
+                    &mut self.output,
+                    &mut self.payload_buffer,
+                    &mut self.metrics,
+                    &mut self.recorder,
+                    current,
+                )?;
+                self.last_send_time = current;
             }
             segment.sequence_num = *sequence_num;
             segment.timestamp = *timestamp;
-            segment.encode(&mut self.payload_buffer);
+            segment.encode(&mut self.payload_buffer, self.wire_format);
         }
         self.ack_list.clear();
 
         // probe window size (if remote window size equals zero)
         if self.remote_window_size == 0 {
             if self.probe_wait == 0 {
-                self.probe_wait = PROBE_INIT;
+                self.probe_wait = self.probe_init;
                 self.next_probe_time = self.current_time + self.probe_wait;
-            } else {
-                if time_diff(self.current_time, self.next_probe_time) >= 0 {
-                    if self.probe_wait < PROBE_INIT {
-                        self.probe_wait = PROBE_INIT;
-                    }
-                    self.probe_wait += self.probe_wait / 2;
-                    if self.probe_wait > PROBE_LIMIT {
-                        self.probe_wait = PROBE_LIMIT;
-                    }
-                    self.next_probe_time = self.current_time + self.probe_wait;
-                    self.probe |= ASK_SEND;
+            } else if time_diff(self.current_time, self.next_probe_time) >= 0 {
+                if self.probe_wait < self.probe_init {
+                    self.probe_wait = self.probe_init;
+                }
+                self.probe_wait += self.probe_wait / 2;
+                if self.probe_wait > self.probe_limit {
+                    self.probe_wait = self.probe_limit;
                 }
+                self.next_probe_time = self.current_time + self.probe_wait;
+                self.probe |= ASK_SEND;
             }
         } else {
             self.next_probe_time = 0;
@@ -646,40 +1364,74 @@ impl ReliableConnection {
         // flush window probing commands
         if (self.probe & ASK_SEND) != 0 {
             segment.command = CMD_WASK;
-            if self.payload_buffer.remaining_mut() + PROTOCOL_OVERHEAD > self.max_transmission_unit
-            {
-                // TODO: Write out bytes
-                self.payload_buffer.clear();
+            if self.payload_buffer.len() + PROTOCOL_OVERHEAD > self.max_transmission_unit {
+                write_packet(
+                    &mut self.output,
+                    &mut self.payload_buffer,
+                    &mut self.metrics,
+                    &mut self.recorder,
+                    current,
+                )?;
+                self.last_send_time = current;
             }
-            segment.encode(&mut self.payload_buffer);
+            segment.encode(&mut self.payload_buffer, self.wire_format);
+            self.events.emit(current, Event::WindowProbeSent);
         }
 
         // flush window probing commands
         if (self.probe & ASK_TELL) != 0 {
             segment.command = CMD_WINS;
-            if self.payload_buffer.remaining_mut() + PROTOCOL_OVERHEAD > self.max_transmission_unit
-            {
-                // TODO: Write out bytes
-                self.payload_buffer.clear();
+            if self.payload_buffer.len() + PROTOCOL_OVERHEAD > self.max_transmission_unit {
+                write_packet(
+                    &mut self.output,
+                    &mut self.payload_buffer,
+                    &mut self.metrics,
+                    &mut self.recorder,
+                    current,
+                )?;
+                self.last_send_time = current;
             }
-            segment.encode(&mut self.payload_buffer);
+            segment.encode(&mut self.payload_buffer, self.wire_format);
+            self.events.emit(
+                current,
+                Event::WindowSizeSent {
+                    window_size: segment.window_size,
+                },
+            );
         }
 
         self.probe = 0;
 
-        // calculate window size
-        let mut congestion_window_size = cmp::min(self.send_window_size, self.remote_window_size);
-        if self.use_congestion_control {
-            congestion_window_size = cmp::min(self.congestion_window_size, congestion_window_size);
+        // answer the last keepalive received by echoing its timestamp
+        if let Some(timestamp) = self.pending_pong.take() {
+            segment.command = CMD_PONG;
+            segment.timestamp = timestamp;
+            if self.payload_buffer.len() + PROTOCOL_OVERHEAD > self.max_transmission_unit {
+                write_packet(
+                    &mut self.output,
+                    &mut self.payload_buffer,
+                    &mut self.metrics,
+                    &mut self.recorder,
+                    current,
+                )?;
+                self.last_send_time = current;
+            }
+            segment.encode(&mut self.payload_buffer, self.wire_format);
         }
 
+        // calculate window size
+        let congestion_window_size = self.effective_window_size();
+
+        self.queue_transfer_chunks();
+        self.expire_segments();
+
         // move data from send_queue to send_buffer
         while self.next_send_sequence_num
             < self.unacked_send_sequence_num + congestion_window_size as u32
         {
             if let Some(mut new_segment) = self.send_queue.pop_front() {
+                self.mid_message = new_segment.fragment_id != 0;
                 new_segment.session_id = self.session_id;
-                new_segment.command = CMD_PUSH;
                 new_segment.window_size = segment.window_size;
                 new_segment.timestamp = current;
                 new_segment.sequence_num = self.next_send_sequence_num;
@@ -694,12 +1446,13 @@ impl ReliableConnection {
                 break;
             }
         }
+        self.wake_stream_tasks();
 
         // calculate resent
         let resent = if self.fast_resend > 0 {
             self.fast_resend
         } else {
-            u32::max_value()
+            u32::MAX
         };
         let rto_min = if self.nodelay == 0 {
             self.calculated_rto >> 3
@@ -712,11 +1465,20 @@ impl ReliableConnection {
             let mut need_send = false;
             if buffer_segment.xmit == 0 {
                 need_send = true;
+                self.metrics.record_transmission(false);
                 buffer_segment.xmit += 1;
                 buffer_segment.rto = self.calculated_rto;
                 buffer_segment.resend_time = current + buffer_segment.rto + rto_min;
+                self.events.emit(
+                    current,
+                    Event::SegmentSent {
+                        sequence_num: buffer_segment.sequence_num,
+                        len: buffer_segment.data.len(),
+                    },
+                );
             } else if time_diff(current, buffer_segment.resend_time) >= 0 {
                 need_send = true;
+                self.metrics.record_transmission(true);
                 buffer_segment.xmit += 1;
                 self.xmit += 1;
                 if self.nodelay == 0 {
@@ -726,12 +1488,28 @@ impl ReliableConnection {
                 }
                 buffer_segment.resend_time = current + buffer_segment.rto;
                 lost = true;
+                self.events.emit(
+                    current,
+                    Event::SegmentRetransmitted {
+                        sequence_num: buffer_segment.sequence_num,
+                        transmissions: buffer_segment.xmit,
+                    },
+                );
             } else if buffer_segment.fastack >= resent {
                 need_send = true;
+                self.metrics.record_transmission(true);
+                self.metrics.increment(DataPoint::SegmentsFastRetransmitted);
                 buffer_segment.xmit += 1;
                 buffer_segment.fastack = 0;
                 buffer_segment.resend_time = current + buffer_segment.rto;
                 change = true;
+                self.events.emit(
+                    current,
+                    Event::SegmentFastRetransmitted {
+                        sequence_num: buffer_segment.sequence_num,
+                        transmissions: buffer_segment.xmit,
+                    },
+                );
             }
 
             if need_send {
@@ -742,23 +1520,50 @@ impl ReliableConnection {
                 let len = buffer_segment.data.len();
                 let need = PROTOCOL_OVERHEAD + len;
 
-                if self.payload_buffer.remaining_mut() + need > self.max_transmission_unit {
-                    // TODO: Need to write here.
-                    self.payload_buffer.clear();
+                if self.payload_buffer.len() + need > self.max_transmission_unit {
+                    write_packet(
+                        &mut self.output,
+                        &mut self.payload_buffer,
+                        &mut self.metrics,
+                        &mut self.recorder,
+                        current,
+                    )?;
+                    self.last_send_time = current;
                 }
-                buffer_segment.encode(&mut self.payload_buffer);
+                buffer_segment.encode(&mut self.payload_buffer, self.wire_format);
 
-                // never used
-                // if segment.xmit >= self.dead_link {
-                //     self.state = -1;
-                // }
+                if buffer_segment.xmit >= self.dead_link {
+                    dead_link = true;
+                }
             }
         }
 
+        // send a keepalive if nothing has been sent for a while
+        if self.keepalive_interval > 0
+            && self.wire_format == WireFormat::Mercury
+            && self.payload_buffer.is_empty()
+            && time_diff(current, self.last_send_time) >= self.keepalive_interval as i32
+        {
+            segment.command = CMD_PING;
+            segment.timestamp = current;
+            segment.encode(&mut self.payload_buffer, self.wire_format);
+        }
+
         // flush remaining segments
-        if self.payload_buffer.remaining_mut() > 0 {
-            // TODO: Need to write here.
-            self.payload_buffer.clear();
+        if !self.payload_buffer.is_empty() {
+            write_packet(
+                &mut self.output,
+                &mut self.payload_buffer,
+                &mut self.metrics,
+                &mut self.recorder,
+                current,
+            )?;
+            self.last_send_time = current;
+        }
+
+        if dead_link {
+            debug!("Session {} lost its link", self.session_id);
+            self.change_state(ConnectionState::DeadLink);
         }
 
         // update ssthresh
@@ -785,51 +1590,478 @@ impl ReliableConnection {
             self.congestion_window_size = 1;
             self.incr = self.max_segment_size as u32;
         }
+        self.metrics
+            .set_congestion_window(self.congestion_window_size as u32);
+        self.report_congestion_window(old_congestion_window);
+
+        Ok(())
     }
 
-    // Calculates the number of open slots in the receive queue based on the set recv window size.
-    fn num_open_slots_in_recv_queue(&self) -> usize {
-        if self.recv_queue.len() < self.recv_window_size {
-            self.recv_window_size - self.recv_queue.len()
-        } else {
-            0
+    // Sizes the recv window to twice the bandwidth-delay product of the data received when it's
+    // auto-tuned. While the remote is held back by the window the bandwidth measured grows with it,
+    // so the window keeps doubling until the link is saturated. It never shrinks below the
+    // fragments of a message partly in the recv_queue, which could no longer be delivered.
+    fn tune_recv_window(&mut self) {
+        let max_size = match self.max_recv_window_size {
+            Some(max_size) => cmp::max(max_size, self.min_recv_window_size),
+            None => return,
+        };
+        // Without a round trip time there's no bandwidth-delay product
+        if self.static_rtt == 0 {
+            return;
         }
-    }
-}
 
-#[inline]
-fn time_diff(later: u32, earlier: u32) -> i32 {
-    later as i32 - earlier as i32
-}
+        // kbit/s to bytes per millisec
+        let bytes_per_ms = f64::from(self.metrics.received_bandwidth_kbps()) / 8.0;
+        let bandwidth_delay_product = bytes_per_ms * f64::from(self.static_rtt);
+        let segments = (2.0 * bandwidth_delay_product / self.max_segment_size as f64).ceil();
+        let partial_message = match self.recv_queue.front() {
+            Some(segment) if !self.in_streaming_mode => usize::from(segment.fragment_id) + 1,
+            _ => 0,
+        };
+        let min_size = cmp::max(self.min_recv_window_size, partial_message);
+        self.recv_window_size = (segments as usize).clamp(min_size, cmp::max(max_size, min_size));
+    }
 
-#[inline]
-fn bound(lower: u32, value: u32, upper: u32) -> u32 {
-    cmp::min(cmp::max(lower, value), upper)
-}
+    fn change_state(&mut self, to: ConnectionState) {
+        let from = mem::replace(&mut self.connection_state, to);
+        self.events
+            .emit(self.current_time, Event::StateChanged { from, to });
+    }
 
-#[cfg(test)]
-mod test {
-    use super::{time_diff, ProtocolError, ReliableConnection, Segment};
-    use bytes::BytesMut;
-    use std::io::Bytes;
-    use std::{
-        thread,
-        time::{Duration, SystemTime},
-    };
+    fn report_congestion_window(&mut self, from: usize) {
+        if self.congestion_window_size != from {
+            self.events.emit(
+                self.current_time,
+                Event::CongestionWindowChanged {
+                    from,
+                    to: self.congestion_window_size,
+                },
+            );
+        }
+    }
 
-    #[test]
-    fn test_recv_with_empty_queue() {
-        let mut connection = ReliableConnection::new(0);
-        let mut buffer = Vec::with_capacity(10);
-        assert_eq!(
-            connection.recv(&mut buffer).unwrap_err(),
-            ProtocolError::EmptyRecvQueue
-        );
+    // Feeds chunks of the current outgoing transfer into the send_queue, never queueing more than
+    // a window of segments at a time.
+    fn queue_transfer_chunks(&mut self) {
+        if let Some(transfer) = self.outgoing_transfers.front_mut() {
+            while !transfer.is_fully_queued() && self.send_queue.len() < self.send_window_size {
+                let chunk = transfer.next_chunk(self.max_segment_size);
+                self.send_queue.push_back(Segment {
+                    command: CMD_CHUNK,
+                    ..Segment::new(chunk)
+                });
+            }
+        }
     }
 
-    #[test]
+    // Records an acknowledged segment and credits the current outgoing transfer with its data if
+    // it was a chunk.
+    fn segment_acked(&mut self, segment: &Segment) {
+        self.metrics.record_acked(segment.data.len());
+        self.events.emit(
+            self.current_time,
+            Event::SegmentAcked {
+                sequence_num: segment.sequence_num,
+            },
+        );
+        if segment.command != CMD_CHUNK {
+            return;
+        }
+        if let Some(transfer) = self.outgoing_transfers.front_mut() {
+            transfer.acked += segment.data.len() - CHUNK_HEADER_SIZE;
+        }
+    }
+
+    // Reports progress on the current outgoing transfer and moves on to the next one once it has
+    // been fully acknowledged.
+    fn report_transfer_progress(&mut self) {
+        while let Some(transfer) = self.outgoing_transfers.front_mut() {
+            transfer.report_progress();
+            if !transfer.is_complete() {
+                break;
+            }
+            self.outgoing_transfers.pop_front();
+        }
+    }
+
+    // Moves the chunks of large messages out of the recv_queue and into the transfer they belong
+    // to.
+    fn take_chunks(&mut self) {
+        let incoming_transfer = &mut self.incoming_transfer;
+        let completed_transfers = &mut self.completed_transfers;
+        let pool = &mut self.pool;
+        let max_transfer_size = self.max_transfer_size;
+        self.recv_queue.retain_mut(|segment| {
+            if segment.command != CMD_CHUNK {
+                return true;
+            }
+            match receive_chunk(incoming_transfer, &segment.data, max_transfer_size) {
+                Chunk::Partial => {}
+                Chunk::Complete(data) => completed_transfers.push_back(data),
+                Chunk::Cancelled => debug!("Remote cancelled its transfer"),
+                Chunk::Invalid => debug!("Dropping invalid chunk"),
+            }
+            pool.put(mem::take(&mut segment.data));
+            false
+        });
+    }
+
+    // Drops expired messages which haven't been sent yet and replaces the segments of expired
+    // messages which are already in flight with empty CMD_SKIP segments. KCP has no way to skip a
+    // message, so with its wire format the ones in flight are still delivered.
+    fn expire_segments(&mut self) {
+        let current = self.current_time;
+        let expired = |segment: &Segment| {
+            segment
+                .expire_time
+                .is_some_and(|expire_time| time_diff(current, expire_time) >= 0)
+        };
+        let can_skip = self.wire_format == WireFormat::Mercury;
+
+        for segment in self.send_buffer.iter_mut() {
+            if can_skip && segment.command == CMD_PUSH && expired(segment) {
+                segment.command = CMD_SKIP;
+                segment.data.clear();
+            }
+        }
+
+        // The rest of a message which is partially in flight has to be skipped rather than dropped
+        // so that the remote still receives its final fragment.
+        let mut in_flight = self.mid_message;
+        let mut index = 0;
+        while index < self.send_queue.len() {
+            let segment = &mut self.send_queue[index];
+            let last_fragment = segment.fragment_id == 0;
+            if !expired(segment) {
+                index += 1;
+            } else if in_flight {
+                if can_skip {
+                    segment.command = CMD_SKIP;
+                    segment.data.clear();
+                }
+                index += 1;
+            } else if let Some(segment) = self.send_queue.remove(index) {
+                self.pool.put(segment.data);
+            }
+            if last_fragment {
+                in_flight = false;
+            }
+        }
+    }
+
+    // Removes complete messages from the front of the recv_queue which the remote skipped.
+    fn drop_skipped_messages(&mut self) {
+        while let Some(end) = self
+            .recv_queue
+            .iter()
+            .position(|segment| segment.fragment_id == 0)
+        {
+            let skipped = self
+                .recv_queue
+                .iter()
+                .take(end + 1)
+                .any(|segment| segment.command == CMD_SKIP);
+            if !skipped {
+                break;
+            }
+            debug!("Dropping skipped message of {} segments", end + 1);
+            for segment in self.recv_queue.drain(..=end) {
+                self.pool.put(segment.data);
+            }
+        }
+    }
+
+    // Calculates the number of open slots in the receive queue based on the set recv window size.
+    fn num_open_slots_in_recv_queue(&self) -> usize {
+        if self.recv_queue.len() < self.recv_window_size {
+            self.recv_window_size - self.recv_queue.len()
+        } else {
+            0
+        }
+    }
+
+    // Wakes the tasks waiting on the connection's `AsyncRead` and `AsyncWrite` implementations
+    // once they can make progress.
+    fn wake_stream_tasks(&mut self) {
+        #[cfg(feature = "futures-io")]
+        {
+            if !self.recv_queue.is_empty() {
+                if let Some(waker) = self.read_waker.take() {
+                    waker.wake();
+                }
+            }
+            if self.send_queue.len() < self.send_window_size {
+                if let Some(waker) = self.write_waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+
+    fn check_streaming_mode(&self) -> io::Result<()> {
+        if self.in_streaming_mode {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "connection is not in streaming mode",
+            ))
+        }
+    }
+}
+
+/// Reads the received byte stream. Fails with `WouldBlock` when nothing has been received yet.
+/// Only available in streaming mode.
+impl<W: Write> Read for ReliableConnection<W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.check_streaming_mode()?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let fast_recover = self.recv_queue.len() >= self.recv_window_size;
+        let mut read = 0;
+        while read < buf.len() {
+            let segment = match self.recv_queue.front_mut() {
+                Some(segment) => segment,
+                None => break,
+            };
+            let len = cmp::min(buf.len() - read, segment.data.len());
+            buf[read..read + len].copy_from_slice(&segment.data[..len]);
+            read += len;
+            if len == segment.data.len() {
+                if let Some(segment) = self.recv_queue.pop_front() {
+                    self.pool.put(segment.data);
+                }
+            } else {
+                // Splitting off the bytes read would leave a buffer too small for the pool, the
+                // rest is moved to another one instead
+                let mut rest = self.pool.take();
+                rest.extend_from_slice(&segment.data[len..]);
+                self.pool.put(mem::replace(&mut segment.data, rest));
+            }
+        }
+
+        if read == 0 {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.recorder
+            .record(self.current_time, CaptureKind::Read, &buf[..read]);
+        self.refill_recv_queue(fast_recover);
+        Ok(read)
+    }
+}
+
+/// Queues bytes to be sent on the next `update`. Fails with `WouldBlock` while a full window of
+/// segments is already waiting to be sent. Only available in streaming mode.
+impl<W: Write> Write for ReliableConnection<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_streaming_mode()?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let open_slots = self.send_window_size.saturating_sub(self.send_queue.len());
+        if open_slots == 0 {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let len = cmp::min(buf.len(), open_slots * self.max_segment_size);
+        self.send(&buf[..len])?;
+        Ok(len)
+    }
+
+    /// Data is only sent on `update`, so there is nothing to flush here.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl<W: Write + Unpin> AsyncRead for ReliableConnection<W> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let connection = self.get_mut();
+        match connection.read(buf) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                connection.read_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            result => Poll::Ready(result),
+        }
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl<W: Write + Unpin> AsyncWrite for ReliableConnection<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let connection = self.get_mut();
+        match connection.write(buf) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                connection.write_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            result => Poll::Ready(result),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+// A packet given to `input`, from which the payloads of received segments are split off.
+trait InputPacket {
+    // Returns the part of the packet which wasn't parsed yet.
+    fn remaining(&self) -> &[u8];
+
+    fn advance(&mut self, cnt: usize);
+
+    // Splits off the payload of a segment, copying it into a buffer from `pool` if needed.
+    fn split_to(&mut self, len: usize, pool: &mut BufferPool) -> BytesMut;
+}
+
+impl InputPacket for &[u8] {
+    fn remaining(&self) -> &[u8] {
+        self
+    }
+
+    fn advance(&mut self, cnt: usize) {
+        *self = &self[cnt..];
+    }
+
+    fn split_to(&mut self, len: usize, pool: &mut BufferPool) -> BytesMut {
+        let mut data = pool.take();
+        data.extend_from_slice(&self[..len]);
+        self.advance(len);
+        data
+    }
+}
+
+impl InputPacket for BytesMut {
+    fn remaining(&self) -> &[u8] {
+        self
+    }
+
+    fn advance(&mut self, cnt: usize) {
+        BytesMut::advance(self, cnt);
+    }
+
+    fn split_to(&mut self, len: usize, _pool: &mut BufferPool) -> BytesMut {
+        BytesMut::split_to(self, len)
+    }
+}
+
+// Writes the buffered segments out as a single packet.
+fn write_packet<W: Write>(
+    output: &mut W,
+    buffer: &mut BytesMut,
+    metrics: &mut Metrics,
+    recorder: &mut Recorder,
+    current: u32,
+) -> ProtocolResult<()> {
+    output.write_all(buffer)?;
+    metrics.record_sent(buffer.len());
+    recorder.record(current, CaptureKind::Outbound, buffer);
+    buffer.clear();
+    Ok(())
+}
+
+#[inline]
+pub(crate) fn time_diff(later: u32, earlier: u32) -> i32 {
+    later as i32 - earlier as i32
+}
+
+#[inline]
+fn bound(lower: u32, value: u32, upper: u32) -> u32 {
+    cmp::min(cmp::max(lower, value), upper)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        time_diff, ConnectionState, ProtocolError, ReliableConnection, Segment, PROTOCOL_OVERHEAD,
+    };
+    use crate::{
+        Config, DataPoint, Event, Preset, WireFormat, CMD_PING, CMD_PONG, CMD_PUSH, CMD_SKIP,
+        IDLE_TIMEOUT, KEEPALIVE_INTERVAL, PROBE_INIT,
+    };
+    use bytes::{Bytes, BytesMut};
+    use std::{
+        io::{self, Read, Write},
+        sync::{Arc, Mutex},
+    };
+
+    // Collects every packet written by a connection.
+    #[derive(Default)]
+    struct PacketSink(Vec<Vec<u8>>);
+
+    impl Write for PacketSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn new_connection() -> ReliableConnection<PacketSink> {
+        ReliableConnection::new(0, PacketSink::default())
+    }
+
+    // Delivers every packet written by `from` to `to`.
+    fn pump(from: &mut ReliableConnection<PacketSink>, to: &mut ReliableConnection<PacketSink>) {
+        for packet in from.output.0.drain(..) {
+            to.input(&packet).unwrap();
+        }
+    }
+
+    // Updates both connections every 100ms and delivers their packets until `done` returns true.
+    fn run_until<F>(
+        client: &mut ReliableConnection<PacketSink>,
+        server: &mut ReliableConnection<PacketSink>,
+        mut done: F,
+    ) where
+        F: FnMut(&mut ReliableConnection<PacketSink>, &mut ReliableConnection<PacketSink>) -> bool,
+    {
+        for step in 0..1_000 {
+            client.update(step * 100).unwrap();
+            server.update(step * 100).unwrap();
+            pump(client, server);
+            pump(server, client);
+            if done(client, server) {
+                return;
+            }
+        }
+        panic!("connections never reached the expected state");
+    }
+
+    #[test]
+    fn test_recv_with_empty_queue() {
+        let mut connection = new_connection();
+        let mut buffer = Vec::with_capacity(10);
+        assert_eq!(
+            connection.recv(&mut buffer).unwrap_err(),
+            ProtocolError::EmptyRecvQueue
+        );
+    }
+
+    #[test]
     fn test_recv_with_too_small_buffer() {
-        let mut connection = ReliableConnection::new(0);
+        let mut connection = new_connection();
         let mut buffer = Vec::new();
         connection
             .recv_queue
@@ -840,11 +2072,61 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_input_bytes_keeps_payloads_in_place() {
+        let mut sender = new_connection();
+        let mut receiver = new_connection();
+        sender.send(b"hello").unwrap();
+        sender.send(&[7; 3_000]).unwrap();
+        sender.update(0).unwrap();
+
+        let mut regions = Vec::new();
+        for packet in sender.output.0.drain(..) {
+            let packet = Bytes::from(packet);
+            let start = packet.as_ptr() as usize;
+            regions.push(start..start + packet.len());
+            receiver.input_bytes(packet).unwrap();
+        }
+        let in_place = |data: &Bytes| {
+            let start = data.as_ptr() as usize;
+            regions
+                .iter()
+                .any(|region| region.contains(&start) && start + data.len() <= region.end)
+        };
+
+        let message = receiver.recv_bytes().unwrap();
+        assert_eq!(&message[..], b"hello");
+        assert!(in_place(&message));
+
+        let fragments = receiver.recv_fragments().unwrap();
+        assert_eq!(fragments.len(), 3);
+        assert_eq!(fragments.iter().map(Bytes::len).sum::<usize>(), 3_000);
+        assert!(fragments.iter().all(in_place));
+        assert_eq!(
+            receiver.recv_bytes().unwrap_err(),
+            ProtocolError::EmptyRecvQueue
+        );
+    }
+
+    #[test]
+    fn test_recv_bytes_joins_fragments() {
+        let mut sender = new_connection();
+        let mut receiver = new_connection();
+        let payload = (0..4_000).map(|i| i as u8).collect::<Vec<_>>();
+        sender.send(&payload).unwrap();
+        sender.update(0).unwrap();
+        for packet in sender.output.0.drain(..) {
+            receiver.input_bytes(Bytes::from(packet)).unwrap();
+        }
+        assert_eq!(receiver.peek_size().unwrap(), payload.len());
+        assert_eq!(&receiver.recv_bytes().unwrap()[..], &payload[..]);
+    }
+
     // TODO: Add many more tests around recv
 
     #[test]
     fn test_open_slots_in_recv_queue() {
-        let mut connection = ReliableConnection::new(0);
+        let mut connection = new_connection();
         assert_eq!(connection.recv_window_size, 32);
         assert_eq!(connection.num_open_slots_in_recv_queue(), 32);
         for _ in 0..32 {
@@ -852,13 +2134,156 @@ mod test {
         }
 
         assert_eq!(connection.num_open_slots_in_recv_queue(), 0);
-        connection.set_window_sizes(32, 0);
+        connection.set_window_sizes(32, 16).unwrap();
         assert_eq!(connection.num_open_slots_in_recv_queue(), 0);
     }
 
+    #[test]
+    fn test_window_sizes_validated() {
+        let mut connection = new_connection();
+        assert_eq!(
+            connection.set_window_sizes(0, 32).unwrap_err(),
+            ProtocolError::InvalidConfiguration("")
+        );
+        assert!(connection.set_window_sizes(32, 65_536).is_err());
+        assert!(connection
+            .set_recv_window_auto_tuning(Some(65_536))
+            .is_err());
+        assert!(connection.set_window_sizes(32, 65_535).is_ok());
+        assert_eq!(connection.recv_window_size(), 65_535);
+    }
+
+    #[test]
+    fn test_with_config_applies_tuning() {
+        let config = Config::default()
+            .with_preset(Preset::Turbo)
+            .with_mtu(500)
+            .with_window_sizes(64, 128)
+            .with_window_probe(1_000, 4_000);
+        let connection =
+            ReliableConnection::with_config(0, PacketSink::default(), &config).unwrap();
+        assert_eq!(connection.max_segment_size, 500 - PROTOCOL_OVERHEAD);
+        assert_eq!(connection.send_window_size, 64);
+        assert_eq!(connection.recv_window_size(), 128);
+        assert_eq!(connection.interval, 10);
+        assert_eq!(connection.fast_resend, 2);
+        assert_eq!(connection.minimum_rto, 30);
+        assert_eq!(connection.probe_limit, 4_000);
+
+        let invalid = config.with_interval(0);
+        assert_eq!(
+            ReliableConnection::with_config(0, PacketSink::default(), &invalid).err(),
+            Some(ProtocolError::InvalidConfiguration(""))
+        );
+    }
+
+    #[test]
+    fn test_fragment_limit_follows_recv_window() {
+        let mut connection = new_connection();
+        let payload = vec![0; 32 * connection.max_segment_size];
+        assert_eq!(
+            connection.send(&payload).unwrap_err(),
+            ProtocolError::FragmentsGreaterThanWindowSize
+        );
+        connection.set_window_sizes(32, 64).unwrap();
+        // The remote may still have the default window
+        assert_eq!(
+            connection.send(&payload).unwrap_err(),
+            ProtocolError::FragmentsGreaterThanWindowSize
+        );
+        connection.remote_recv_window_size = Some(64);
+        assert!(connection.send(&payload).is_ok());
+
+        connection.set_window_sizes(32, 1_024).unwrap();
+        connection.remote_recv_window_size = Some(1_024);
+        let payload = vec![0; 257 * connection.max_segment_size];
+        assert_eq!(
+            connection.send(&payload).unwrap_err(),
+            ProtocolError::PayloadTooLarge(0, 0)
+        );
+    }
+
+    #[test]
+    fn test_fragment_limit_follows_remote_window() {
+        let mut buffer = vec![0; 200_000];
+        let mut receive = |client: &mut _, server: &mut ReliableConnection<PacketSink>| {
+            let mut len = None;
+            run_until(client, server, |_, server| {
+                len = server.recv(&mut buffer).ok();
+                len.is_some()
+            });
+            len.unwrap()
+        };
+
+        for server_window_size in [32, 128] {
+            let mut client = new_connection();
+            client.set_window_sizes(32, 256).unwrap();
+            let mut server = new_connection();
+            server.set_window_sizes(32, server_window_size).unwrap();
+
+            // Until the remote advertises its window it's assumed to have the default one
+            let payload = vec![0; 100 * client.max_segment_size];
+            assert_eq!(
+                client.send(&payload).unwrap_err(),
+                ProtocolError::FragmentsGreaterThanWindowSize
+            );
+            client.send(b"hello").unwrap();
+            assert_eq!(receive(&mut client, &mut server), 5);
+            run_until(&mut client, &mut server, |client, _| {
+                client.send_buffer.is_empty()
+            });
+
+            if server_window_size == 32 {
+                assert_eq!(
+                    client.send(&payload).unwrap_err(),
+                    ProtocolError::FragmentsGreaterThanWindowSize
+                );
+            } else {
+                client.send(&payload).unwrap();
+                assert_eq!(receive(&mut client, &mut server), payload.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_recv_window_auto_tuned_by_bandwidth_delay_product() {
+        let mut connection = new_connection();
+        connection.set_recv_window_auto_tuning(Some(1_024)).unwrap();
+        // No round trip time measured yet
+        connection.update(0).unwrap();
+        assert_eq!(connection.recv_window_size(), 32);
+
+        // 8000 kbit/s over a 200ms round trip is 200KB in flight
+        connection.static_rtt = 200;
+        connection
+            .metrics
+            .calculate_receive_bandwidth(10_000_000, 1.0);
+        connection.tune_recv_window();
+        assert_eq!(connection.recv_window_size(), 291);
+
+        connection.set_recv_window_auto_tuning(Some(100)).unwrap();
+        connection.tune_recv_window();
+        assert_eq!(connection.recv_window_size(), 100);
+
+        // The window doesn't shrink below a message partly in the recv_queue
+        connection.recv_queue.push_back(Segment {
+            fragment_id: 59,
+            ..Segment::new(BytesMut::new())
+        });
+        connection.static_rtt = 1;
+        connection.tune_recv_window();
+        assert_eq!(connection.recv_window_size(), 60);
+        connection.recv_queue.clear();
+        connection.tune_recv_window();
+        assert_eq!(connection.recv_window_size(), 32);
+
+        connection.set_recv_window_auto_tuning(None).unwrap();
+        assert_eq!(connection.recv_window_size(), 32);
+    }
+
     #[test]
     fn test_peek_size() {
-        let connection = ReliableConnection::new(0);
+        let connection = new_connection();
         assert_eq!(
             connection.peek_size().unwrap_err(),
             ProtocolError::IncompleteMessage
@@ -869,9 +2294,9 @@ mod test {
 
     #[test]
     fn test_send_with_empty_buffer_throws_error() {
-        let mut connection = ReliableConnection::new(0);
+        let mut connection = new_connection();
         assert_eq!(
-            connection.send(&vec![]).unwrap_err(),
+            connection.send(&[]).unwrap_err(),
             ProtocolError::EmptyPayload
         );
     }
@@ -880,7 +2305,7 @@ mod test {
 
     #[test]
     fn test_set_mtu_error_when_too_small() {
-        let mut connection = ReliableConnection::new(0);
+        let mut connection = new_connection();
         // Errors when too small
         assert_eq!(
             connection.set_mtu(0).unwrap_err(),
@@ -893,23 +2318,141 @@ mod test {
     }
 
     #[test]
-    fn test_set_mtu_resize_when_large_truncate_when_small() {
-        let mut connection = ReliableConnection::new(0);
+    fn test_set_mtu_keeps_payload_buffer_empty() {
+        let mut connection = new_connection();
         assert_eq!(connection.payload_buffer.len(), 0);
         assert_eq!(connection.payload_buffer.capacity(), 4272);
 
+        // Lowering the MTU doesn't shrink the buffer, segments sent with the previous one may
+        // still have to be resent
         assert!(connection.set_mtu(50).is_ok());
         assert_eq!(connection.max_transmission_unit, 50);
         assert_eq!(connection.max_segment_size, 26);
-        assert_eq!(connection.payload_buffer.len(), 222);
+        assert_eq!(connection.payload_buffer.len(), 0);
         assert_eq!(connection.payload_buffer.capacity(), 4272);
 
-        // Looks like Bytes doubles its buffer when resized.
         assert!(connection.set_mtu(1500).is_ok());
         assert_eq!(connection.max_transmission_unit, 1500);
         assert_eq!(connection.max_segment_size, 1476);
-        assert_eq!(connection.payload_buffer.len(), 4572);
-        assert_eq!(connection.payload_buffer.capacity(), 8544);
+        assert_eq!(connection.payload_buffer.len(), 0);
+        assert!(connection.payload_buffer.capacity() >= 4572);
+    }
+
+    #[test]
+    fn test_set_mtu_resegments_queued_messages() {
+        let mut client = new_connection();
+        let mut server = new_connection();
+        client.set_window_sizes(2, 32).unwrap();
+        let first: Vec<u8> = (0..5_000).map(|i| i as u8).collect();
+        let second: Vec<u8> = (0..3_000).map(|i| (i * 7) as u8).collect();
+        client.send(&first).unwrap();
+        client.send(&second).unwrap();
+        client.update(0).unwrap();
+        assert_eq!(client.send_buffer.len(), 2);
+
+        // The rest of the first message keeps its fragments, the second one is split again
+        client.set_mtu(500).unwrap();
+        let fragment_ids: Vec<u8> = client
+            .send_queue
+            .iter()
+            .map(|segment| segment.fragment_id)
+            .collect();
+        assert_eq!(fragment_ids, vec![1, 0, 6, 5, 4, 3, 2, 1, 0]);
+        assert!(client
+            .send_queue
+            .iter()
+            .skip(2)
+            .all(|segment| segment.data.len() <= 476));
+
+        let mut buffer = [0; 8_000];
+        let mut received = Vec::new();
+        run_until(&mut client, &mut server, |_, server| {
+            while let Ok(len) = server.recv(&mut buffer) {
+                received.push(buffer[..len].to_vec());
+            }
+            received.len() == 2
+        });
+        assert_eq!(received, vec![first, second]);
+    }
+
+    #[test]
+    fn test_set_mtu_rejected_when_queued_message_no_longer_fits() {
+        let mut connection = new_connection();
+        let payload = vec![0; 20 * connection.max_segment_size];
+        connection.send(&payload).unwrap();
+        assert_eq!(
+            connection.set_mtu(500).unwrap_err(),
+            ProtocolError::FragmentsGreaterThanWindowSize
+        );
+        assert_eq!(connection.max_transmission_unit, 1400);
+        assert_eq!(connection.send_queue.len(), 20);
+
+        assert!(connection.set_mtu(1000).is_ok());
+        assert_eq!(connection.send_queue.len(), 29);
+    }
+
+    #[test]
+    fn test_set_mtu_requeues_transfer_chunks() {
+        let mut client = new_connection();
+        let mut server = new_connection();
+        client.set_window_sizes(8, 32).unwrap();
+        let payload: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        client.send_large(Bytes::from(payload.clone())).unwrap();
+        client.send(b"hello").unwrap();
+        // The second flush queues chunks behind the full window
+        client.update(0).unwrap();
+        client.update(100).unwrap();
+        assert!(client
+            .send_queue
+            .iter()
+            .any(|segment| segment.data.len() > 576));
+
+        client.set_mtu(600).unwrap();
+        assert!(client
+            .send_queue
+            .iter()
+            .all(|segment| segment.data.len() <= 576));
+
+        let mut received = None;
+        run_until(&mut client, &mut server, |_, server| {
+            received = server.recv_large();
+            received.is_some()
+        });
+        assert_eq!(received.unwrap(), Bytes::from(payload));
+        let mut buffer = [0; 16];
+        assert_eq!(server.recv(&mut buffer).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_reconfigure_applies_preset_to_live_connection() {
+        let mut connection = new_connection();
+        connection.update(0).unwrap();
+        assert_eq!(connection.check(0), 100);
+
+        let turbo = Config::default().with_preset(Preset::Turbo);
+        connection.reconfigure(&turbo).unwrap();
+        assert_eq!(connection.check(0), 10);
+        assert_eq!(connection.fast_resend, 2);
+        assert_eq!(connection.minimum_rto, 30);
+
+        assert!(connection.reconfigure(&turbo.with_mtu(10)).is_err());
+        assert_eq!(connection.max_transmission_unit, 1400);
+    }
+
+    #[test]
+    fn test_reconfigure_lowers_mtu_and_grows_recv_window_together() {
+        let mut connection = new_connection();
+        let payload = vec![0; 20 * connection.max_segment_size];
+        connection.send(&payload).unwrap();
+        // The message needs 58 segments of the smaller MTU, more than the current window holds
+        assert!(connection.set_mtu(500).is_err());
+
+        let config = Config::default().with_mtu(500).with_window_sizes(32, 128);
+        connection.remote_recv_window_size = Some(128);
+        connection.reconfigure(&config).unwrap();
+        assert_eq!(connection.max_transmission_unit, 500);
+        assert_eq!(connection.recv_window_size(), 128);
+        assert_eq!(connection.send_queue.len(), 58);
     }
 
     #[test]
@@ -923,7 +2466,7 @@ mod test {
 
     #[test]
     fn test_check() {
-        //        let mut connection = ReliableConnection::new(0);
+        //        let mut connection = new_connection();
         //        let current = SystemTime::now();
         //        assert_eq!(connection.check(current), current);
         //        connection.update(current);
@@ -933,4 +2476,701 @@ mod test {
     }
 
     // TODO: Add more tests for check
+
+    #[test]
+    fn test_keepalive_sent_when_idle() {
+        let mut connection = new_connection();
+        connection.update(0).unwrap();
+        assert!(connection.output.0.is_empty());
+
+        connection.update(900).unwrap();
+        assert!(connection.output.0.is_empty());
+
+        connection.update(1000).unwrap();
+        assert_eq!(connection.output.0.len(), 1);
+        assert_eq!(connection.output.0[0][4], CMD_PING);
+    }
+
+    #[test]
+    fn test_keepalive_not_sent_when_disabled() {
+        let mut connection = new_connection();
+        connection.set_keepalive(0).unwrap();
+        connection.update(0).unwrap();
+        connection.update(5000).unwrap();
+        assert!(connection.output.0.is_empty());
+    }
+
+    #[test]
+    fn test_keepalive_not_sent_while_sending_data() {
+        let mut connection = new_connection();
+        connection.update(0).unwrap();
+        connection.send(b"hello").unwrap();
+        connection.update(900).unwrap();
+        assert_eq!(connection.output.0.len(), 1);
+
+        // The data segment was sent 100ms ago so the connection isn't idle yet.
+        connection.update(1000).unwrap();
+        assert_eq!(connection.output.0.len(), 1);
+    }
+
+    #[test]
+    fn test_keepalive_reply_is_used_as_rtt_sample() {
+        let mut client = new_connection();
+        let mut server = new_connection();
+        client.update(0).unwrap();
+        server.update(0).unwrap();
+
+        client.update(1000).unwrap();
+        let ping = client.output.0.pop().unwrap();
+        server.update(1010).unwrap();
+        server.input(&ping).unwrap();
+        server.update(1110).unwrap();
+        let pong = server.output.0.pop().unwrap();
+        assert_eq!(pong[4], CMD_PONG);
+
+        client.update(1050).unwrap();
+        client.input(&pong).unwrap();
+        assert_eq!(client.static_rtt, 50);
+        assert_eq!(client.floating_rtt, 25);
+    }
+
+    #[test]
+    fn test_rtt_histogram_records_every_ack_sample() {
+        let mut client = new_connection();
+        let mut server = new_connection();
+        client.update(0).unwrap();
+        server.update(0).unwrap();
+        for _ in 0..3 {
+            client.send(b"hello").unwrap();
+            let acked = client.metrics().get_count(DataPoint::PacketsAcked);
+            run_until(&mut client, &mut server, |client, _| {
+                client.metrics().get_count(DataPoint::PacketsAcked) > acked
+            });
+        }
+        let statistics = client.rtt_histogram().statistics().unwrap();
+        assert_eq!(statistics.samples, 3);
+        assert!(statistics.min <= statistics.p50 && statistics.p50 <= statistics.max);
+
+        client.reset_rtt_histogram();
+        assert_eq!(client.rtt_histogram().samples(), 0);
+    }
+
+    fn record_events(
+        connection: &mut ReliableConnection<PacketSink>,
+    ) -> Arc<Mutex<Vec<(u32, Event)>>> {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&events);
+        connection.set_event_listener(move |timestamp, event: &Event| {
+            recorded.lock().unwrap().push((timestamp, *event));
+        });
+        events
+    }
+
+    #[test]
+    fn test_events_trace_retransmission() {
+        let mut client = new_connection();
+        let mut server = new_connection();
+        let client_events = record_events(&mut client);
+        let server_events = record_events(&mut server);
+        client.send(b"hello").unwrap();
+        client.update(0).unwrap();
+        server.update(0).unwrap();
+        // Lose the first transmission
+        client.output.0.clear();
+
+        run_until(&mut client, &mut server, |client, _| {
+            client.metrics().get_count(DataPoint::PacketsAcked) == 1
+        });
+        let client_events = client_events.lock().unwrap();
+        assert_eq!(
+            client_events[0],
+            (
+                0,
+                Event::SegmentSent {
+                    sequence_num: 0,
+                    len: 5
+                }
+            )
+        );
+        assert_eq!(
+            client_events[1],
+            (0, Event::CongestionWindowChanged { from: 0, to: 1 })
+        );
+        let (retransmitted_at, retransmitted) = client_events[2];
+        assert!(retransmitted_at > 0);
+        assert_eq!(
+            retransmitted,
+            Event::SegmentRetransmitted {
+                sequence_num: 0,
+                transmissions: 2
+            }
+        );
+        let (acked_at, acked) = client_events[client_events.len() - 2];
+        assert!(acked_at > retransmitted_at);
+        assert_eq!(acked, Event::SegmentAcked { sequence_num: 0 });
+        assert_eq!(
+            *client_events.last().unwrap(),
+            (acked_at, Event::CongestionWindowChanged { from: 1, to: 2 })
+        );
+        assert!(server_events.lock().unwrap().contains(&(
+            retransmitted_at,
+            Event::SegmentReceived {
+                sequence_num: 0,
+                len: 5
+            }
+        )));
+    }
+
+    #[test]
+    fn test_events_trace_window_probes_and_timeout() {
+        let mut connection = new_connection();
+        let events = record_events(&mut connection);
+        connection.set_idle_timeout(0).unwrap();
+        connection.update(0).unwrap();
+        connection.remote_window_size = 0;
+        connection.update(100).unwrap();
+        connection.update(PROBE_INIT + 100).unwrap();
+        assert!(events
+            .lock()
+            .unwrap()
+            .contains(&(PROBE_INIT + 100, Event::WindowProbeSent)));
+
+        connection.set_keepalive(0).unwrap();
+        connection.set_idle_timeout(500).unwrap();
+        connection.update(PROBE_INIT + 200).unwrap();
+        assert_eq!(
+            *events.lock().unwrap().last().unwrap(),
+            (
+                PROBE_INIT + 200,
+                Event::StateChanged {
+                    from: ConnectionState::Connected,
+                    to: ConnectionState::TimedOut
+                }
+            )
+        );
+
+        connection.clear_event_listener();
+        let recorded = events.lock().unwrap().len();
+        connection.update(PROBE_INIT + 300).unwrap();
+        assert_eq!(events.lock().unwrap().len(), recorded);
+    }
+
+    #[test]
+    fn test_times_out_when_nothing_received() {
+        let mut connection = new_connection();
+        connection.set_keepalive(400).unwrap();
+        connection.set_idle_timeout(500).unwrap();
+        connection.update(0).unwrap();
+        connection.update(499).unwrap();
+        assert_eq!(connection.state(), ConnectionState::Connected);
+
+        connection.update(500).unwrap();
+        assert_eq!(connection.state(), ConnectionState::TimedOut);
+    }
+
+    #[test]
+    fn test_input_resets_idle_timeout() {
+        let mut client = new_connection();
+        let mut server = new_connection();
+        client.set_keepalive(100).unwrap();
+        server.set_keepalive(100).unwrap();
+        server.set_idle_timeout(500).unwrap();
+        client.update(0).unwrap();
+        server.update(0).unwrap();
+
+        client.update(400).unwrap();
+        server.update(400).unwrap();
+        server.input(&client.output.0.pop().unwrap()).unwrap();
+
+        server.update(800).unwrap();
+        assert_eq!(server.state(), ConnectionState::Connected);
+        server.update(900).unwrap();
+        assert_eq!(server.state(), ConnectionState::TimedOut);
+    }
+
+    #[test]
+    fn test_link_dies_after_too_many_transmissions() {
+        let config = Config::default().with_dead_link(3).with_idle_timeout(0);
+        let mut connection =
+            ReliableConnection::with_config(0, PacketSink::default(), &config).unwrap();
+        connection.send(b"lost").unwrap();
+        let mut current = 0;
+        while connection.state() == ConnectionState::Connected {
+            assert!(current < 10_000, "link never considered dead");
+            connection.update(current).unwrap();
+            current += 100;
+        }
+        assert_eq!(connection.state(), ConnectionState::DeadLink);
+        assert_eq!(connection.send_buffer.front().unwrap().xmit, 3);
+
+        connection.output.0.clear();
+        connection.update(current + 10_000).unwrap();
+        assert!(connection.output.0.is_empty());
+    }
+
+    // A PUSH of "hello" from a C KCP peer with conv 0x11223344: frg 0, wnd 128, ts 1000, sn 0,
+    // una 0, len 5, every field little-endian.
+    const KCP_PUSH: [u8; 29] = [
+        0x44, 0x33, 0x22, 0x11, 81, 0, 128, 0, 0xE8, 0x03, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0,
+        0, b'h', b'e', b'l', b'l', b'o',
+    ];
+    // The ACK it expects back: wnd 32, ts 1000 echoed, sn 0, una 1, len 0.
+    const KCP_ACK: [u8; 24] = [
+        0x44, 0x33, 0x22, 0x11, 82, 0, 32, 0, 0xE8, 0x03, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0,
+    ];
+
+    fn new_kcp_connection(session_id: u32) -> ReliableConnection<PacketSink> {
+        let config = Config::default().with_wire_format(WireFormat::Kcp);
+        ReliableConnection::with_config(session_id, PacketSink::default(), &config).unwrap()
+    }
+
+    #[test]
+    fn test_kcp_wire_format_interoperates_with_c_kcp() {
+        let mut connection = new_kcp_connection(0x1122_3344);
+        connection.update(0).unwrap();
+        connection.input(&KCP_PUSH).unwrap();
+        let mut buffer = [0; 16];
+        let len = connection.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], b"hello");
+
+        connection.update(100).unwrap();
+        assert_eq!(connection.output.0, vec![KCP_ACK.to_vec()]);
+    }
+
+    #[test]
+    fn test_kcp_wire_format_round_trip() {
+        let mut client = new_kcp_connection(1);
+        let mut server = new_kcp_connection(1);
+        client.send(b"hello").unwrap();
+        client.send_with_ttl(b"world", 10_000).unwrap();
+        let mut received = Vec::new();
+        run_until(&mut client, &mut server, |_, server| {
+            let mut buffer = [0; 16];
+            while let Ok(len) = server.recv(&mut buffer) {
+                received.push(buffer[..len].to_vec());
+            }
+            received.len() == 2
+        });
+        assert_eq!(received, vec![b"hello".to_vec(), b"world".to_vec()]);
+    }
+
+    #[test]
+    fn test_kcp_wire_format_rejects_mercury_commands() {
+        let mut ping = KCP_ACK;
+        ping[4] = CMD_PING;
+        let mut connection = new_kcp_connection(0x1122_3344);
+        assert_eq!(
+            connection.input(&ping).unwrap_err(),
+            ProtocolError::InvalidCommand
+        );
+        assert!(connection.send_large(Bytes::from_static(b"large")).is_err());
+    }
+
+    #[test]
+    fn test_kcp_wire_format_sends_no_keepalives() {
+        let mut connection = new_kcp_connection(0);
+        connection.update(0).unwrap();
+        connection.update(5_000).unwrap();
+        assert!(connection.output.0.is_empty());
+        assert!(connection.reconfigure(&Config::default()).is_err());
+    }
+
+    #[test]
+    fn test_no_packets_sent_after_timing_out() {
+        let mut connection = new_connection();
+        connection.set_keepalive(400).unwrap();
+        connection.set_idle_timeout(500).unwrap();
+        connection.update(0).unwrap();
+        connection.update(500).unwrap();
+        connection.update(2000).unwrap();
+        assert!(connection.output.0.is_empty());
+    }
+
+    #[test]
+    fn test_keepalive_setters_validated() {
+        let mut connection = new_connection();
+        assert_eq!(
+            connection.set_idle_timeout(1_000).unwrap_err(),
+            ProtocolError::InvalidConfiguration("")
+        );
+        assert_eq!(connection.idle_timeout, IDLE_TIMEOUT);
+        assert!(connection.set_keepalive(IDLE_TIMEOUT).is_err());
+        assert_eq!(connection.keepalive_interval, KEEPALIVE_INTERVAL);
+        // Keepalives can always be disabled, and then the idle timeout lowered
+        connection.set_keepalive(0).unwrap();
+        connection.set_idle_timeout(1_000).unwrap();
+
+        let config = Config::default()
+            .with_wire_format(WireFormat::Kcp)
+            .with_keepalive_interval(0);
+        let mut connection =
+            ReliableConnection::with_config(0, PacketSink::default(), &config).unwrap();
+        assert!(connection.set_keepalive(100).is_err());
+        assert_eq!(connection.keepalive_interval, 0);
+    }
+
+    #[test]
+    fn test_window_sizes_respect_auto_tuning_limit() {
+        let mut connection = new_connection();
+        connection.set_recv_window_auto_tuning(Some(64)).unwrap();
+        assert!(connection.set_window_sizes(32, 128).is_err());
+        assert_eq!(connection.recv_window_size(), 32);
+        assert!(connection.set_window_sizes(32, 64).is_ok());
+        assert!(connection.set_recv_window_auto_tuning(Some(32)).is_err());
+    }
+
+    #[test]
+    fn test_expired_message_dropped_before_sending() {
+        let mut connection = new_connection();
+        connection.update(0).unwrap();
+        connection.send_with_ttl(b"stale", 50).unwrap();
+        connection.update(100).unwrap();
+        assert_eq!(connection.num_segments_awaiting_send(), 0);
+        assert!(connection.output.0.is_empty());
+    }
+
+    #[test]
+    fn test_unexpired_message_is_sent() {
+        let mut connection = new_connection();
+        connection.update(0).unwrap();
+        connection.send_with_ttl(b"fresh", 200).unwrap();
+        connection.update(100).unwrap();
+        assert_eq!(connection.output.0.len(), 1);
+        assert_eq!(connection.output.0[0][4], CMD_PUSH);
+    }
+
+    #[test]
+    fn test_expired_in_flight_message_is_skipped() {
+        let mut client = new_connection();
+        let mut server = new_connection();
+        client.send_with_ttl(b"stale", 50).unwrap();
+        client.send(b"fresh").unwrap();
+        client.update(0).unwrap();
+        server.update(0).unwrap();
+
+        // Lose the first transmission
+        client.output.0.clear();
+        client.update(100).unwrap();
+        client.update(300).unwrap();
+
+        let packet = &client.output.0[0];
+        assert_eq!(packet[4], CMD_SKIP);
+        assert_eq!(&packet[20..24], &[0, 0, 0, 0]);
+        assert_eq!(packet[28], CMD_PUSH);
+
+        pump(&mut client, &mut server);
+        let mut buffer = [0; 16];
+        assert_eq!(server.recv(&mut buffer).unwrap(), 5);
+        assert_eq!(&buffer[..5], b"fresh");
+        assert_eq!(
+            server.recv(&mut buffer).unwrap_err(),
+            ProtocolError::EmptyRecvQueue
+        );
+    }
+
+    #[test]
+    fn test_expired_partially_sent_message_is_skipped() {
+        let mut client = new_connection();
+        let mut server = new_connection();
+        // Only two of the three fragments fit in the window
+        client.set_window_sizes(2, 32).unwrap();
+        client.send_with_ttl(&[1; 3000], 50).unwrap();
+        client.send(b"after").unwrap();
+        client.update(0).unwrap();
+        server.update(0).unwrap();
+        assert_eq!(client.send_buffer.len(), 2);
+
+        // Lose the first transmission and let the message expire
+        client.output.0.clear();
+        client.update(100).unwrap();
+        client.update(300).unwrap();
+        pump(&mut client, &mut server);
+        server.update(300).unwrap();
+        pump(&mut server, &mut client);
+        client.update(400).unwrap();
+        pump(&mut client, &mut server);
+
+        let mut buffer = [0; 3000];
+        assert_eq!(server.recv(&mut buffer).unwrap(), 5);
+        assert_eq!(&buffer[..5], b"after");
+        assert!(server.recv_queue.is_empty());
+    }
+
+    #[test]
+    fn test_large_message_transferred_alongside_other_messages() {
+        let mut client = new_connection();
+        let mut server = new_connection();
+        let payload: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let progress = reports.clone();
+        let id = client
+            .send_large_with_progress(Bytes::from(payload.clone()), move |p| {
+                progress.lock().unwrap().push(p)
+            })
+            .unwrap();
+        client.send(b"hello").unwrap();
+
+        let mut received = None;
+        run_until(&mut client, &mut server, |_, server| {
+            received = server.recv_large();
+            received.is_some()
+        });
+        assert_eq!(received.unwrap(), Bytes::from(payload));
+
+        let mut buffer = [0; 16];
+        assert_eq!(server.recv(&mut buffer).unwrap(), 5);
+        assert_eq!(&buffer[..5], b"hello");
+
+        run_until(&mut client, &mut server, |client, _| {
+            client.transfer_progress(id).is_none()
+        });
+        let reports = reports.lock().unwrap();
+        assert!(reports.len() > 1);
+        assert!(reports
+            .windows(2)
+            .all(|pair| pair[0].transferred < pair[1].transferred));
+        assert!(reports.last().unwrap().is_complete());
+    }
+
+    #[test]
+    fn test_large_message_exceeds_receive_window() {
+        let mut client = new_connection();
+        let mut server = new_connection();
+        let window_bytes = server.recv_window_size * server.max_segment_size;
+        let payload = Bytes::from(vec![7; window_bytes * 3]);
+        assert_eq!(
+            client.send(&payload).unwrap_err(),
+            ProtocolError::FragmentsGreaterThanWindowSize
+        );
+
+        client.send_large(payload.clone()).unwrap();
+        assert!(client.send_queue.is_empty());
+        client.update(0).unwrap();
+        assert!(client.send_queue.len() + client.send_buffer.len() <= client.send_window_size);
+
+        let mut received = None;
+        run_until(&mut client, &mut server, |_, server| {
+            received = server.recv_large();
+            received.is_some()
+        });
+        assert_eq!(received.unwrap(), payload);
+    }
+
+    #[test]
+    fn test_transfer_larger_than_maximum_is_dropped() {
+        let mut client = new_connection();
+        let config = Config::default().with_max_transfer_size(10_000);
+        let mut server =
+            ReliableConnection::with_config(0, PacketSink::default(), &config).unwrap();
+        let id = client.send_large(Bytes::from(vec![1; 20_000])).unwrap();
+        run_until(&mut client, &mut server, |client, _| {
+            client.transfer_progress(id).is_none()
+        });
+        assert!(server.recv_large().is_none());
+        assert!(server.incoming_transfer_progress().is_none());
+
+        client.send_large(Bytes::from(vec![2; 10_000])).unwrap();
+        let mut received = None;
+        run_until(&mut client, &mut server, |_, server| {
+            received = server.recv_large();
+            received.is_some()
+        });
+        assert_eq!(received.unwrap(), Bytes::from(vec![2; 10_000]));
+    }
+
+    #[test]
+    fn test_cancelled_transfer_is_discarded() {
+        let mut client = new_connection();
+        let mut server = new_connection();
+        let first = client.send_large(Bytes::from(vec![1; 200_000])).unwrap();
+        run_until(&mut client, &mut server, |_, server| {
+            server.incoming_transfer_progress().is_some()
+        });
+        assert!(client.cancel_transfer(first));
+        assert!(!client.cancel_transfer(first));
+        assert!(client.transfer_progress(first).is_none());
+
+        client.send_large(Bytes::from(vec![2; 50_000])).unwrap();
+        let mut received = None;
+        run_until(&mut client, &mut server, |_, server| {
+            received = server.recv_large();
+            received.is_some()
+        });
+        assert_eq!(received.unwrap(), Bytes::from(vec![2; 50_000]));
+        assert!(server.incoming_transfer_progress().is_none());
+        assert!(server.recv_large().is_none());
+    }
+
+    #[test]
+    fn test_large_windows_recover_from_loss() {
+        let mut client = new_connection();
+        let mut server = new_connection();
+        client.set_window_sizes(4_096, 4_096).unwrap();
+        server.set_window_sizes(4_096, 4_096).unwrap();
+        for i in 0..3_000u32 {
+            client.send(&i.to_be_bytes()).unwrap();
+        }
+
+        let mut received = Vec::new();
+        let mut dropped = 0;
+        for step in 0..100 {
+            client.update(step * 100).unwrap();
+            server.update(step * 100).unwrap();
+            for packet in client.output.0.drain(..) {
+                // Lose every seventh packet
+                dropped += 1;
+                if dropped % 7 != 0 {
+                    server.input(&packet).unwrap();
+                }
+            }
+            pump(&mut server, &mut client);
+            let mut buffer = [0; 4];
+            while server.recv(&mut buffer).is_ok() {
+                received.push(u32::from_be_bytes(buffer));
+            }
+            if received.len() == 3_000 {
+                break;
+            }
+        }
+        assert_eq!(received, (0..3_000).collect::<Vec<_>>());
+        assert!(client.metrics().get_count(DataPoint::SegmentsRetransmitted) > 0);
+    }
+
+    #[test]
+    fn test_metrics_track_traffic() {
+        let mut client = new_connection();
+        let mut server = new_connection();
+        client.send(b"hello").unwrap();
+        client.update(0).unwrap();
+        server.update(0).unwrap();
+        // Lose the first transmission
+        client.output.0.clear();
+
+        run_until(&mut client, &mut server, |client, _| {
+            client.metrics().get_count(DataPoint::PacketsAcked) == 1
+        });
+        let metrics = client.metrics();
+        assert_eq!(metrics.get_count(DataPoint::BytesAcked), 5);
+        assert_eq!(metrics.get_count(DataPoint::SegmentsSent), 2);
+        assert_eq!(metrics.get_count(DataPoint::SegmentsRetransmitted), 1);
+        assert_eq!(metrics.packet_loss(), 0.5);
+        assert!(metrics.get_count(DataPoint::PacketsSent) >= 2);
+        assert!(metrics.get_count(DataPoint::BytesSent) >= 2 * (24 + 5));
+        assert!(metrics.get_count(DataPoint::PacketsReceived) >= 1);
+        assert!(metrics.rtt() > 0);
+        assert_eq!(metrics.rto(), client.calculated_rto);
+        assert!(metrics.congestion_window() >= 1);
+        assert!(metrics.sent_bandwidth_kbps() > 0.0);
+    }
+
+    #[test]
+    fn test_stream_round_trip() {
+        let mut client = new_connection();
+        let mut server = new_connection();
+        client.set_streaming_mode(true);
+        server.set_streaming_mode(true);
+
+        client.write_all(b"hello ").unwrap();
+        client.write_all(b"world").unwrap();
+        // Both writes are merged into a single segment
+        assert_eq!(client.send_queue.len(), 1);
+
+        let mut buffer = [0; 4];
+        assert_eq!(
+            server.read(&mut buffer).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        run_until(&mut client, &mut server, |_, server| {
+            !server.recv_queue.is_empty()
+        });
+
+        let mut received = Vec::new();
+        while let Ok(len) = server.read(&mut buffer) {
+            received.extend_from_slice(&buffer[..len]);
+        }
+        assert_eq!(received, b"hello world");
+    }
+
+    #[test]
+    fn test_stream_larger_than_window() {
+        let mut client = new_connection();
+        let mut server = new_connection();
+        client.set_streaming_mode(true);
+        server.set_streaming_mode(true);
+        let payload: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+
+        let mut written = 0;
+        let mut received = Vec::new();
+        let mut buffer = [0; 4096];
+        run_until(&mut client, &mut server, |client, server| {
+            while written < payload.len() {
+                match client.write(&payload[written..]) {
+                    Ok(len) => written += len,
+                    Err(e) => {
+                        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+                        break;
+                    }
+                }
+            }
+            while let Ok(len) = server.read(&mut buffer) {
+                received.extend_from_slice(&buffer[..len]);
+            }
+            received.len() == payload.len()
+        });
+        assert_eq!(received, payload);
+    }
+
+    #[test]
+    fn test_stream_requires_streaming_mode() {
+        let mut connection = new_connection();
+        assert_eq!(
+            connection.write(b"data").unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            connection.read(&mut [0; 4]).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[cfg(feature = "futures-io")]
+    #[test]
+    fn test_async_read_wakes_on_input() {
+        use futures_io::AsyncRead;
+        use std::{
+            pin::Pin,
+            sync::atomic::{AtomicBool, Ordering},
+            task::{Context, Poll, Wake},
+        };
+
+        struct Flag(AtomicBool);
+
+        impl Wake for Flag {
+            fn wake(self: Arc<Self>) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let mut client = new_connection();
+        let mut server = new_connection();
+        client.set_streaming_mode(true);
+        server.set_streaming_mode(true);
+
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        let waker = flag.clone().into();
+        let mut cx = Context::from_waker(&waker);
+        let mut buffer = [0; 16];
+        assert!(Pin::new(&mut server)
+            .poll_read(&mut cx, &mut buffer)
+            .is_pending());
+
+        client.write_all(b"ping").unwrap();
+        client.update(0).unwrap();
+        pump(&mut client, &mut server);
+        assert!(flag.0.load(Ordering::SeqCst));
+        match Pin::new(&mut server).poll_read(&mut cx, &mut buffer) {
+            Poll::Ready(Ok(len)) => assert_eq!(&buffer[..len], b"ping"),
+            _ => panic!("expected the received data"),
+        }
+    }
 }
@@ -1,7 +1,5 @@
 use crate::guarantees::{DeliveryGuarantee, OrderingGuarantee};
 use bytes::{Bytes, BytesMut};
-use crc::crc32;
-use lazy_static::lazy_static;
 
 /// Represents a request to send a payload (with a particular delivery guarantee) to process.
 pub struct Datagram<'a> {
@@ -36,6 +34,19 @@ impl<'a> Datagram<'a> {
         }
     }
 
+    /// Redundant datagrams are sequenced datagrams which are resent in every packet of the stream
+    /// until the remote acknowledges them, so that losing a single packet never loses a datagram.
+    /// Meant for small and frequent datagrams such as player input.
+    /// e.g. [1, 4, 3, 2, 4] returns [1, 2, 3, 4] unless several packets in a row are lost.
+    pub fn redundant(payload: &'a [u8], stream_id: usize) -> Self {
+        Self {
+            delivery: DeliveryGuarantee::Redundant,
+            ordering: OrderingGuarantee::Sequenced,
+            stream_id,
+            payload,
+        }
+    }
+
     /// Reliable datagrams are UDP datagrams monitored by a reliabililty layer to ensure they arrive
     /// at the destination. Prevents duplication.
     /// e.g. [1, 4, 3, 2, 4] returns [1, 4, 3, 2] with a smaller chance of losing a datagram.
@@ -97,19 +108,6 @@ impl ReceivedDatagram {
     }
 }
 
-lazy_static! {
-    static ref PROTOCOL_VERSION: String = format!(
-        "{}-{}.{}",
-        env!("CARGO_PKG_NAME"),
-        env!("CARGO_PKG_VERSION_MAJOR"),
-        env!("CARGO_PKG_VERSION_MINOR")
-    );
-}
-
-fn calc_checksum(payload: &[u8]) -> u32 {
-    crc32::checksum_ieee(&[PROTOCOL_VERSION.as_bytes(), payload].concat())
-}
-
 #[cfg(test)]
 mod test {
     use super::{Datagram, DeliveryGuarantee, OrderingGuarantee};
@@ -134,6 +132,14 @@ mod test {
         assert_eq!(datagram.stream_id, 0);
     }
 
+    #[test]
+    fn ensure_redundant_creation() {
+        let datagram = Datagram::redundant(test_payload(), 0);
+        assert_eq!(datagram.delivery, DeliveryGuarantee::Redundant);
+        assert_eq!(datagram.ordering, OrderingGuarantee::Sequenced);
+        assert_eq!(datagram.stream_id, 0);
+    }
+
     #[test]
     fn ensure_reliable_creation() {
         let datagram = Datagram::reliable(test_payload());
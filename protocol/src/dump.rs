@@ -0,0 +1,385 @@
+use crate::{
+    capture::CaptureLayer, config::WireFormat, handshake::Hello, message::Message,
+    segment::SegmentHeader, ProtocolError, CMD_ACK, CMD_CHUNK, CMD_PING, CMD_PONG, CMD_PUSH,
+    CMD_SKIP, CMD_WASK, CMD_WINS, PACKET_FEC_DATA, PACKET_FEC_PARITY, PACKET_HELLO,
+    PACKET_REDUNDANT_ACK, PACKET_RELIABLE, PACKET_UNRELIABLE, PROTOCOL_OVERHEAD,
+};
+use bytes::{Buf, Bytes};
+use std::{fmt, io::Cursor};
+
+const INDENT: &str = "  ";
+
+/// Writes a human readable description of every header in `packet`, one per line. Headers which
+/// `ReliableConnection::input` or `Endpoint::receive` would reject are flagged with a line
+/// starting with `!` naming the error they would return, and decoding stops there. Segments are
+/// read in `format`, the wire format of the connection which sent them (see
+/// `CaptureReader::config`).
+pub fn dump_packet<W: fmt::Write>(
+    out: &mut W,
+    packet: &[u8],
+    layer: CaptureLayer,
+    format: WireFormat,
+) -> fmt::Result {
+    match layer {
+        CaptureLayer::Connection => dump_segments(out, packet, format, 0),
+        CaptureLayer::Endpoint => dump_endpoint_packet(out, packet, format, 0),
+    }
+}
+
+/// Returns the name of a segment command, as used by `dump_packet`.
+pub fn command_name(command: u8) -> Option<&'static str> {
+    match command {
+        CMD_PUSH => Some("PUSH"),
+        CMD_ACK => Some("ACK"),
+        CMD_WASK => Some("WASK"),
+        CMD_WINS => Some("WINS"),
+        CMD_PING => Some("PING"),
+        CMD_PONG => Some("PONG"),
+        CMD_SKIP => Some("SKIP"),
+        CMD_CHUNK => Some("CHUNK"),
+        _ => None,
+    }
+}
+
+fn dump_segments<W: fmt::Write>(
+    out: &mut W,
+    packet: &[u8],
+    format: WireFormat,
+    depth: usize,
+) -> fmt::Result {
+    let mut cursor = Cursor::new(packet);
+    if cursor.remaining() < PROTOCOL_OVERHEAD {
+        return flag(
+            out,
+            depth,
+            format_args!("{} bytes, shorter than a segment header", packet.len()),
+            ProtocolError::BufferTooSmall,
+        );
+    }
+
+    let mut first_session_id = None;
+    while cursor.remaining() >= PROTOCOL_OVERHEAD {
+        let SegmentHeader {
+            session_id,
+            command,
+            fragment_id,
+            window_size,
+            timestamp,
+            sequence_num,
+            unacked_sequence_num,
+            len,
+        } = SegmentHeader::decode(cursor.bytes(), format);
+        cursor.advance(PROTOCOL_OVERHEAD);
+
+        if *first_session_id.get_or_insert(session_id) != session_id {
+            return flag(
+                out,
+                depth,
+                format_args!(
+                    "session {} differs from the packet's first segment",
+                    session_id
+                ),
+                ProtocolError::InvalidSessionId,
+            );
+        }
+        if cursor.remaining() < len {
+            return flag(
+                out,
+                depth,
+                format_args!("segment claims {} bytes, {} left", len, cursor.remaining()),
+                ProtocolError::IncompleteMessage,
+            );
+        }
+        let name = match command_name(command) {
+            Some(name) if format == WireFormat::Mercury || is_kcp_command(command) => name,
+            Some(name) => {
+                return flag(
+                    out,
+                    depth,
+                    format_args!("command {} doesn't exist in the kcp wire format", name),
+                    ProtocolError::InvalidCommand,
+                );
+            }
+            None => {
+                return flag(
+                    out,
+                    depth,
+                    format_args!("unknown command {}", command),
+                    ProtocolError::InvalidCommand,
+                );
+            }
+        };
+
+        indent(out, depth)?;
+        writeln!(
+            out,
+            "segment session={} cmd={} frg={} wnd={} ts={} sn={} una={} len={}",
+            session_id,
+            name,
+            fragment_id,
+            window_size,
+            timestamp,
+            sequence_num,
+            unacked_sequence_num,
+            len
+        )?;
+        cursor.advance(len);
+    }
+
+    if cursor.has_remaining() {
+        indent(out, depth)?;
+        writeln!(out, "{} trailing bytes ignored", cursor.remaining())?;
+    }
+    Ok(())
+}
+
+// The commands of the C implementation of KCP, the only ones of the kcp wire format.
+fn is_kcp_command(command: u8) -> bool {
+    command == CMD_PUSH || command == CMD_ACK || command == CMD_WASK || command == CMD_WINS
+}
+
+fn dump_endpoint_packet<W: fmt::Write>(
+    out: &mut W,
+    packet: &[u8],
+    format: WireFormat,
+    depth: usize,
+) -> fmt::Result {
+    let kind = match packet.first() {
+        Some(kind) => *kind,
+        None => {
+            return flag(out, depth, "empty packet", ProtocolError::MalformedPacket);
+        }
+    };
+    let body = &packet[1..];
+    match kind {
+        PACKET_UNRELIABLE => {
+            indent(out, depth)?;
+            writeln!(out, "unreliable len={}", body.len())?;
+            dump_messages(out, body, depth + 1)
+        }
+        PACKET_RELIABLE => {
+            indent(out, depth)?;
+            writeln!(out, "reliable len={}", body.len())?;
+            dump_segments(out, body, format, depth + 1)
+        }
+        PACKET_FEC_DATA if body.len() >= 3 => {
+            let mut cursor = Cursor::new(body);
+            let group = cursor.get_u16_be();
+            let index = cursor.get_u8();
+            indent(out, depth)?;
+            writeln!(out, "fec data group={} index={}", group, index)?;
+            dump_endpoint_packet(out, &body[3..], format, depth + 1)
+        }
+        PACKET_FEC_PARITY if body.len() >= 5 => {
+            let mut cursor = Cursor::new(body);
+            let group = cursor.get_u16_be();
+            let count = cursor.get_u8();
+            let lengths = cursor.get_u16_be();
+            indent(out, depth)?;
+            writeln!(
+                out,
+                "fec parity group={} count={} lengths={:#06x} len={}",
+                group,
+                count,
+                lengths,
+                cursor.remaining()
+            )
+        }
+        PACKET_FEC_DATA | PACKET_FEC_PARITY => flag(
+            out,
+            depth,
+            "truncated fec header",
+            ProtocolError::MalformedPacket,
+        ),
+        PACKET_REDUNDANT_ACK => {
+            indent(out, depth)?;
+            writeln!(out, "redundant ack")?;
+            if !body.len().is_multiple_of(3) {
+                return flag(
+                    out,
+                    depth + 1,
+                    format_args!("{} bytes isn't a whole number of acks", body.len()),
+                    ProtocolError::MalformedPacket,
+                );
+            }
+            let mut cursor = Cursor::new(body);
+            while cursor.has_remaining() {
+                let stream_id = cursor.get_u8();
+                let sequence_num = cursor.get_u16_be();
+                indent(out, depth + 1)?;
+                writeln!(out, "ack stream={} seq={}", stream_id, sequence_num)?;
+            }
+            Ok(())
+        }
+        PACKET_HELLO => match Hello::decode(packet) {
+            Some(hello) => {
+                indent(out, depth)?;
+                writeln!(
+                    out,
+                    "hello version={} features={} received={} wants_reply={}",
+                    hello.version, hello.features, hello.received, hello.wants_reply
+                )
+            }
+            None => flag(
+                out,
+                depth,
+                "truncated hello",
+                ProtocolError::MalformedPacket,
+            ),
+        },
+        kind => flag(
+            out,
+            depth,
+            format_args!("unknown packet kind {}", kind),
+            ProtocolError::MalformedPacket,
+        ),
+    }
+}
+
+fn dump_messages<W: fmt::Write>(out: &mut W, body: &[u8], depth: usize) -> fmt::Result {
+    let mut payload = Bytes::from(body);
+    while !payload.is_empty() {
+        let message = match Message::decode(&mut payload) {
+            Some(message) => message,
+            None => {
+                return flag(
+                    out,
+                    depth,
+                    "truncated or malformed message",
+                    ProtocolError::MalformedPacket,
+                );
+            }
+        };
+        indent(out, depth)?;
+        writeln!(
+            out,
+            "message stream={} delivery={:?} ordering={:?} seq={} len={}",
+            message.stream_id,
+            message.delivery,
+            message.ordering,
+            message.sequence_num,
+            message.payload.len()
+        )?;
+    }
+    Ok(())
+}
+
+fn flag<W, D>(out: &mut W, depth: usize, description: D, error: ProtocolError) -> fmt::Result
+where
+    W: fmt::Write,
+    D: fmt::Display,
+{
+    indent(out, depth)?;
+    writeln!(out, "! {} ({:?})", description, error)
+}
+
+fn indent<W: fmt::Write>(out: &mut W, depth: usize) -> fmt::Result {
+    for _ in 0..depth {
+        out.write_str(INDENT)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::dump_packet;
+    use crate::{
+        CaptureLayer, Config, Datagram, Endpoint, ReliableConnection, WireFormat, CMD_PING,
+        CMD_PUSH,
+    };
+
+    fn dump(packet: &[u8], layer: CaptureLayer) -> String {
+        let mut out = String::new();
+        dump_packet(&mut out, packet, layer, WireFormat::Mercury).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_dumps_connection_segments() {
+        let mut connection = ReliableConnection::new(7, Vec::new());
+        connection.send(b"hello").unwrap();
+        connection.update(100).unwrap();
+        let packet = connection.output_mut().clone();
+        assert_eq!(
+            dump(&packet, CaptureLayer::Connection),
+            "segment session=7 cmd=PUSH frg=0 wnd=32 ts=100 sn=0 una=0 len=5\n"
+        );
+    }
+
+    #[test]
+    fn test_dumps_kcp_segments() {
+        let config = Config::default()
+            .with_wire_format(WireFormat::Kcp)
+            .with_keepalive_interval(0);
+        let mut connection = ReliableConnection::with_config(7, Vec::new(), &config).unwrap();
+        connection.send(b"hello").unwrap();
+        connection.update(100).unwrap();
+        let mut packet = connection.output_mut().clone();
+        let mut out = String::new();
+        dump_packet(&mut out, &packet, CaptureLayer::Connection, WireFormat::Kcp).unwrap();
+        assert_eq!(
+            out,
+            "segment session=7 cmd=PUSH frg=0 wnd=32 ts=100 sn=0 una=0 len=5\n"
+        );
+
+        packet[4] = CMD_PING;
+        let mut out = String::new();
+        dump_packet(&mut out, &packet, CaptureLayer::Connection, WireFormat::Kcp).unwrap();
+        assert_eq!(
+            out,
+            "! command PING doesn't exist in the kcp wire format (InvalidCommand)\n"
+        );
+    }
+
+    #[test]
+    fn test_flags_rejected_segments() {
+        assert_eq!(
+            dump(&[0; 10], CaptureLayer::Connection),
+            "! 10 bytes, shorter than a segment header (BufferTooSmall)\n"
+        );
+
+        let mut packet = vec![0, 0, 0, 1, CMD_PUSH];
+        packet.extend_from_slice(&[0; 15]);
+        packet.extend_from_slice(&[0, 0, 0, 9, 1, 2]);
+        assert_eq!(
+            dump(&packet, CaptureLayer::Connection),
+            "! segment claims 9 bytes, 2 left (IncompleteMessage)\n"
+        );
+
+        packet[4] = 1;
+        packet[23] = 0;
+        assert_eq!(
+            dump(&packet, CaptureLayer::Connection),
+            "! unknown command 1 (InvalidCommand)\n"
+        );
+    }
+
+    #[test]
+    fn test_dumps_endpoint_packets() {
+        let mut endpoint = Endpoint::new();
+        endpoint.send(Datagram::unreliable(b"hello")).unwrap();
+        endpoint.update(0).unwrap();
+        let hello = endpoint.poll_packet().unwrap();
+        assert_eq!(
+            dump(&hello, CaptureLayer::Endpoint),
+            "hello version=1 features=fec received=false wants_reply=true\n"
+        );
+        assert_eq!(
+            dump(&hello[..4], CaptureLayer::Endpoint),
+            "! truncated hello (MalformedPacket)\n"
+        );
+        let packet = endpoint.poll_packet().unwrap();
+        assert_eq!(
+            dump(&packet, CaptureLayer::Endpoint),
+            "unreliable len=11\n  message stream=255 delivery=Unreliable ordering=None seq=0 len=5\n"
+        );
+        assert_eq!(
+            dump(&packet[..8], CaptureLayer::Endpoint),
+            "unreliable len=7\n  ! truncated or malformed message (MalformedPacket)\n"
+        );
+        assert_eq!(
+            dump(&[9], CaptureLayer::Endpoint),
+            "! unknown packet kind 9 (MalformedPacket)\n"
+        );
+    }
+}
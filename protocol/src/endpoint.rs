@@ -1,13 +1,26 @@
 use crate::{
-    config::Config,
-    datagram::{Datagram, ReceivedDatagram},
+    capture::{CaptureKind, PacketRecorder, Recorder},
+    config::{Config, WireFormat},
+    connection::{time_diff, ConnectionState, ReliableConnection},
+    datagram::{self, Datagram, ReceivedDatagram},
     errors::{ProtocolError, ProtocolResult},
+    fec::{FecDecoder, FecEncoder, FEC_OVERHEAD},
     guarantees::{DeliveryGuarantee, OrderingGuarantee},
+    handshake::{Features, Handshake, Hello},
+    histogram::RttHistogram,
+    message::Message,
     metrics::{DataPoint, Metrics},
+    scheduler::Scheduler,
     streams::{OrderedStream, SequencedStream},
+    MAX_STREAM_ID, MESSAGE_OVERHEAD, PACKET_FEC_DATA, PACKET_FEC_PARITY, PACKET_HELLO,
+    PACKET_REDUNDANT_ACK, PACKET_RELIABLE, PACKET_UNRELIABLE, PROTOCOL_VERSION,
 };
-use bytes::{Bytes, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use log::debug;
+use std::{
+    collections::VecDeque,
+    io::{self, Cursor},
+};
 
 /// `Endpoint` provides the interface into the protocol handling
 pub struct Endpoint {
@@ -15,42 +28,379 @@ pub struct Endpoint {
     ordered_streams: Box<[OrderedStream]>,
     sequenced_streams: Box<[SequencedStream]>,
 
-    /// Congestion Control
-    rtt: f32,
+    /// Orders queued datagrams by stream priority and bandwidth budget
+    scheduler: Scheduler,
+    /// Reliability layer used for reliable datagrams
+    connection: ReliableConnection<PacketQueue>,
+    /// Outgoing packets waiting to be polled
+    packets: VecDeque<Bytes>,
+    /// Received datagrams waiting to be polled
+    received: VecDeque<ReceivedDatagram>,
+    last_update_time: Option<u32>,
+
+    /// Forward error correction of outgoing and incoming packets
+    fec_encoder: FecEncoder,
+    fec_decoder: FecDecoder,
+    /// Redundancy ratio applied to the reliability layer's packets while the reliable messages
+    /// of FEC protected streams are still in flight
+    reliable_redundancy: f32,
+    /// Newest message received on each redundant stream since the last acknowledgement was sent
+    redundant_acks: Vec<(u8, u16)>,
 
     /// Metrics tracking around `Endpoint` operations
     metrics: Metrics,
+    /// Captures the packets received and sent
+    recorder: Recorder,
+    /// Agrees on the protocol version and features with the remote endpoint
+    handshake: Handshake,
 }
 
 impl Endpoint {
-    pub fn new(config: Config) -> Self {
+    /// Creates an endpoint with the default config.
+    pub fn new() -> Self {
+        Self::with_config(Config::default()).expect("the default config is valid")
+    }
+
+    /// Creates an endpoint, or fails with `InvalidConfiguration` if the config doesn't validate.
+    pub fn with_config(config: Config) -> ProtocolResult<Self> {
+        config.validate()?;
+        // packets carry a type byte and unreliable messages next to the segments, which KCP
+        // peers don't understand
+        if config.wire_format() != WireFormat::Mercury {
+            return Err(ProtocolError::InvalidConfiguration(
+                "wire_format: an Endpoint only speaks the mercury wire format.",
+            ));
+        }
         let ordered_size = config.ordered_streams_size();
         let sequenced_size = config.sequenced_streams_size();
         let bandwidth_smoothing_factor = config.bandwidth_smoothing_factor();
-        Self {
+        let handshake = Handshake::new(config.features());
+        let connection = ReliableConnection::with_config(
+            0,
+            PacketQueue::default(),
+            &connection_config(&config),
+        )?;
+        Ok(Self {
             config,
             ordered_streams: vec![OrderedStream::new(); ordered_size].into_boxed_slice(),
             sequenced_streams: vec![SequencedStream::new(); sequenced_size].into_boxed_slice(),
-            rtt: 0.0,
+            scheduler: Scheduler::new(),
+            connection,
+            packets: VecDeque::new(),
+            received: VecDeque::new(),
+            last_update_time: None,
+            fec_encoder: FecEncoder::new(),
+            fec_decoder: FecDecoder::new(),
+            reliable_redundancy: 0.0,
+            redundant_acks: Vec::new(),
             metrics: Metrics::new(bandwidth_smoothing_factor),
+            recorder: Recorder::new(),
+            handshake,
+        })
+    }
+
+    /// Applies new settings to the running endpoint, e.g. a preset or a lower MTU. Reliable data
+    /// which hasn't been sent yet is split again for the new MTU while segments in flight are left
+    /// as they are, and queued unreliable messages which no longer fit in a packet are dropped.
+    /// The number of streams can't change. Fails without changing anything if the config isn't
+    /// valid.
+    pub fn reconfigure(&mut self, config: Config) -> ProtocolResult<()> {
+        config.validate()?;
+        if config.ordered_streams_size() != self.config.ordered_streams_size() {
+            return Err(ProtocolError::InvalidConfiguration(
+                "ordered_streams_size: can't change on a running endpoint.",
+            ));
+        }
+        if config.sequenced_streams_size() != self.config.sequenced_streams_size() {
+            return Err(ProtocolError::InvalidConfiguration(
+                "sequenced_streams_size: can't change on a running endpoint.",
+            ));
+        }
+        if config.features() != self.config.features() {
+            return Err(ProtocolError::InvalidConfiguration(
+                "features: can't change on a running endpoint.",
+            ));
+        }
+
+        self.connection.reconfigure(&connection_config(&config))?;
+        for _ in 0..self
+            .scheduler
+            .reconfigure(&config, max_packet_size(&config))
+        {
+            self.metrics.increment(DataPoint::MessagesDropped);
+        }
+        self.metrics
+            .set_bandwidth_smoothing_factor(config.bandwidth_smoothing_factor());
+        self.config = config;
+        Ok(())
+    }
+
+    /// Queues a datagram to send. Queued datagrams are packed into packets on the next `update`,
+    /// from the highest priority stream down.
+    pub fn send(&mut self, datagram: Datagram) -> ProtocolResult<()> {
+        // Stream ids are sent in a byte, whose last value is the stream of unordered datagrams
+        if datagram.ordering != OrderingGuarantee::None && datagram.stream_id > MAX_STREAM_ID {
+            return Err(ProtocolError::InvalidStreamId);
+        }
+        let message = match datagram.delivery {
+            DeliveryGuarantee::Reliable => self.handle_reliable_send(&datagram)?,
+            DeliveryGuarantee::Unreliable | DeliveryGuarantee::Redundant => {
+                self.handle_unreliable_send(&datagram)?
+            }
+        };
+        let settings = self.config.stream_settings(datagram.stream_id);
+        self.scheduler.push(message, settings);
+        Ok(())
+    }
+
+    /// Processes a packet received from the remote endpoint. The datagrams it carried are
+    /// returned by `poll_datagram`.
+    pub fn receive(&mut self, packet: &[u8]) -> ProtocolResult<()> {
+        self.receive_bytes(Bytes::from(packet))
+    }
+
+    /// Like `receive`, but takes ownership of the packet. The datagrams it carried share its
+    /// memory rather than being copied out of it.
+    pub fn receive_bytes(&mut self, packet: Bytes) -> ProtocolResult<()> {
+        self.metrics.record_received(packet.len());
+        let timestamp = self.last_update_time.unwrap_or(0);
+        self.recorder
+            .record(timestamp, CaptureKind::Inbound, &packet);
+        let result = self.receive_packet(packet);
+        if result.is_err() {
+            self.metrics.increment(DataPoint::PacketsInvalid);
+        }
+        result
+    }
+
+    /// Returns `VersionMismatch` once the remote turned out to speak another version of the wire
+    /// protocol, from then on nothing but the hellos telling it so is sent.
+    pub fn state(&self) -> ConnectionState {
+        self.connection.state()
+    }
+
+    /// Returns the optional features both endpoints offered, once the hello of the remote was
+    /// received. Packets which arrive before it are dropped.
+    pub fn features(&self) -> Option<Features> {
+        self.handshake.features()
+    }
+
+    /// Returns the metrics of this endpoint, including the ones of its reliability layer. They're
+    /// brought up to date on every `update`.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Returns the histogram of the round trip times measured by the reliability layer.
+    pub fn rtt_histogram(&self) -> &RttHistogram {
+        self.connection.rtt_histogram()
+    }
+
+    /// Forgets the round trip times measured so far, e.g. at the start of a match.
+    pub fn reset_rtt_histogram(&mut self) {
+        self.connection.reset_rtt_histogram();
+    }
+
+    /// Sets the recorder which captures every `update` and every packet received or polled, for
+    /// analysis with the capture tools. Unlike the captures of a `ReliableConnection` these can't
+    /// be replayed. A recorder which fails is dropped.
+    pub fn set_recorder<R>(&mut self, recorder: R)
+    where
+        R: PacketRecorder + 'static,
+    {
+        self.recorder.set(Some(Box::new(recorder)));
+    }
+
+    pub fn clear_recorder(&mut self) {
+        self.recorder.set(None);
+    }
+
+    fn receive_packet(&mut self, packet: Bytes) -> ProtocolResult<()> {
+        if packet.first() == Some(&PACKET_HELLO) {
+            return self.handle_hello(&packet);
+        }
+        if let Some(version) = self.handshake.mismatched_version() {
+            return Err(ProtocolError::VersionMismatch(PROTOCOL_VERSION, version));
+        }
+        let features = match self.handshake.features() {
+            Some(features) => features,
+            None => {
+                // Its layout may not even be the one of our version
+                debug!("Dropping a packet received before the hello of the remote");
+                return Ok(());
+            }
+        };
+
+        match packet.first() {
+            Some(&PACKET_FEC_DATA) | Some(&PACKET_FEC_PARITY)
+                if features.contains(Features::FEC) =>
+            {
+                let decoded = self
+                    .fec_decoder
+                    .decode(packet)
+                    .ok_or(ProtocolError::MalformedPacket)?;
+                if let Some(recovered) = decoded.recovered {
+                    debug!("Recovered a lost packet of {} bytes", recovered.len());
+                    self.metrics.increment(DataPoint::PacketsRecovered);
+                    self.handle_packet(recovered)?;
+                }
+                match decoded.packet {
+                    Some(packet) => self.handle_packet(packet),
+                    None => Ok(()),
+                }
+            }
+            _ => self.handle_packet(packet),
+        }
+    }
+
+    fn handle_hello(&mut self, packet: &[u8]) -> ProtocolResult<()> {
+        let hello = Hello::decode(packet).ok_or(ProtocolError::MalformedPacket)?;
+        let result = self.handshake.receive(hello);
+        if result.is_err() {
+            self.connection.disconnect(ConnectionState::VersionMismatch);
+        }
+        result
+    }
+
+    /// Returns the next datagram received from the remote endpoint.
+    pub fn poll_datagram(&mut self) -> Option<ReceivedDatagram> {
+        self.received.pop_front()
+    }
+
+    /// Schedules queued datagrams and updates the reliability layer. Call it repeatedly (every
+    /// 10ms-100ms) with the current time in millisec and then drain the outgoing packets with
+    /// `poll_packet`.
+    pub fn update(&mut self, current: u32) -> ProtocolResult<()> {
+        let elapsed = match self.last_update_time {
+            Some(last_update_time) => time_diff(current, last_update_time).max(0) as u32,
+            None => 0,
+        };
+        self.last_update_time = Some(current);
+        self.metrics.tick(elapsed);
+        self.recorder.record(current, CaptureKind::Update, &[]);
+
+        let state = self.connection.state();
+        if state == ConnectionState::Connected || state == ConnectionState::VersionMismatch {
+            if let Some(hello) = self.handshake.poll_hello() {
+                self.packets.push_back(hello);
+            }
+        }
+        if state == ConnectionState::VersionMismatch {
+            return Ok(());
+        }
+        // Packets are only protected once the remote is known to understand parity packets
+        let fec = self
+            .handshake
+            .features()
+            .is_some_and(|features| features.contains(Features::FEC));
+
+        self.scheduler
+            .refill(elapsed, self.connection.estimated_bandwidth());
+        let scheduled = self
+            .scheduler
+            .schedule(&mut self.connection, max_packet_size(&self.config))?;
+        for _ in 0..scheduled.dropped {
+            self.metrics.increment(DataPoint::MessagesDropped);
+        }
+        for (packet, redundancy) in scheduled.packets {
+            let redundancy = if fec { redundancy } else { 0.0 };
+            self.fec_encoder
+                .encode(packet, redundancy, &mut self.packets);
+        }
+
+        if fec {
+            self.reliable_redundancy = self.reliable_redundancy.max(scheduled.reliable_redundancy);
+        }
+        self.connection.update(current)?;
+        for packet in self.connection.output_mut().0.drain(..) {
+            self.fec_encoder
+                .encode(packet, self.reliable_redundancy, &mut self.packets);
+        }
+        if self.connection.num_segments_awaiting_send() == 0 {
+            self.reliable_redundancy = 0.0;
         }
+
+        self.fec_encoder.finish(&mut self.packets);
+        self.metrics.sync_connection(self.connection.metrics());
+
+        if !self.redundant_acks.is_empty() {
+            let mut packet = BytesMut::with_capacity(1 + 3 * self.redundant_acks.len());
+            packet.put_u8(PACKET_REDUNDANT_ACK);
+            for (stream_id, sequence_num) in self.redundant_acks.drain(..) {
+                packet.put_u8(stream_id);
+                packet.put_u16_be(sequence_num);
+            }
+            self.packets.push_back(packet.freeze());
+        }
+        Ok(())
     }
 
-    /// Process a datagram to send. Returns a Bytes object representing the appropriately serialized
-    /// datagram.
-    pub fn send(&mut self, datagram: Datagram) -> ProtocolResult<Bytes> {
-        match datagram.delivery {
-            DeliveryGuarantee::Reliable => self.handle_reliable_send(datagram),
-            DeliveryGuarantee::Unreliable => self.handle_unreliable_send(datagram),
+    /// Returns the next packet which is ready to be sent to the remote endpoint.
+    pub fn poll_packet(&mut self) -> Option<Bytes> {
+        let packet = self.packets.pop_front()?;
+        self.metrics.record_sent(packet.len());
+        let timestamp = self.last_update_time.unwrap_or(0);
+        self.recorder
+            .record(timestamp, CaptureKind::Outbound, &packet);
+        Some(packet)
+    }
+
+    fn handle_packet(&mut self, mut packet: Bytes) -> ProtocolResult<()> {
+        match packet.first() {
+            Some(&PACKET_UNRELIABLE) => self.handle_messages(packet.slice_from(1)),
+            Some(&PACKET_RELIABLE) => {
+                // Advancing rather than slicing leaves the connection the only owner of the packet
+                packet.advance(1);
+                self.connection.input_bytes(packet)?;
+                while let Ok(message) = self.connection.recv_bytes() {
+                    self.handle_messages(message)?;
+                }
+                Ok(())
+            }
+            Some(&PACKET_REDUNDANT_ACK) => {
+                let mut cursor = Cursor::new(&packet[1..]);
+                if !cursor.remaining().is_multiple_of(3) {
+                    return Err(ProtocolError::MalformedPacket);
+                }
+                while cursor.has_remaining() {
+                    let stream_id = cursor.get_u8();
+                    let sequence_num = cursor.get_u16_be();
+                    self.scheduler.acknowledge(stream_id, sequence_num);
+                }
+                Ok(())
+            }
+            _ => Err(ProtocolError::MalformedPacket),
         }
     }
 
-    /// Process received data into a datagram
-    pub fn receive(&mut self, datagram: &[u8]) -> ProtocolResult<ReceivedDatagram> {
-        Ok(ReceivedDatagram::Full { payload: "".into() })
+    // Queues every message of a packet (or of a message received through the reliability layer)
+    // which satisfies its ordering guarantee.
+    fn handle_messages(&mut self, mut payload: Bytes) -> ProtocolResult<()> {
+        while !payload.is_empty() {
+            let message = Message::decode(&mut payload).ok_or(ProtocolError::MalformedPacket)?;
+            if message.ordering == OrderingGuarantee::Sequenced {
+                let stream = self
+                    .sequenced_streams
+                    .get_mut(usize::from(message.stream_id))
+                    .ok_or(ProtocolError::InvalidStreamId)?;
+                let accepted = stream.accept(message.sequence_num);
+                if message.delivery == DeliveryGuarantee::Redundant {
+                    // Acknowledge even duplicates so that the remote stops resending them
+                    if let Some(newest) = stream.last_received() {
+                        acknowledge(&mut self.redundant_acks, message.stream_id, newest);
+                    }
+                }
+                if !accepted {
+                    debug!("Ignoring stale message on stream {}", message.stream_id);
+                    continue;
+                }
+            }
+            self.received.push_back(datagram::full(message.payload));
+        }
+        Ok(())
     }
 
-    fn handle_reliable_send(&mut self, datagram: Datagram) -> ProtocolResult<Bytes> {
+    fn handle_reliable_send(&mut self, datagram: &Datagram) -> ProtocolResult<Message> {
         if datagram.payload.len() > self.config.max_payload_size_bytes() {
             self.metrics.increment(DataPoint::PacketsTooLargeToSend);
             return Err(ProtocolError::PayloadTooLarge(
@@ -59,35 +409,49 @@ impl Endpoint {
             ));
         }
 
-        //        let bytes = BytesMut::with_capacity(datagram.payload.len());
-        //
-        //        Ok(bytes.freeze())
-
         let stream_id = datagram.stream_id;
 
-        match datagram.ordering {
-            OrderingGuarantee::None => Ok(Bytes::new()),
+        let sequence_num = match datagram.ordering {
+            OrderingGuarantee::None => 0,
             OrderingGuarantee::Sequenced => {
                 if stream_id >= self.sequenced_streams.len() {
                     return Err(ProtocolError::InvalidStreamId);
                 }
-                let stream: &SequencedStream = &self.sequenced_streams[stream_id];
-                Ok(Bytes::new())
+                self.sequenced_streams[stream_id].next_sequence_num()
             }
             OrderingGuarantee::Ordered => {
                 if stream_id >= self.ordered_streams.len() {
                     return Err(ProtocolError::InvalidStreamId);
                 }
-                let stream: &OrderedStream = &self.ordered_streams[stream_id];
-                Ok(Bytes::new())
+                self.ordered_streams[stream_id].next_sequence_num()
             }
-        }
+        };
+
+        Ok(new_message(datagram, sequence_num))
     }
 
-    fn handle_unreliable_send(&mut self, datagram: Datagram) -> ProtocolResult<Bytes> {
+    fn handle_unreliable_send(&mut self, datagram: &Datagram) -> ProtocolResult<Message> {
+        // Unreliable datagrams are never split so they have to fit in a single packet.
+        let max_payload_size = max_packet_size(&self.config) - MESSAGE_OVERHEAD - 1;
+        if datagram.payload.len() > max_payload_size {
+            self.metrics.increment(DataPoint::PacketsTooLargeToSend);
+            return Err(ProtocolError::PayloadTooLarge(
+                datagram.payload.len(),
+                max_payload_size,
+            ));
+        }
+
+        let stream_id = datagram.stream_id;
+
         match datagram.ordering {
-            OrderingGuarantee::None => Ok(Bytes::new()),
-            OrderingGuarantee::Sequenced => Ok(Bytes::new()),
+            OrderingGuarantee::None => Ok(new_message(datagram, 0)),
+            OrderingGuarantee::Sequenced => {
+                if stream_id >= self.sequenced_streams.len() {
+                    return Err(ProtocolError::InvalidStreamId);
+                }
+                let sequence_num = self.sequenced_streams[stream_id].next_sequence_num();
+                Ok(new_message(datagram, sequence_num))
+            }
             OrderingGuarantee::Ordered => {
                 // This should never be able to be configured.
                 Err(ProtocolError::InvalidConfiguration(
@@ -98,28 +462,139 @@ impl Endpoint {
     }
 }
 
+// Records the newest message received on a redundant stream.
+fn acknowledge(acks: &mut Vec<(u8, u16)>, stream_id: u8, sequence_num: u16) {
+    match acks.iter_mut().find(|(id, _)| *id == stream_id) {
+        Some(ack) => ack.1 = sequence_num,
+        None => acks.push((stream_id, sequence_num)),
+    }
+}
+
+fn new_message(datagram: &Datagram, sequence_num: u16) -> Message {
+    Message {
+        // `send` only lets through stream ids which fit in a byte
+        stream_id: datagram.stream_id as u8,
+        delivery: datagram.delivery,
+        ordering: datagram.ordering,
+        sequence_num,
+        payload: Bytes::from(datagram.payload),
+    }
+}
+
+impl Default for Endpoint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Returns the largest packet which may be handed to the FEC encoder. Room for the FEC headers is
+// kept whenever FEC is offered, as the connection is set up before the handshake tells whether
+// it's used.
+fn max_packet_size(config: &Config) -> usize {
+    if config.features().contains(Features::FEC) {
+        config.mtu() - FEC_OVERHEAD
+    } else {
+        config.mtu()
+    }
+}
+
+// Returns the settings of the reliability layer, whose packets have to leave room for the packet
+// type byte added by `PacketQueue`.
+fn connection_config(config: &Config) -> Config {
+    config.clone().with_mtu(max_packet_size(config) - 1)
+}
+
+// Collects the packets written by the reliability layer, tagging each of them as reliable.
+#[derive(Default)]
+struct PacketQueue(VecDeque<Bytes>);
+
+impl io::Write for PacketQueue {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut packet = BytesMut::with_capacity(buf.len() + 1);
+        packet.put_u8(PACKET_RELIABLE);
+        packet.put_slice(buf);
+        self.0.push_back(packet.freeze());
+        debug!("Queued reliable packet of {} bytes", buf.len());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::{Config, Datagram, DeliveryGuarantee, Endpoint, OrderingGuarantee, ProtocolError};
+    use super::{
+        Config, DataPoint, Datagram, DeliveryGuarantee, Endpoint, OrderingGuarantee, ProtocolError,
+        ReceivedDatagram,
+    };
+    use crate::{
+        handshake::Hello, ConnectionState, Features, Preset, WireFormat, PROTOCOL_VERSION,
+    };
+    use crate::{
+        CaptureKind, PacketRecorder, PACKET_FEC_DATA, PACKET_FEC_PARITY, PACKET_REDUNDANT_ACK,
+        PACKET_RELIABLE, PACKET_UNRELIABLE,
+    };
+    use bytes::Bytes;
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    fn poll_packets(endpoint: &mut Endpoint) -> Vec<Bytes> {
+        let mut packets = Vec::new();
+        while let Some(packet) = endpoint.poll_packet() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    // Creates two endpoints which went through the handshake, so that their packets only carry
+    // data. The hellos are exchanged without updating the endpoints.
+    fn connect(client_config: Config, server_config: Config) -> (Endpoint, Endpoint) {
+        let mut client = Endpoint::with_config(client_config).unwrap();
+        let mut server = Endpoint::with_config(server_config).unwrap();
+        for _ in 0..2 {
+            if let Some(hello) = client.handshake.poll_hello() {
+                server.receive(&hello).unwrap();
+            }
+            if let Some(hello) = server.handshake.poll_hello() {
+                client.receive(&hello).unwrap();
+            }
+        }
+        (client, server)
+    }
+
+    fn poll_payloads(endpoint: &mut Endpoint) -> Vec<Vec<u8>> {
+        let mut payloads = Vec::new();
+        while let Some(datagram) = endpoint.poll_datagram() {
+            match datagram {
+                ReceivedDatagram::Full { payload } => payloads.push(payload.to_vec()),
+                ReceivedDatagram::Fragment { .. } => panic!("unexpected fragment"),
+            }
+        }
+        payloads
+    }
 
     #[test]
     fn error_on_large_payload_for_reliable_send() {
         let config = Config::default()
             .with_max_fragments(1)
             .with_fragment_size_bytes(1);
-        let mut endpoint = Endpoint::new(config);
+        let mut endpoint = Endpoint::with_config(config).unwrap();
         let payload = "Hello world!".as_bytes();
         let datagram = Datagram::reliable(payload);
         assert_eq!(
             endpoint.send(datagram).unwrap_err(),
-            ProtocolError::PayloadTooLarge(12, 2)
+            ProtocolError::PayloadTooLarge(12, 1)
         );
     }
 
     #[test]
     fn error_on_invalid_stream_id_ordered() {
         let config = Config::default();
-        let mut endpoint = Endpoint::new(config);
+        let mut endpoint = Endpoint::with_config(config).unwrap();
         let payload = "Hello world!".as_bytes();
         let datagram = Datagram::reliable_ordered(payload, 2);
         assert_eq!(
@@ -131,7 +606,7 @@ mod test {
     #[test]
     fn error_on_invalid_stream_id_sequenced() {
         let config = Config::default();
-        let mut endpoint = Endpoint::new(config);
+        let mut endpoint = Endpoint::with_config(config).unwrap();
         let payload = "Hello world!".as_bytes();
         let datagram = Datagram::reliable_sequenced(payload, 2);
         assert_eq!(
@@ -140,10 +615,36 @@ mod test {
         );
     }
 
+    #[test]
+    fn error_on_stream_id_out_of_range() {
+        let config = Config::default()
+            .with_ordered_streams_size(255)
+            .with_sequenced_streams_size(255);
+        let mut endpoint = Endpoint::with_config(config).unwrap();
+        for stream_id in [255, 256].iter() {
+            assert_eq!(
+                endpoint
+                    .send(Datagram::sequenced(b"hello", *stream_id))
+                    .unwrap_err(),
+                ProtocolError::InvalidStreamId
+            );
+            assert_eq!(
+                endpoint
+                    .send(Datagram::reliable_ordered(b"hello", *stream_id))
+                    .unwrap_err(),
+                ProtocolError::InvalidStreamId
+            );
+        }
+        endpoint.send(Datagram::sequenced(b"hello", 254)).unwrap();
+
+        let too_many = Config::default().with_sequenced_streams_size(256);
+        assert!(Endpoint::with_config(too_many).is_err());
+    }
+
     #[test]
     fn error_on_unreliable_ordered_config() {
         let config = Config::default();
-        let mut endpoint = Endpoint::new(config);
+        let mut endpoint = Endpoint::with_config(config).unwrap();
         let payload = "Hello world!".as_bytes();
         let datagram = Datagram {
             stream_id: 0,
@@ -156,4 +657,475 @@ mod test {
             ProtocolError::InvalidConfiguration("Unable to send an unreliable and ordered packet.")
         )
     }
+
+    #[test]
+    fn error_on_unreliable_payload_larger_than_a_packet() {
+        let mut endpoint = Endpoint::new();
+        let payload = vec![0; 1400];
+        assert_eq!(
+            endpoint.send(Datagram::unreliable(&payload)).unwrap_err(),
+            ProtocolError::PayloadTooLarge(1400, 1387)
+        );
+    }
+
+    #[test]
+    fn nothing_sent_until_update() {
+        let mut endpoint = Endpoint::new();
+        endpoint.send(Datagram::unreliable(b"hello")).unwrap();
+        assert!(endpoint.poll_packet().is_none());
+        endpoint.update(0).unwrap();
+        assert!(endpoint.poll_packet().is_some());
+    }
+
+    #[test]
+    fn higher_priority_streams_packed_first() {
+        let config = Config::default()
+            .with_sequenced_streams_size(2)
+            .with_stream_priority(1, 10);
+        let mut endpoint = connect(config, Config::default()).0;
+        endpoint.send(Datagram::sequenced(b"chat", 0)).unwrap();
+        endpoint.send(Datagram::sequenced(b"state", 1)).unwrap();
+        endpoint.update(0).unwrap();
+
+        let packet = endpoint.poll_packet().unwrap();
+        assert_eq!(packet[0], PACKET_UNRELIABLE);
+        assert_eq!(&packet[1..7], &[1, 0x02, 0, 0, 0, 5]);
+        assert_eq!(&packet[7..12], b"state");
+        assert_eq!(&packet[12..18], &[0, 0x02, 0, 0, 0, 4]);
+        assert_eq!(&packet[18..22], b"chat");
+    }
+
+    #[test]
+    fn reliable_datagrams_sent_through_the_connection() {
+        let mut endpoint = connect(Config::default(), Config::default()).0;
+        endpoint
+            .send(Datagram::reliable_ordered(b"hello", 0))
+            .unwrap();
+        endpoint
+            .send(Datagram::reliable_ordered(b"world", 0))
+            .unwrap();
+        endpoint.update(0).unwrap();
+
+        let packet = endpoint.poll_packet().unwrap();
+        assert_eq!(packet[0], PACKET_RELIABLE);
+        // Two segments, each carrying a message header and a five byte payload
+        assert_eq!(packet.len(), 1 + 2 * (24 + 6 + 5));
+        assert_eq!(&packet[25..31], &[0, 0x11, 0, 0, 0, 5]);
+        assert_eq!(&packet[60..66], &[0, 0x11, 0, 1, 0, 5]);
+        assert!(endpoint.poll_packet().is_none());
+    }
+
+    #[test]
+    fn packets_never_longer_than_the_mtu() {
+        let config = Config::default()
+            .with_mtu(200)
+            .with_stream_redundancy(0, 0.5)
+            .with_stream_redundancy(0xFF, 0.5);
+        let (mut client, mut server) = connect(config.clone(), config);
+        let reliable: Vec<u8> = (0..1_000).map(|i| i as u8).collect();
+        client
+            .send(Datagram::reliable_ordered(&reliable, 0))
+            .unwrap();
+        client.send(Datagram::unreliable(&[1; 187])).unwrap();
+
+        let mut payloads = Vec::new();
+        for step in 0..20 {
+            client.update(step * 10).unwrap();
+            server.update(step * 10).unwrap();
+            for packet in poll_packets(&mut client) {
+                assert!(packet.len() <= 200);
+                server.receive(&packet).unwrap();
+            }
+            for packet in poll_packets(&mut server) {
+                client.receive(&packet).unwrap();
+            }
+            payloads.extend(poll_payloads(&mut server));
+        }
+        assert_eq!(payloads, vec![vec![1; 187], reliable]);
+    }
+
+    #[test]
+    fn unreliable_datagrams_over_budget_are_dropped() {
+        let config = Config::default().with_stream_bandwidth_budget(0xFF, 10);
+        let mut endpoint = connect(config, Config::default()).0;
+        endpoint.send(Datagram::unreliable(b"first")).unwrap();
+        endpoint.send(Datagram::unreliable(b"second")).unwrap();
+        endpoint.update(0).unwrap();
+
+        assert_eq!(endpoint.poll_packet().unwrap().len(), 1 + 6 + 5);
+        assert!(endpoint.poll_packet().is_none());
+        assert_eq!(endpoint.metrics.get_count(DataPoint::MessagesDropped), 1);
+    }
+
+    #[test]
+    fn unreliable_only_traffic_not_held_back_by_congestion_control() {
+        let config = Config::default().with_preset(Preset::Normal);
+        let (mut client, mut server) = connect(config.clone(), config);
+        let mut payloads = Vec::new();
+        for step in 0..60 {
+            for _ in 0..4 {
+                client
+                    .send(Datagram::unreliable(&[step as u8; 200]))
+                    .unwrap();
+            }
+            client.update(step * 16).unwrap();
+            for packet in poll_packets(&mut client) {
+                server.receive(&packet).unwrap();
+            }
+            payloads.extend(poll_payloads(&mut server));
+        }
+        assert_eq!(client.metrics.get_count(DataPoint::MessagesDropped), 0);
+        assert_eq!(payloads.len(), 240);
+    }
+
+    #[test]
+    fn reconfigure_lowers_mtu_of_running_endpoint() {
+        let mut client = Endpoint::new();
+        let mut server = Endpoint::new();
+        let reliable: Vec<u8> = (0..1_400).map(|i| i as u8).collect();
+        client.send(Datagram::unreliable(&[1; 800])).unwrap();
+        client.send(Datagram::unreliable(b"small")).unwrap();
+        client
+            .send(Datagram::reliable_ordered(&reliable, 0))
+            .unwrap();
+
+        let config = Config::default().with_preset(Preset::Turbo).with_mtu(500);
+        client.reconfigure(config).unwrap();
+        assert_eq!(client.metrics.get_count(DataPoint::MessagesDropped), 1);
+
+        let mut payloads = Vec::new();
+        for step in 0..10 {
+            client.update(step * 10).unwrap();
+            server.update(step * 10).unwrap();
+            for packet in poll_packets(&mut client) {
+                assert!(packet.len() <= 500);
+                server.receive(&packet).unwrap();
+            }
+            for packet in poll_packets(&mut server) {
+                client.receive(&packet).unwrap();
+            }
+            payloads.extend(poll_payloads(&mut server));
+        }
+        assert_eq!(payloads, vec![b"small".to_vec(), reliable]);
+    }
+
+    #[test]
+    fn reconfigure_keeps_the_number_of_streams() {
+        let mut endpoint = Endpoint::new();
+        let config = Config::default().with_ordered_streams_size(4);
+        assert_eq!(
+            endpoint.reconfigure(config).unwrap_err(),
+            ProtocolError::InvalidConfiguration("")
+        );
+        assert!(endpoint
+            .reconfigure(Config::default().with_mtu(10))
+            .is_err());
+        assert!(endpoint
+            .reconfigure(Config::default().with_wire_format(WireFormat::Kcp))
+            .is_err());
+    }
+
+    #[test]
+    fn packets_before_the_hello_are_dropped() {
+        let mut client = Endpoint::new();
+        let mut server = Endpoint::new();
+        client.send(Datagram::unreliable(b"early")).unwrap();
+        client.update(0).unwrap();
+        let packets = poll_packets(&mut client);
+        assert_eq!(packets.len(), 2);
+        server.receive(&packets[1]).unwrap();
+        assert!(server.poll_datagram().is_none());
+        assert_eq!(server.features(), None);
+
+        server.receive(&packets[0]).unwrap();
+        server.receive(&packets[1]).unwrap();
+        assert_eq!(poll_payloads(&mut server), vec![b"early".to_vec()]);
+        assert_eq!(server.features(), Some(Features::FEC));
+    }
+
+    #[test]
+    fn version_mismatch_disconnects() {
+        let mut endpoint = Endpoint::new();
+        let hello = Hello {
+            version: PROTOCOL_VERSION + 1,
+            features: Features::FEC,
+            received: false,
+            wants_reply: true,
+        };
+        assert_eq!(
+            endpoint.receive(&hello.encode()).unwrap_err(),
+            ProtocolError::VersionMismatch(0, 0)
+        );
+        assert_eq!(endpoint.state(), ConnectionState::VersionMismatch);
+        assert_eq!(
+            endpoint.receive(&[PACKET_UNRELIABLE]).unwrap_err(),
+            ProtocolError::VersionMismatch(0, 0)
+        );
+
+        // Only the hello telling the remote our version goes out
+        endpoint.send(Datagram::unreliable(b"data")).unwrap();
+        endpoint.update(0).unwrap();
+        let packets = poll_packets(&mut endpoint);
+        assert_eq!(packets.len(), 1);
+        let reply = Hello::decode(&packets[0]).unwrap();
+        assert_eq!(reply.version, PROTOCOL_VERSION);
+        assert!(!reply.wants_reply);
+        endpoint.update(100).unwrap();
+        assert!(endpoint.poll_packet().is_none());
+    }
+
+    #[test]
+    fn fec_only_used_when_both_endpoints_offer_it() {
+        let config = Config::default().with_stream_redundancy(0xFF, 0.5);
+        let plain = Config::default().with_features(Features::empty());
+        let (mut client, mut server) = connect(config.clone(), plain.clone());
+        assert_eq!(client.features(), Some(Features::empty()));
+        client.send(Datagram::unreliable(b"plain")).unwrap();
+        client.update(0).unwrap();
+        let packets = poll_packets(&mut client);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0][0], PACKET_UNRELIABLE);
+        assert!(client.reconfigure(plain).is_err());
+
+        // Parity packets aren't accepted by an endpoint which didn't offer FEC
+        let (mut protected, _) = connect(config, Config::default());
+        protected.send(Datagram::unreliable(b"protected")).unwrap();
+        protected.update(0).unwrap();
+        let packets = poll_packets(&mut protected);
+        assert_eq!(packets[0][0], PACKET_FEC_DATA);
+        assert_eq!(
+            server.receive(&packets[0]).unwrap_err(),
+            ProtocolError::MalformedPacket
+        );
+    }
+
+    #[test]
+    fn kcp_wire_format_is_rejected() {
+        let config = Config::default().with_wire_format(WireFormat::Kcp);
+        assert!(Endpoint::with_config(config).is_err());
+    }
+
+    #[test]
+    fn datagrams_received_from_remote_endpoint() {
+        let mut client = Endpoint::new();
+        let mut server = Endpoint::new();
+        client.send(Datagram::unreliable(b"unreliable")).unwrap();
+        client
+            .send(Datagram::reliable_ordered(b"reliable", 0))
+            .unwrap();
+        client.update(0).unwrap();
+        for packet in poll_packets(&mut client) {
+            server.receive(&packet).unwrap();
+        }
+        assert_eq!(
+            poll_payloads(&mut server),
+            vec![b"unreliable".to_vec(), b"reliable".to_vec()]
+        );
+    }
+
+    #[test]
+    fn stale_sequenced_datagrams_are_ignored() {
+        let mut client = Endpoint::new();
+        let mut server = Endpoint::new();
+        client.send(Datagram::sequenced(b"old", 0)).unwrap();
+        client.update(0).unwrap();
+        let old = poll_packets(&mut client);
+        client.send(Datagram::sequenced(b"new", 0)).unwrap();
+        client.update(100).unwrap();
+        for packet in poll_packets(&mut client).iter().chain(old.iter()) {
+            server.receive(packet).unwrap();
+        }
+        assert_eq!(poll_payloads(&mut server), vec![b"new".to_vec()]);
+    }
+
+    #[test]
+    fn malformed_packet_is_rejected() {
+        let mut endpoint = connect(Config::default(), Config::default()).0;
+        assert_eq!(
+            endpoint.receive(&[PACKET_UNRELIABLE, 0, 0]).unwrap_err(),
+            ProtocolError::MalformedPacket
+        );
+        assert_eq!(
+            endpoint.receive(&[42]).unwrap_err(),
+            ProtocolError::MalformedPacket
+        );
+    }
+
+    #[test]
+    fn lost_unreliable_packet_recovered_by_fec() {
+        let config = Config::default().with_stream_redundancy(0xFF, 0.5);
+        let (mut client, mut server) = connect(config, Config::default());
+        // Each datagram fills its own packet
+        client.send(Datagram::unreliable(&[1; 1000])).unwrap();
+        client.send(Datagram::unreliable(&[2; 1000])).unwrap();
+        client.update(0).unwrap();
+
+        let packets = poll_packets(&mut client);
+        assert_eq!(packets.len(), 3);
+        assert_eq!(packets[0][0], PACKET_FEC_DATA);
+        assert_eq!(packets[2][0], PACKET_FEC_PARITY);
+        for packet in packets.iter().skip(1) {
+            server.receive(packet).unwrap();
+        }
+        assert_eq!(
+            poll_payloads(&mut server),
+            vec![vec![2; 1000], vec![1; 1000]]
+        );
+        assert_eq!(server.metrics.get_count(DataPoint::PacketsRecovered), 1);
+
+        // The original arriving late isn't delivered twice
+        server.receive(&packets[0]).unwrap();
+        assert!(server.poll_datagram().is_none());
+    }
+
+    #[test]
+    fn lost_reliable_packet_recovered_by_fec() {
+        let config = Config::default().with_stream_redundancy(0, 0.25);
+        let (mut client, mut server) = connect(config, Config::default());
+        client
+            .send(Datagram::reliable_ordered(b"hello", 0))
+            .unwrap();
+        client.update(0).unwrap();
+
+        let packets = poll_packets(&mut client);
+        assert_eq!(packets.len(), 2);
+        server.receive(&packets[1]).unwrap();
+        assert_eq!(poll_payloads(&mut server), vec![b"hello".to_vec()]);
+        assert_eq!(server.metrics.get_count(DataPoint::PacketsRecovered), 1);
+    }
+
+    #[test]
+    fn unprotected_streams_are_not_wrapped() {
+        let config = Config::default()
+            .with_sequenced_streams_size(2)
+            .with_stream_redundancy(1, 0.5);
+        let mut endpoint = connect(config, Config::default()).0;
+        endpoint.send(Datagram::sequenced(b"plain", 0)).unwrap();
+        endpoint.update(0).unwrap();
+        let packets = poll_packets(&mut endpoint);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0][0], PACKET_UNRELIABLE);
+    }
+
+    #[test]
+    fn redundant_datagrams_survive_lost_packets() {
+        let (mut client, mut server) = connect(Config::default(), Config::default());
+        client.send(Datagram::redundant(b"input 1", 0)).unwrap();
+        client.update(0).unwrap();
+        // Lose the first packet
+        poll_packets(&mut client);
+
+        client.send(Datagram::redundant(b"input 2", 0)).unwrap();
+        client.update(100).unwrap();
+        let packets = poll_packets(&mut client);
+        assert_eq!(packets.len(), 1);
+        // A duplicate delivers nothing new
+        server.receive(&packets[0]).unwrap();
+        server.receive(&packets[0]).unwrap();
+        assert_eq!(
+            poll_payloads(&mut server),
+            vec![b"input 1".to_vec(), b"input 2".to_vec()]
+        );
+
+        server.update(100).unwrap();
+        let acks = poll_packets(&mut server);
+        assert_eq!(
+            acks,
+            vec![Bytes::from_static(&[PACKET_REDUNDANT_ACK, 0, 0, 1])]
+        );
+        client.receive(&acks[0]).unwrap();
+
+        // Acknowledged messages are no longer resent
+        client.send(Datagram::redundant(b"input 3", 0)).unwrap();
+        client.update(200).unwrap();
+        let packets = poll_packets(&mut client);
+        assert_eq!(packets[0].len(), 1 + 6 + 7);
+        server.receive(&packets[0]).unwrap();
+        assert_eq!(poll_payloads(&mut server), vec![b"input 3".to_vec()]);
+    }
+
+    #[test]
+    fn redundant_history_is_limited() {
+        let config = Config::default().with_stream_redundant_history(0, 2);
+        let mut endpoint = connect(config, Config::default()).0;
+        for (time, input) in [b"1", b"2", b"3"].iter().enumerate() {
+            endpoint.send(Datagram::redundant(&input[..], 0)).unwrap();
+            endpoint.update(time as u32 * 100).unwrap();
+        }
+        let packets = poll_packets(&mut endpoint);
+        let last = &packets[2];
+        assert_eq!(last.len(), 1 + 2 * (6 + 1));
+        assert_eq!(&last[1..8], &[0, 0x22, 0, 1, 0, 1, b'2']);
+        assert_eq!(&last[8..15], &[0, 0x22, 0, 2, 0, 1, b'3']);
+    }
+
+    #[test]
+    fn metrics_include_reliability_layer() {
+        let mut client = Endpoint::new();
+        let mut server = Endpoint::new();
+        client
+            .send(Datagram::reliable_ordered(b"hello", 0))
+            .unwrap();
+        for time in 0..5 {
+            client.update(time * 100).unwrap();
+            server.update(time * 100).unwrap();
+            for packet in poll_packets(&mut client) {
+                server.receive(&packet).unwrap();
+            }
+            for packet in poll_packets(&mut server) {
+                client.receive(&packet).unwrap();
+            }
+        }
+        assert!(server.receive(&[42]).is_err());
+
+        // Both endpoints opened at once, so their hellos crossed and three went each way besides
+        // the data and its ack
+        let metrics = client.metrics();
+        assert_eq!(metrics.get_count(DataPoint::PacketsSent), 4);
+        assert_eq!(metrics.get_count(DataPoint::PacketsReceived), 4);
+        assert_eq!(metrics.get_count(DataPoint::PacketsAcked), 1);
+        assert!(metrics.rtt() > 0);
+        assert_eq!(server.metrics().get_count(DataPoint::PacketsInvalid), 1);
+    }
+
+    #[test]
+    fn test_recorder_captures_packets() {
+        type Record = (u32, CaptureKind, Vec<u8>);
+        struct Records(Arc<Mutex<Vec<Record>>>);
+
+        impl PacketRecorder for Records {
+            fn record(&mut self, timestamp: u32, kind: CaptureKind, data: &[u8]) -> io::Result<()> {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push((timestamp, kind, data.to_vec()));
+                Ok(())
+            }
+        }
+
+        let records = Arc::new(Mutex::new(Vec::new()));
+        // The hellos are exchanged first, so that the data packet is the only one polled
+        let (mut sender, mut receiver) = connect(Config::default(), Config::default());
+        sender.set_recorder(Records(Arc::clone(&records)));
+        receiver.set_recorder(Records(Arc::clone(&records)));
+
+        sender.send(Datagram::unreliable(b"hello")).unwrap();
+        sender.update(10).unwrap();
+        let packet = sender.poll_packet().unwrap();
+        assert_eq!(packet[0], PACKET_UNRELIABLE);
+        assert!(sender.poll_packet().is_none());
+        receiver.update(20).unwrap();
+        receiver.receive(&packet).unwrap();
+
+        let records = records.lock().unwrap();
+        assert_eq!(
+            *records,
+            vec![
+                (10, CaptureKind::Update, Vec::new()),
+                (10, CaptureKind::Outbound, packet.to_vec()),
+                (20, CaptureKind::Update, Vec::new()),
+                (20, CaptureKind::Inbound, packet.to_vec()),
+            ]
+        );
+    }
 }
@@ -15,11 +15,14 @@ pub enum ProtocolError {
     BufferTooSmall,
     InvalidSessionId,
     InvalidCommand,
+    MalformedPacket,
     IOError(io::Error),
 
     PayloadTooLarge(usize, usize),
     InvalidStreamId,
     InvalidConfiguration(&'static str),
+    /// The remote speaks another version of the wire protocol: (local version, remote version).
+    VersionMismatch(u16, u16),
 }
 
 impl Display for ProtocolError {
@@ -43,6 +46,7 @@ impl Display for ProtocolError {
             ),
             ProtocolError::InvalidSessionId => write!(f, "Session id doesn't match."),
             ProtocolError::InvalidCommand => write!(f, "Unrecognized command."),
+            ProtocolError::MalformedPacket => write!(f, "Received a malformed packet."),
             ProtocolError::IOError(e) => write!(f, "An IO Error occurred. Reason: {:?}.", e),
             ProtocolError::PayloadTooLarge(size, max_size) => write!(
                 f,
@@ -51,6 +55,11 @@ impl Display for ProtocolError {
             ),
             ProtocolError::InvalidStreamId => write!(f, "The desired stream id is too large."),
             ProtocolError::InvalidConfiguration(s) => write!(f, "Invalid Configuration: {}", s),
+            ProtocolError::VersionMismatch(local, remote) => write!(
+                f,
+                "Protocol version mismatch: version {} can't talk to the remote's version {}.",
+                local, remote
+            ),
         }
     }
 }
@@ -63,6 +72,15 @@ impl From<io::Error> for ProtocolError {
     }
 }
 
+impl From<ProtocolError> for io::Error {
+    fn from(error: ProtocolError) -> io::Error {
+        match error {
+            ProtocolError::IOError(inner) => inner,
+            error => io::Error::other(error),
+        }
+    }
+}
+
 impl PartialEq for ProtocolError {
     fn eq(&self, other: &ProtocolError) -> bool {
         match (self, other) {
@@ -74,12 +92,16 @@ impl PartialEq for ProtocolError {
             (ProtocolError::IncompleteMessage, ProtocolError::IncompleteMessage) => true,
             (ProtocolError::EmptyRecvQueue, ProtocolError::EmptyRecvQueue) => true,
             (ProtocolError::BufferTooSmall, ProtocolError::BufferTooSmall) => true,
+            (ProtocolError::InvalidSessionId, ProtocolError::InvalidSessionId) => true,
+            (ProtocolError::InvalidCommand, ProtocolError::InvalidCommand) => true,
+            (ProtocolError::MalformedPacket, ProtocolError::MalformedPacket) => true,
             (ProtocolError::PayloadTooLarge(_, _), ProtocolError::PayloadTooLarge(_, _)) => true,
             (ProtocolError::InvalidStreamId, ProtocolError::InvalidStreamId) => true,
             (ProtocolError::InvalidConfiguration(_), ProtocolError::InvalidConfiguration(_)) => {
                 true
             }
             (ProtocolError::IOError(_), ProtocolError::IOError(_)) => true,
+            (ProtocolError::VersionMismatch(_, _), ProtocolError::VersionMismatch(_, _)) => true,
             (_, _) => false,
         }
     }
@@ -0,0 +1,91 @@
+use crate::connection::ConnectionState;
+
+/// Something which happened inside a `ReliableConnection`. Together with the timestamp passed to
+/// `EventListener::on_event` these allow building a timeline of a session.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    /// A segment was transmitted for the first time.
+    SegmentSent {
+        sequence_num: u32,
+        len: usize,
+    },
+    /// A segment was transmitted again because its retransmission timeout expired.
+    SegmentRetransmitted {
+        sequence_num: u32,
+        transmissions: u32,
+    },
+    /// A segment was transmitted again because later segments were acked before it.
+    SegmentFastRetransmitted {
+        sequence_num: u32,
+        transmissions: u32,
+    },
+    /// A data segment within the receive window arrived.
+    SegmentReceived {
+        sequence_num: u32,
+        len: usize,
+    },
+    /// The remote acknowledged a segment.
+    SegmentAcked {
+        sequence_num: u32,
+    },
+    /// The remote window was closed so we asked for its size.
+    WindowProbeSent,
+    /// The remote asked for the size of our window.
+    WindowProbeReceived,
+    /// We told the remote the size of our window.
+    WindowSizeSent {
+        window_size: u16,
+    },
+    CongestionWindowChanged {
+        from: usize,
+        to: usize,
+    },
+    StateChanged {
+        from: ConnectionState,
+        to: ConnectionState,
+    },
+}
+
+/// Receives the events of a connection, see `ReliableConnection::set_event_listener`. Implemented
+/// for closures taking the timestamp (the time passed to the last `update`) and the event.
+pub trait EventListener: Send {
+    fn on_event(&mut self, timestamp: u32, event: &Event);
+}
+
+impl<F> EventListener for F
+where
+    F: FnMut(u32, &Event) + Send,
+{
+    fn on_event(&mut self, timestamp: u32, event: &Event) {
+        self(timestamp, event)
+    }
+}
+
+// Hands the events of a connection to its listener and, with the `tracing` feature, to `tracing`
+// as trace level events.
+pub(crate) struct Events {
+    #[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+    session_id: u32,
+    listener: Option<Box<dyn EventListener>>,
+}
+
+impl Events {
+    pub(crate) fn new(session_id: u32) -> Self {
+        Self {
+            session_id,
+            listener: None,
+        }
+    }
+
+    pub(crate) fn set_listener(&mut self, listener: Option<Box<dyn EventListener>>) {
+        self.listener = listener;
+    }
+
+    pub(crate) fn emit(&mut self, timestamp: u32, event: Event) {
+        #[cfg(feature = "tracing")]
+        tracing::trace!(session_id = self.session_id, timestamp, event = ?event);
+        if let Some(listener) = self.listener.as_mut() {
+            listener.on_event(timestamp, &event);
+        }
+    }
+}
@@ -0,0 +1,320 @@
+use crate::{PACKET_FEC_DATA, PACKET_FEC_PARITY};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::{collections::VecDeque, io::Cursor};
+
+// kind: 1 byte, group: 2 bytes, index: 1 byte
+const DATA_HEADER_SIZE: usize = 4;
+// kind: 1 byte, group: 2 bytes, count: 1 byte, xor of the packet lengths: 2 bytes
+const PARITY_HEADER_SIZE: usize = 6;
+/// Number of bytes added to the packets protected by FEC. Parity packets are the longest, their
+/// header comes on top of the longest packet of their group.
+pub(crate) const FEC_OVERHEAD: usize = PARITY_HEADER_SIZE;
+// Number of groups the decoder keeps around waiting for their missing packets
+const MAX_PENDING_GROUPS: usize = 64;
+
+/// Returns the number of packets protected by each parity packet for a redundancy ratio. A ratio
+/// of 0.25 sends one parity packet for every four packets, 0 disables FEC.
+pub(crate) fn group_size(redundancy: f32) -> usize {
+    if redundancy <= 0.0 {
+        return 0;
+    }
+    (1.0 / redundancy).round().clamp(1.0, f32::from(u8::MAX)) as usize
+}
+
+/// Protects outgoing packets with XOR parity.
+///
+/// Packets are collected into groups and a parity packet holding the XOR of every packet in the
+/// group is sent once the group is full (or when `finish` is called). The receiver can rebuild any
+/// single packet lost from a group. Packets with different redundancy ratios are grouped
+/// separately.
+pub(crate) struct FecEncoder {
+    next_group_id: u16,
+    groups: Vec<EncoderGroup>,
+}
+
+struct EncoderGroup {
+    id: u16,
+    size: usize,
+    count: u8,
+    lengths: u16,
+    parity: BytesMut,
+}
+
+impl FecEncoder {
+    pub(crate) fn new() -> Self {
+        Self {
+            next_group_id: 0,
+            groups: Vec::new(),
+        }
+    }
+
+    /// Wraps `packet` for the group matching `redundancy` and pushes it, followed by the group's
+    /// parity packet once the group is full, onto `out`.
+    pub(crate) fn encode(&mut self, packet: Bytes, redundancy: f32, out: &mut VecDeque<Bytes>) {
+        let size = group_size(redundancy);
+        if size == 0 {
+            out.push_back(packet);
+            return;
+        }
+
+        let index = match self.groups.iter().position(|group| group.size == size) {
+            Some(index) => index,
+            None => {
+                let id = self.next_group_id;
+                self.next_group_id = self.next_group_id.wrapping_add(1);
+                self.groups.push(EncoderGroup {
+                    id,
+                    size,
+                    count: 0,
+                    lengths: 0,
+                    parity: BytesMut::new(),
+                });
+                self.groups.len() - 1
+            }
+        };
+
+        let group = &mut self.groups[index];
+        let mut wrapped = BytesMut::with_capacity(DATA_HEADER_SIZE + packet.len());
+        wrapped.put_u8(PACKET_FEC_DATA);
+        wrapped.put_u16_be(group.id);
+        wrapped.put_u8(group.count);
+        wrapped.put_slice(&packet);
+        out.push_back(wrapped.freeze());
+
+        group.count += 1;
+        group.lengths ^= packet.len() as u16;
+        xor_into(&mut group.parity, &packet);
+
+        if usize::from(group.count) == size {
+            let group = self.groups.swap_remove(index);
+            out.push_back(group.into_parity());
+        }
+    }
+
+    /// Sends the parity packets of every group which isn't full yet, so that no packet waits on
+    /// traffic which may never come.
+    pub(crate) fn finish(&mut self, out: &mut VecDeque<Bytes>) {
+        for group in self.groups.drain(..) {
+            out.push_back(group.into_parity());
+        }
+    }
+}
+
+impl EncoderGroup {
+    fn into_parity(self) -> Bytes {
+        let mut packet = BytesMut::with_capacity(PARITY_HEADER_SIZE + self.parity.len());
+        packet.put_u8(PACKET_FEC_PARITY);
+        packet.put_u16_be(self.id);
+        packet.put_u8(self.count);
+        packet.put_u16_be(self.lengths);
+        packet.put_slice(&self.parity);
+        packet.freeze()
+    }
+}
+
+/// Unwraps packets protected by `FecEncoder` and rebuilds the ones lost in transit.
+pub(crate) struct FecDecoder {
+    groups: VecDeque<DecoderGroup>,
+}
+
+struct DecoderGroup {
+    id: u16,
+    received: Vec<(u8, Bytes)>,
+    parity: Option<(u8, u16, Bytes)>,
+    recovered: Option<u8>,
+}
+
+/// The outcome of feeding a packet to `FecDecoder::decode`.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Decoded {
+    /// The packet which was wrapped, unless it was a parity packet or a duplicate of a packet
+    /// which was already rebuilt.
+    pub(crate) packet: Option<Bytes>,
+    /// A lost packet of the same group which could be rebuilt thanks to this one.
+    pub(crate) recovered: Option<Bytes>,
+}
+
+impl FecDecoder {
+    pub(crate) fn new() -> Self {
+        Self {
+            groups: VecDeque::new(),
+        }
+    }
+
+    /// Decodes a packet whose first byte is `PACKET_FEC_DATA` or `PACKET_FEC_PARITY`. Returns
+    /// `None` if the packet is malformed.
+    pub(crate) fn decode(&mut self, packet: Bytes) -> Option<Decoded> {
+        let mut cursor = Cursor::new(&packet[..]);
+        if cursor.remaining() < DATA_HEADER_SIZE {
+            return None;
+        }
+        let kind = cursor.get_u8();
+        let id = cursor.get_u16_be();
+        let mut decoded = Decoded::default();
+
+        let group = self.group(id);
+        if kind == PACKET_FEC_DATA {
+            let index = cursor.get_u8();
+            if group.recovered == Some(index) {
+                return Some(decoded);
+            }
+            let inner = packet.slice_from(DATA_HEADER_SIZE);
+            if group.received.iter().all(|(i, _)| *i != index) {
+                group.received.push((index, inner.clone()));
+            }
+            decoded.packet = Some(inner);
+        } else {
+            if cursor.remaining() < PARITY_HEADER_SIZE - 3 {
+                return None;
+            }
+            let count = cursor.get_u8();
+            let lengths = cursor.get_u16_be();
+            group.parity = Some((count, lengths, packet.slice_from(PARITY_HEADER_SIZE)));
+        }
+
+        decoded.recovered = group.recover();
+        Some(decoded)
+    }
+
+    fn group(&mut self, id: u16) -> &mut DecoderGroup {
+        let index = match self.groups.iter().position(|group| group.id == id) {
+            Some(index) => index,
+            None => {
+                if self.groups.len() == MAX_PENDING_GROUPS {
+                    self.groups.pop_front();
+                }
+                self.groups.push_back(DecoderGroup {
+                    id,
+                    received: Vec::new(),
+                    parity: None,
+                    recovered: None,
+                });
+                self.groups.len() - 1
+            }
+        };
+        &mut self.groups[index]
+    }
+}
+
+impl DecoderGroup {
+    // Rebuilds the missing packet once the parity and all but one of the packets arrived.
+    fn recover(&mut self) -> Option<Bytes> {
+        let (count, lengths, parity) = match &self.parity {
+            Some(parity) => parity,
+            None => return None,
+        };
+        if self.recovered.is_some() || self.received.len() + 1 != usize::from(*count) {
+            return None;
+        }
+
+        let missing = (0..*count).find(|index| self.received.iter().all(|(i, _)| i != index))?;
+        let mut length = *lengths;
+        let mut data = BytesMut::from(&parity[..]);
+        for (_, packet) in self.received.iter() {
+            length ^= packet.len() as u16;
+            xor_into(&mut data, packet);
+        }
+        if usize::from(length) > data.len() {
+            return None;
+        }
+        data.truncate(usize::from(length));
+        self.recovered = Some(missing);
+        Some(data.freeze())
+    }
+}
+
+// XORs `data` into `parity`, growing it with zeroes if `data` is longer.
+fn xor_into(parity: &mut BytesMut, data: &[u8]) {
+    if parity.len() < data.len() {
+        parity.resize(data.len(), 0);
+    }
+    for (parity, byte) in parity.iter_mut().zip(data) {
+        *parity ^= byte;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{group_size, FecDecoder, FecEncoder};
+    use bytes::Bytes;
+    use std::collections::VecDeque;
+
+    fn packets() -> Vec<Bytes> {
+        vec![
+            Bytes::from_static(b"first packet"),
+            Bytes::from_static(b"second"),
+            Bytes::from_static(b"the third packet is longest"),
+        ]
+    }
+
+    #[test]
+    fn test_group_size() {
+        assert_eq!(group_size(0.0), 0);
+        assert_eq!(group_size(0.25), 4);
+        assert_eq!(group_size(0.3), 3);
+        assert_eq!(group_size(2.0), 1);
+        assert_eq!(group_size(0.0001), 255);
+    }
+
+    #[test]
+    fn test_parity_sent_when_group_is_full() {
+        let mut encoder = FecEncoder::new();
+        let mut out = VecDeque::new();
+        for packet in packets() {
+            encoder.encode(packet, 1.0 / 3.0, &mut out);
+        }
+        assert_eq!(out.len(), 4);
+        encoder.finish(&mut out);
+        assert_eq!(out.len(), 4);
+    }
+
+    #[test]
+    fn test_unprotected_packets_are_not_wrapped() {
+        let mut encoder = FecEncoder::new();
+        let mut out = VecDeque::new();
+        encoder.encode(Bytes::from_static(b"plain"), 0.0, &mut out);
+        encoder.finish(&mut out);
+        assert_eq!(out, vec![Bytes::from_static(b"plain")]);
+    }
+
+    #[test]
+    fn test_recovers_any_single_lost_packet() {
+        for lost in 0..3 {
+            let mut encoder = FecEncoder::new();
+            let mut out = VecDeque::new();
+            for packet in packets() {
+                encoder.encode(packet, 0.25, &mut out);
+            }
+            encoder.finish(&mut out);
+            assert_eq!(out.len(), 4);
+            out.remove(lost);
+
+            let mut decoder = FecDecoder::new();
+            let mut recovered = None;
+            for packet in out {
+                let decoded = decoder.decode(packet).unwrap();
+                if decoded.recovered.is_some() {
+                    recovered = decoded.recovered;
+                }
+            }
+            assert_eq!(recovered.unwrap(), packets()[lost]);
+        }
+    }
+
+    #[test]
+    fn test_late_copy_of_recovered_packet_is_dropped() {
+        let mut encoder = FecEncoder::new();
+        let mut out = VecDeque::new();
+        for packet in packets().into_iter().take(2) {
+            encoder.encode(packet, 0.5, &mut out);
+        }
+        let late = out.pop_front().unwrap();
+
+        let mut decoder = FecDecoder::new();
+        let decoded = decoder.decode(out.pop_front().unwrap()).unwrap();
+        assert!(decoded.recovered.is_none());
+        let decoded = decoder.decode(out.pop_front().unwrap()).unwrap();
+        assert_eq!(decoded.recovered.unwrap(), packets()[0]);
+        assert_eq!(decoder.decode(late).unwrap().packet, None);
+    }
+}
@@ -5,6 +5,30 @@
 pub enum DeliveryGuarantee {
     Unreliable,
     Reliable,
+    /// Unreliable, but every packet also carries the newest unacknowledged messages sent before
+    /// it so that a single lost packet doesn't lose anything.
+    Redundant,
+}
+
+impl DeliveryGuarantee {
+    /// Returns the id used to represent this guarantee on the wire.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            DeliveryGuarantee::Unreliable => 0,
+            DeliveryGuarantee::Reliable => 1,
+            DeliveryGuarantee::Redundant => 2,
+        }
+    }
+
+    /// Returns the guarantee represented by `id` on the wire.
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(DeliveryGuarantee::Unreliable),
+            1 => Some(DeliveryGuarantee::Reliable),
+            2 => Some(DeliveryGuarantee::Redundant),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -13,3 +37,24 @@ pub enum OrderingGuarantee {
     Ordered,
     Sequenced,
 }
+
+impl OrderingGuarantee {
+    /// Returns the id used to represent this guarantee on the wire.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            OrderingGuarantee::None => 0,
+            OrderingGuarantee::Ordered => 1,
+            OrderingGuarantee::Sequenced => 2,
+        }
+    }
+
+    /// Returns the guarantee represented by `id` on the wire.
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(OrderingGuarantee::None),
+            1 => Some(OrderingGuarantee::Ordered),
+            2 => Some(OrderingGuarantee::Sequenced),
+            _ => None,
+        }
+    }
+}
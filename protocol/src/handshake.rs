@@ -0,0 +1,328 @@
+use crate::{
+    errors::{ProtocolError, ProtocolResult},
+    PACKET_HELLO, PROTOCOL_VERSION,
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::{
+    fmt::{self, Display, Formatter},
+    io::Cursor,
+    ops::BitOr,
+};
+
+// kind: 1 byte, version: 2 bytes, features: 4 bytes, flags: 1 byte
+const HELLO_SIZE: usize = 8;
+// flag: the sender has received the hello of the receiver
+const HELLO_RECEIVED: u8 = 0b01;
+// flag: the sender wants to know that its hello was received
+const HELLO_WANTS_REPLY: u8 = 0b10;
+
+/// Optional features of the protocol. Each endpoint offers the ones it's configured with during
+/// the handshake and only the features offered by both are used.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Features(u32);
+
+impl Features {
+    /// Selective acknowledgements. Reserved, not implemented yet.
+    pub const SACK: Features = Features(1);
+    /// Encrypted payloads. Reserved, not implemented yet.
+    pub const ENCRYPTION: Features = Features(1 << 1);
+    /// Compressed payloads. Reserved, not implemented yet.
+    pub const COMPRESSION: Features = Features(1 << 2);
+    /// Parity packets protecting the streams configured with a redundancy ratio.
+    pub const FEC: Features = Features(1 << 3);
+    /// The features implemented by this version of the protocol.
+    pub const SUPPORTED: Features = Features::FEC;
+
+    const NAMES: [(Features, &'static str); 4] = [
+        (Features::SACK, "sack"),
+        (Features::ENCRYPTION, "encryption"),
+        (Features::COMPRESSION, "compression"),
+        (Features::FEC, "fec"),
+    ];
+
+    pub const fn empty() -> Self {
+        Features(0)
+    }
+
+    /// Keeps unknown bits, which a newer remote may offer, so that they are printed as they were
+    /// received. They never end up in the agreed features.
+    pub const fn from_bits(bits: u32) -> Self {
+        Features(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersection(self, other: Features) -> Self {
+        Features(self.0 & other.0)
+    }
+
+    /// Looks a single feature up by its name, e.g. "fec".
+    pub fn from_name(name: &str) -> Option<Self> {
+        Features::NAMES
+            .iter()
+            .find(|(_, feature_name)| *feature_name == name)
+            .map(|(feature, _)| *feature)
+    }
+
+    /// Returns the names of the known features in the set.
+    pub fn names(self) -> impl Iterator<Item = &'static str> {
+        Features::NAMES
+            .iter()
+            .filter(move |(feature, _)| self.contains(*feature))
+            .map(|(_, name)| *name)
+    }
+}
+
+impl BitOr for Features {
+    type Output = Features;
+
+    fn bitor(self, other: Features) -> Features {
+        Features(self.0 | other.0)
+    }
+}
+
+/// Writes the feature names separated by `|`, e.g. "sack|fec", or "none".
+impl Display for Features {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut separator = "";
+        for name in self.names() {
+            write!(f, "{}{}", separator, name)?;
+            separator = "|";
+        }
+        let known = Features::NAMES
+            .iter()
+            .fold(0, |bits, (feature, _)| bits | feature.0);
+        let unknown = self.0 & !known;
+        if unknown != 0 {
+            write!(f, "{}{:#x}", separator, unknown)?;
+        } else if self.is_empty() {
+            write!(f, "none")?;
+        }
+        Ok(())
+    }
+}
+
+/// The packet an endpoint opens the connection with. Its layout must never change: the version
+/// has to be readable by every other version of the protocol.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) struct Hello {
+    pub version: u16,
+    pub features: Features,
+    /// The sender has received the hello of the receiver.
+    pub received: bool,
+    /// The sender wants a hello back to know that its own was received.
+    pub wants_reply: bool,
+}
+
+impl Hello {
+    pub fn encode(&self) -> Bytes {
+        let mut packet = BytesMut::with_capacity(HELLO_SIZE);
+        packet.put_u8(PACKET_HELLO);
+        packet.put_u16_be(self.version);
+        packet.put_u32_be(self.features.bits());
+        let mut flags = 0;
+        if self.received {
+            flags |= HELLO_RECEIVED;
+        }
+        if self.wants_reply {
+            flags |= HELLO_WANTS_REPLY;
+        }
+        packet.put_u8(flags);
+        packet.freeze()
+    }
+
+    /// Decodes a hello packet, kind byte included. Newer versions may append fields.
+    pub fn decode(packet: &[u8]) -> Option<Self> {
+        if packet.len() < HELLO_SIZE || packet[0] != PACKET_HELLO {
+            return None;
+        }
+        let mut cursor = Cursor::new(&packet[1..]);
+        let version = cursor.get_u16_be();
+        let features = Features::from_bits(cursor.get_u32_be());
+        let flags = cursor.get_u8();
+        Some(Hello {
+            version,
+            features,
+            received: flags & HELLO_RECEIVED != 0,
+            wants_reply: flags & HELLO_WANTS_REPLY != 0,
+        })
+    }
+}
+
+/// Exchanges hellos with the remote to agree on the protocol version and features.
+///
+/// A hello is sent on every update until the remote confirms it has received it, so that a lost
+/// hello doesn't stall the connection, and a hello asking for a reply is always answered. Once
+/// the versions turned out to differ hellos are only sent as replies, which ask for none.
+pub(crate) struct Handshake {
+    offered: Features,
+    /// Features offered by the remote, once its hello was received.
+    remote: Option<Features>,
+    /// The remote has received our hello.
+    confirmed: bool,
+    reply_pending: bool,
+    /// Version of the remote, if it isn't ours.
+    mismatched_version: Option<u16>,
+}
+
+impl Handshake {
+    pub fn new(offered: Features) -> Self {
+        Self {
+            offered,
+            remote: None,
+            confirmed: false,
+            reply_pending: false,
+            mismatched_version: None,
+        }
+    }
+
+    /// Returns the hello to send on this update, if one is due.
+    pub fn poll_hello(&mut self) -> Option<Bytes> {
+        let done = self.confirmed || self.mismatched_version.is_some();
+        if done && !self.reply_pending {
+            return None;
+        }
+        self.reply_pending = false;
+        let hello = Hello {
+            version: PROTOCOL_VERSION,
+            features: self.offered,
+            received: self.remote.is_some(),
+            wants_reply: !done,
+        };
+        Some(hello.encode())
+    }
+
+    /// Takes in the hello of the remote. Fails with `VersionMismatch` if the remote speaks
+    /// another version of the protocol, in which case it still gets a reply so that it can tell
+    /// too.
+    pub fn receive(&mut self, hello: Hello) -> ProtocolResult<()> {
+        self.reply_pending |= hello.wants_reply;
+        if hello.version != PROTOCOL_VERSION {
+            self.mismatched_version = Some(hello.version);
+            return Err(ProtocolError::VersionMismatch(
+                PROTOCOL_VERSION,
+                hello.version,
+            ));
+        }
+        self.remote = Some(hello.features);
+        self.confirmed |= hello.received;
+        Ok(())
+    }
+
+    /// Returns the version of the remote if it speaks another one than ours.
+    pub fn mismatched_version(&self) -> Option<u16> {
+        self.mismatched_version
+    }
+
+    /// Returns the features offered by both endpoints, once the hello of the remote was received.
+    pub fn features(&self) -> Option<Features> {
+        self.remote
+            .map(|features| features.intersection(self.offered))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Features, Handshake, Hello, HELLO_SIZE};
+    use crate::{ProtocolError, PROTOCOL_VERSION};
+
+    // Delivers the hello of `from`, if it has one to send, and reports whether it did.
+    fn exchange(from: &mut Handshake, to: &mut Handshake) -> bool {
+        match from.poll_hello() {
+            Some(packet) => {
+                to.receive(Hello::decode(&packet).unwrap()).unwrap();
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[test]
+    fn test_hello_layout() {
+        let hello = Hello {
+            version: 0x0102,
+            features: Features::SACK | Features::FEC,
+            received: true,
+            wants_reply: false,
+        };
+        let packet = hello.encode();
+        assert_eq!(&packet[..], &[5, 0x01, 0x02, 0, 0, 0, 0b1001, 0b01][..]);
+        assert_eq!(Hello::decode(&packet), Some(hello));
+        assert_eq!(Hello::decode(&packet[..HELLO_SIZE - 1]), None);
+    }
+
+    #[test]
+    fn test_features_agreed_after_three_hellos() {
+        let mut client = Handshake::new(Features::FEC);
+        let mut server = Handshake::new(Features::FEC | Features::SACK);
+        assert!(exchange(&mut client, &mut server));
+        assert_eq!(server.features(), Some(Features::FEC));
+        assert!(exchange(&mut server, &mut client));
+        assert_eq!(client.features(), Some(Features::FEC));
+        assert!(exchange(&mut client, &mut server));
+        assert!(!exchange(&mut server, &mut client));
+        assert!(!exchange(&mut client, &mut server));
+    }
+
+    #[test]
+    fn test_hello_resent_until_confirmed() {
+        let mut client = Handshake::new(Features::FEC);
+        let mut server = Handshake::new(Features::FEC);
+        // the first hello of the client is lost
+        assert!(client.poll_hello().is_some());
+        assert!(exchange(&mut server, &mut client));
+        // the reply of the client is lost too, the server keeps asking
+        assert!(client.poll_hello().is_some());
+        assert!(exchange(&mut server, &mut client));
+        assert!(exchange(&mut client, &mut server));
+        // the client still asks for a reply since it didn't get any
+        assert!(exchange(&mut server, &mut client));
+        assert!(!exchange(&mut client, &mut server));
+        assert_eq!(server.features(), Some(Features::FEC));
+        assert_eq!(client.features(), Some(Features::FEC));
+    }
+
+    #[test]
+    fn test_other_version_is_answered_and_rejected() {
+        let mut handshake = Handshake::new(Features::FEC);
+        let hello = Hello {
+            version: PROTOCOL_VERSION + 1,
+            features: Features::FEC,
+            received: true,
+            wants_reply: true,
+        };
+        assert_eq!(
+            handshake.receive(hello).unwrap_err(),
+            ProtocolError::VersionMismatch(0, 0)
+        );
+        assert_eq!(handshake.features(), None);
+        assert_eq!(handshake.mismatched_version(), Some(PROTOCOL_VERSION + 1));
+        let reply = Hello::decode(&handshake.poll_hello().unwrap()).unwrap();
+        assert_eq!(reply.version, PROTOCOL_VERSION);
+        assert!(!reply.received);
+        assert!(!reply.wants_reply);
+        assert_eq!(handshake.poll_hello(), None);
+    }
+
+    #[test]
+    fn test_features_names() {
+        assert_eq!((Features::SACK | Features::FEC).to_string(), "sack|fec");
+        assert_eq!(Features::empty().to_string(), "none");
+        assert_eq!(Features::from_bits(0x108).to_string(), "fec|0x100");
+        assert_eq!(
+            Features::from_name("compression"),
+            Some(Features::COMPRESSION)
+        );
+        assert_eq!(Features::from_name("zip"), None);
+    }
+}
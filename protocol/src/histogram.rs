@@ -0,0 +1,186 @@
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+// Values below this are counted exactly, larger ones in buckets a sixteenth of their magnitude wide
+const LINEAR_BUCKETS: usize = 32;
+const SUB_BUCKET_BITS: u32 = 4;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+const BUCKETS: usize = LINEAR_BUCKETS + (32 - SUB_BUCKET_BITS as usize - 1) * SUB_BUCKETS;
+// Gain of the jitter estimate, as in RFC 3550
+const JITTER_GAIN: f32 = 1.0 / 16.0;
+
+/// Histogram of the round trip times measured on a connection.
+///
+/// The buckets are HDR-style: exact below 32ms and within ~6% above, so percentiles stay accurate
+/// over the whole range without storing the samples. Jitter is the smoothed difference between
+/// consecutive samples.
+#[derive(Clone, Debug)]
+pub struct RttHistogram {
+    buckets: Vec<u32>,
+    samples: u64,
+    sum: u64,
+    min: u32,
+    max: u32,
+    last: Option<u32>,
+    jitter: f32,
+}
+
+/// Summary of a `RttHistogram`, all values in millisec.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct RttStatistics {
+    pub samples: u64,
+    pub min: u32,
+    pub avg: f32,
+    pub p50: u32,
+    pub p95: u32,
+    pub p99: u32,
+    pub max: u32,
+    pub jitter: f32,
+}
+
+impl RttHistogram {
+    pub fn new() -> Self {
+        Self {
+            buckets: vec![0; BUCKETS],
+            samples: 0,
+            sum: 0,
+            min: u32::MAX,
+            max: 0,
+            last: None,
+            jitter: 0.0,
+        }
+    }
+
+    /// Adds a round trip time sample in millisec.
+    pub fn record(&mut self, rtt: u32) {
+        self.buckets[bucket_index(rtt)] += 1;
+        self.samples += 1;
+        self.sum += u64::from(rtt);
+        self.min = self.min.min(rtt);
+        self.max = self.max.max(rtt);
+        if let Some(last) = self.last {
+            let difference = last.abs_diff(rtt) as f32;
+            self.jitter += (difference - self.jitter) * JITTER_GAIN;
+        }
+        self.last = Some(rtt);
+    }
+
+    /// Returns the number of samples recorded since the last reset.
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    /// Returns the round trip time below which `percentile` (0-100) percent of the samples fall,
+    /// or `None` if nothing was recorded.
+    pub fn percentile(&self, percentile: f32) -> Option<u32> {
+        if self.samples == 0 {
+            return None;
+        }
+        let rank = ((percentile.clamp(0.0, 100.0) / 100.0) * self.samples as f32).ceil() as u64;
+        let rank = rank.max(1);
+        let mut seen = 0;
+        for (index, count) in self.buckets.iter().enumerate() {
+            seen += u64::from(*count);
+            if seen >= rank {
+                return Some(bucket_upper_bound(index).clamp(self.min, self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    /// Returns the summary of the samples, or `None` if nothing was recorded.
+    pub fn statistics(&self) -> Option<RttStatistics> {
+        if self.samples == 0 {
+            return None;
+        }
+        Some(RttStatistics {
+            samples: self.samples,
+            min: self.min,
+            avg: (self.sum as f64 / self.samples as f64) as f32,
+            p50: self.percentile(50.0)?,
+            p95: self.percentile(95.0)?,
+            p99: self.percentile(99.0)?,
+            max: self.max,
+            jitter: self.jitter,
+        })
+    }
+
+    /// Forgets every sample, e.g. at the start of a match.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+}
+
+impl Default for RttHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn bucket_index(value: u32) -> usize {
+    let value = value as usize;
+    if value < LINEAR_BUCKETS {
+        return value;
+    }
+    let shift = (usize::BITS - value.leading_zeros()) - SUB_BUCKET_BITS - 1;
+    let sub_bucket = (value >> shift) - SUB_BUCKETS;
+    LINEAR_BUCKETS + (shift as usize - 1) * SUB_BUCKETS + sub_bucket
+}
+
+fn bucket_upper_bound(index: usize) -> u32 {
+    if index < LINEAR_BUCKETS {
+        return index as u32;
+    }
+    let shift = (index - LINEAR_BUCKETS) / SUB_BUCKETS + 1;
+    let sub_bucket = ((index - LINEAR_BUCKETS) % SUB_BUCKETS + SUB_BUCKETS) as u64;
+    (((sub_bucket + 1) << shift) - 1).min(u64::from(u32::MAX)) as u32
+}
+
+#[cfg(test)]
+mod test {
+    use super::{bucket_index, bucket_upper_bound, RttHistogram, BUCKETS};
+
+    #[test]
+    fn test_buckets_cover_whole_range() {
+        assert_eq!(bucket_index(31), 31);
+        assert_eq!(bucket_index(32), 32);
+        assert_eq!(bucket_index(u32::MAX), BUCKETS - 1);
+        assert_eq!(bucket_upper_bound(BUCKETS - 1), u32::MAX);
+        for value in [0, 31, 32, 33, 100, 1_000, 60_000, 1 << 20] {
+            let index = bucket_index(value);
+            assert!(bucket_upper_bound(index) >= value);
+            assert!(index == 0 || bucket_upper_bound(index - 1) < value);
+        }
+    }
+
+    #[test]
+    fn test_statistics() {
+        let mut histogram = RttHistogram::new();
+        assert_eq!(histogram.statistics(), None);
+        for rtt in 1..=100 {
+            histogram.record(rtt);
+        }
+        let statistics = histogram.statistics().unwrap();
+        assert_eq!(statistics.samples, 100);
+        assert_eq!(statistics.min, 1);
+        assert_eq!(statistics.max, 100);
+        assert!((statistics.avg - 50.5).abs() < 0.001);
+        assert!((48..=53).contains(&statistics.p50));
+        assert!((95..=100).contains(&statistics.p95));
+        assert!((99..=100).contains(&statistics.p99));
+        assert!(statistics.jitter > 0.0 && statistics.jitter <= 1.0);
+    }
+
+    #[test]
+    fn test_jitter_follows_alternating_samples() {
+        let mut histogram = RttHistogram::new();
+        for i in 0..200 {
+            histogram.record(if i % 2 == 0 { 40 } else { 60 });
+        }
+        assert!((histogram.statistics().unwrap().jitter - 20.0).abs() < 0.01);
+        histogram.reset();
+        assert_eq!(histogram.samples(), 0);
+        assert_eq!(histogram.percentile(50.0), None);
+    }
+}
@@ -1,20 +1,56 @@
+mod capture;
 mod config;
 mod connection;
 mod datagram;
+mod dump;
 mod endpoint;
 mod errors;
+mod events;
+mod fec;
 mod guarantees;
+mod handshake;
+mod histogram;
+mod message;
 mod metrics;
+mod pcap;
+mod pool;
+mod prometheus;
+mod replay;
+mod scheduler;
 mod segment;
 mod sequence_buffer;
 mod streams;
+mod transfer;
+mod window;
 
 pub use crate::{
-    datagram::Datagram,
+    capture::{
+        CaptureKind, CaptureLayer, CaptureReader, CaptureRecord, CaptureWriter, PacketRecorder,
+    },
+    config::{Config, Preset, WireFormat},
+    connection::{ConnectionState, ReliableConnection},
+    datagram::{Datagram, ReceivedDatagram},
+    dump::{command_name, dump_packet},
     endpoint::Endpoint,
     errors::{ProtocolError, ProtocolResult},
+    events::{Event, EventListener},
+    handshake::Features,
+    histogram::{RttHistogram, RttStatistics},
+    metrics::{DataPoint, Metrics, MetricsSnapshot},
+    pcap::{PcapFormat, PcapWriter},
+    prometheus::write_prometheus,
+    replay::Replay,
+    transfer::{ProgressCallback, TransferId, TransferProgress},
 };
 
+/// Internal types exposed for the benchmarks. Not part of the public API.
+#[doc(hidden)]
+pub mod internals {
+    pub use crate::{segment::Segment, sequence_buffer::SequenceBuffer};
+}
+
+// version of the wire protocol, bumped by every change peers of the previous version can't parse
+const PROTOCOL_VERSION: u16 = 1;
 // no delay min rto
 const RTO_NDL: u32 = 30;
 // normal min rto
@@ -29,20 +65,59 @@ const CMD_ACK: u8 = 82;
 const CMD_WASK: u8 = 83;
 // cmd: window size (tell)
 const CMD_WINS: u8 = 84;
+// cmd: keepalive (ask)
+const CMD_PING: u8 = 85;
+// cmd: keepalive (reply)
+const CMD_PONG: u8 = 86;
+// cmd: skip expired data
+const CMD_SKIP: u8 = 87;
+// cmd: push a chunk of a large message
+const CMD_CHUNK: u8 = 88;
 // need to send KCP_CMD_WASK
 const ASK_SEND: u32 = 0b01;
 // need to send KCP_CMD_WINS
 const ASK_TELL: u32 = 0b10;
 const SEND_WINDOW_SIZE: usize = 32;
 const RECV_WINDOW_SIZE: usize = 32;
+// largest receive window the u16 window field of a segment can advertise
+const MAX_WINDOW_SIZE: usize = u16::MAX as usize;
+// the fragment id of a segment is a u8 counting down to 0
+const MAX_FRAGMENTS: usize = u8::MAX as usize + 1;
 const DEFAULT_MTU: usize = 1_400;
-const ACK_FAST: u32 = 3;
-const INTERVAL: u64 = 100;
+const INTERVAL: u32 = 100;
 const PROTOCOL_OVERHEAD: usize = 24;
+// packet: unreliable messages
+const PACKET_UNRELIABLE: u8 = 0;
+// packet: reliable connection segments
+const PACKET_RELIABLE: u8 = 1;
+// packet: packet protected by forward error correction
+const PACKET_FEC_DATA: u8 = 2;
+// packet: parity of a group of FEC protected packets
+const PACKET_FEC_PARITY: u8 = 3;
+// packet: newest redundant message received on each stream
+const PACKET_REDUNDANT_ACK: u8 = 4;
+// packet: protocol version and features offered by an endpoint
+const PACKET_HELLO: u8 = 5;
+// stream ids are sent in a byte
+const MAX_STREAM_ID: usize = 254;
+// stream of the datagrams sent without ordering
+const UNORDERED_STREAM_ID: usize = 0xFF;
+// stream id + guarantees + sequence num + len
+const MESSAGE_OVERHEAD: usize = 6;
+// number of messages resent on redundant streams
+const REDUNDANT_HISTORY: usize = 4;
 const DEADLINK: u32 = 20;
+// weight of the newest sample in the smoothed bandwidth
+const BANDWIDTH_SMOOTHING_FACTOR: f32 = 0.1;
 const THRESH_INIT: u32 = 2;
 const THRESH_MIN: u32 = 2;
 // 7 secs to probe window size
 const PROBE_INIT: u32 = 7_000;
 // up to 120 secs to probe window
 const PROBE_LIMIT: u32 = 120_000;
+// largest message accepted from the remote's send_large, 64 MiB
+const MAX_TRANSFER_SIZE: usize = 64 * 1024 * 1024;
+// send a keepalive after 1 sec without sending anything
+const KEEPALIVE_INTERVAL: u32 = 1_000;
+// time out after 10 secs without receiving anything
+const IDLE_TIMEOUT: u32 = 10_000;
@@ -0,0 +1,125 @@
+use crate::{
+    errors::{ProtocolError, ProtocolResult},
+    guarantees::{DeliveryGuarantee, OrderingGuarantee},
+    MESSAGE_OVERHEAD,
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::io::Cursor;
+
+/// A datagram that has been accepted by the `Endpoint` and is waiting to be scheduled onto the
+/// wire.
+pub struct Message {
+    pub(crate) stream_id: u8,
+    pub(crate) delivery: DeliveryGuarantee,
+    pub(crate) ordering: OrderingGuarantee,
+    pub(crate) sequence_num: u16,
+    pub(crate) payload: Bytes,
+}
+
+impl Message {
+    /// Number of bytes this message takes up on the wire.
+    #[inline]
+    pub fn encoded_len(&self) -> usize {
+        MESSAGE_OVERHEAD + self.payload.len()
+    }
+
+    /// Appends the message to `buf`, or fails with `PayloadTooLarge` if its length doesn't fit in
+    /// the two bytes of the header.
+    pub fn encode(&self, buf: &mut BytesMut) -> ProtocolResult<()> {
+        let max_size = usize::from(u16::MAX);
+        if self.payload.len() > max_size {
+            return Err(ProtocolError::PayloadTooLarge(self.payload.len(), max_size));
+        }
+        buf.put_u8(self.stream_id);
+        buf.put_u8(self.delivery.id() << 4 | self.ordering.id());
+        buf.put_u16_be(self.sequence_num);
+        buf.put_u16_be(self.payload.len() as u16);
+        buf.put_slice(&self.payload);
+        Ok(())
+    }
+
+    /// Decodes the message at the start of `buf` and advances past it. Returns `None` if the
+    /// message is truncated or malformed.
+    pub fn decode(buf: &mut Bytes) -> Option<Message> {
+        if buf.len() < MESSAGE_OVERHEAD {
+            return None;
+        }
+        let mut cursor = Cursor::new(&buf[..MESSAGE_OVERHEAD]);
+        let stream_id = cursor.get_u8();
+        let guarantees = cursor.get_u8();
+        let sequence_num = cursor.get_u16_be();
+        let len = cursor.get_u16_be() as usize;
+        if buf.len() < MESSAGE_OVERHEAD + len {
+            return None;
+        }
+
+        let delivery = DeliveryGuarantee::from_id(guarantees >> 4)?;
+        let ordering = OrderingGuarantee::from_id(guarantees & 0x0F)?;
+        buf.advance(MESSAGE_OVERHEAD);
+        Some(Message {
+            stream_id,
+            delivery,
+            ordering,
+            sequence_num,
+            payload: buf.split_to(len),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Message;
+    use crate::{
+        errors::ProtocolError,
+        guarantees::{DeliveryGuarantee, OrderingGuarantee},
+    };
+    use bytes::{Bytes, BytesMut};
+
+    #[test]
+    fn test_encode_decode() {
+        let message = Message {
+            stream_id: 3,
+            delivery: DeliveryGuarantee::Reliable,
+            ordering: OrderingGuarantee::Sequenced,
+            sequence_num: 513,
+            payload: Bytes::from_static(b"hello"),
+        };
+        let mut buf = BytesMut::new();
+        message.encode(&mut buf).unwrap();
+        message.encode(&mut buf).unwrap();
+
+        let mut buf = buf.freeze();
+        for _ in 0..2 {
+            let decoded = Message::decode(&mut buf).unwrap();
+            assert_eq!(decoded.stream_id, 3);
+            assert_eq!(decoded.delivery, DeliveryGuarantee::Reliable);
+            assert_eq!(decoded.ordering, OrderingGuarantee::Sequenced);
+            assert_eq!(decoded.sequence_num, 513);
+            assert_eq!(decoded.payload, Bytes::from_static(b"hello"));
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_encode_payload_too_large() {
+        let message = Message {
+            stream_id: 0,
+            delivery: DeliveryGuarantee::Unreliable,
+            ordering: OrderingGuarantee::None,
+            sequence_num: 0,
+            payload: Bytes::from(vec![0; 70_000]),
+        };
+        let mut buf = BytesMut::with_capacity(message.encoded_len());
+        assert_eq!(
+            message.encode(&mut buf),
+            Err(ProtocolError::PayloadTooLarge(70_000, 65_535))
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_truncated() {
+        let mut buf = Bytes::from_static(&[0, 0x00, 0, 0, 0, 5, b'h']);
+        assert!(Message::decode(&mut buf).is_none());
+    }
+}
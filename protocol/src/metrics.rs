@@ -1,4 +1,12 @@
-use std::fmt;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+};
+
+// Number of recent segment transmissions the packet loss is calculated over
+const LOSS_WINDOW_SIZE: usize = 256;
 
 /// This is a direct port of the bandwidth calculations found in
 /// https://github.com/networkprotocol/reliable.io
@@ -15,13 +23,25 @@ macro_rules! calc_bandwidth {
 
 /// Stores various metrics information. e.g. number of datagrams/fragments sent, bandwidth
 /// calculations, etc
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Metrics {
     counters: [u64; DataPoint::Length as usize],
     packet_loss: f32,
     sent_bandwidth_kbps: f32,
     received_bandwidth_kbps: f32,
     acked_bandwidth_kbps: f32,
+    rtt: u32,
+    rtt_variance: u32,
+    rto: u32,
+    congestion_window: u32,
+
+    // Bytes sent, received and acked since the bandwidth was last calculated
+    tick_bytes_sent: usize,
+    tick_bytes_received: usize,
+    tick_bytes_acked: usize,
+    // Whether each of the most recent segment transmissions was a retransmission
+    transmissions: VecDeque<bool>,
+    retransmissions: usize,
 
     // Config values to tweak the calculated fields
     bandwidth_smoothing_factor: f32,
@@ -35,10 +55,49 @@ impl Metrics {
             sent_bandwidth_kbps: 0.0,
             received_bandwidth_kbps: 0.0,
             acked_bandwidth_kbps: 0.0,
+            rtt: 0,
+            rtt_variance: 0,
+            rto: 0,
+            congestion_window: 0,
+            tick_bytes_sent: 0,
+            tick_bytes_received: 0,
+            tick_bytes_acked: 0,
+            transmissions: VecDeque::with_capacity(LOSS_WINDOW_SIZE),
+            retransmissions: 0,
             bandwidth_smoothing_factor,
         }
     }
 
+    pub(crate) fn set_bandwidth_smoothing_factor(&mut self, bandwidth_smoothing_factor: f32) {
+        self.bandwidth_smoothing_factor = bandwidth_smoothing_factor;
+    }
+
+    // Returns the percentage (0-1) of the most recent segment transmissions which were
+    // retransmissions of lost segments.
+    pub fn packet_loss(&self) -> f32 {
+        self.packet_loss
+    }
+
+    // Returns the smoothed round trip time in millisec.
+    pub fn rtt(&self) -> u32 {
+        self.rtt
+    }
+
+    // Returns the smoothed deviation of the round trip time in millisec.
+    pub fn rtt_variance(&self) -> u32 {
+        self.rtt_variance
+    }
+
+    // Returns the current retransmission timeout in millisec.
+    pub fn rto(&self) -> u32 {
+        self.rto
+    }
+
+    // Returns the current congestion window in segments.
+    pub fn congestion_window(&self) -> u32 {
+        self.congestion_window
+    }
+
     // Returns the count of a particular data point.
     pub fn get_count(&self, data_point: DataPoint) -> u64 {
         self.counters[data_point as usize]
@@ -64,36 +123,136 @@ impl Metrics {
         self.counters[data_point as usize] += 1;
     }
 
-    // Calculate the sent bandwidth given the bytes sent and the time_delta_ms.
-    pub(crate) fn calculate_sent_bandwidth(&mut self, bytes_sent: usize, time_delta_ms: f64) {
+    // Records a packet of `bytes` being sent.
+    pub(crate) fn record_sent(&mut self, bytes: usize) {
+        self.increment(DataPoint::PacketsSent);
+        self.counters[DataPoint::BytesSent as usize] += bytes as u64;
+        self.tick_bytes_sent += bytes;
+    }
+
+    // Records a packet of `bytes` being received.
+    pub(crate) fn record_received(&mut self, bytes: usize) {
+        self.increment(DataPoint::PacketsReceived);
+        self.counters[DataPoint::BytesReceived as usize] += bytes as u64;
+        self.tick_bytes_received += bytes;
+    }
+
+    // Records a segment carrying `bytes` of data being acknowledged by the remote.
+    pub(crate) fn record_acked(&mut self, bytes: usize) {
+        self.increment(DataPoint::PacketsAcked);
+        self.counters[DataPoint::BytesAcked as usize] += bytes as u64;
+        self.tick_bytes_acked += bytes;
+    }
+
+    // Records a segment transmission and updates the packet loss over the last transmissions.
+    pub(crate) fn record_transmission(&mut self, retransmission: bool) {
+        self.increment(DataPoint::SegmentsSent);
+        if retransmission {
+            self.increment(DataPoint::SegmentsRetransmitted);
+            self.retransmissions += 1;
+        }
+        self.transmissions.push_back(retransmission);
+        if self.transmissions.len() > LOSS_WINDOW_SIZE
+            && self.transmissions.pop_front() == Some(true)
+        {
+            self.retransmissions -= 1;
+        }
+        self.packet_loss = self.retransmissions as f32 / self.transmissions.len() as f32;
+    }
+
+    pub(crate) fn set_rtt(&mut self, rtt: u32, rtt_variance: u32, rto: u32) {
+        self.rtt = rtt;
+        self.rtt_variance = rtt_variance;
+        self.rto = rto;
+    }
+
+    pub(crate) fn set_congestion_window(&mut self, congestion_window: u32) {
+        self.congestion_window = congestion_window;
+    }
+
+    // Calculates the bandwidths from the bytes recorded over the last `elapsed` millisec.
+    pub(crate) fn tick(&mut self, elapsed: u32) {
+        if elapsed == 0 {
+            return;
+        }
+        let time_delta_secs = f64::from(elapsed) / 1000.0;
+        self.calculate_sent_bandwidth(self.tick_bytes_sent, time_delta_secs);
+        self.calculate_receive_bandwidth(self.tick_bytes_received, time_delta_secs);
+        self.calculate_acked_bandwidth(self.tick_bytes_acked, time_delta_secs);
+        self.tick_bytes_sent = 0;
+        self.tick_bytes_received = 0;
+        self.tick_bytes_acked = 0;
+    }
+
+    /// Returns a copy of the current counters and rates.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            counters: DataPoint::ALL
+                .iter()
+                .map(|&data_point| (data_point.name(), self.get_count(data_point)))
+                .collect(),
+            packet_loss: self.packet_loss,
+            sent_bandwidth_kbps: self.sent_bandwidth_kbps,
+            received_bandwidth_kbps: self.received_bandwidth_kbps,
+            acked_bandwidth_kbps: self.acked_bandwidth_kbps,
+            rtt: self.rtt,
+            rtt_variance: self.rtt_variance,
+            rto: self.rto,
+            congestion_window: self.congestion_window,
+        }
+    }
+
+    // Takes over the values only a reliability layer knows about from its metrics.
+    pub(crate) fn sync_connection(&mut self, connection: &Metrics) {
+        for &data_point in [
+            DataPoint::PacketsAcked,
+            DataPoint::BytesAcked,
+            DataPoint::SegmentsRetransmitted,
+            DataPoint::SegmentsFastRetransmitted,
+            DataPoint::SegmentsSent,
+        ]
+        .iter()
+        {
+            self.counters[data_point as usize] = connection.get_count(data_point);
+        }
+        self.packet_loss = connection.packet_loss;
+        self.acked_bandwidth_kbps = connection.acked_bandwidth_kbps;
+        self.rtt = connection.rtt;
+        self.rtt_variance = connection.rtt_variance;
+        self.rto = connection.rto;
+        self.congestion_window = connection.congestion_window;
+    }
+
+    // Calculate the sent bandwidth given the bytes sent over time_delta_secs.
+    pub(crate) fn calculate_sent_bandwidth(&mut self, bytes_sent: usize, time_delta_secs: f64) {
         calc_bandwidth!(
             self.sent_bandwidth_kbps,
             bytes_sent,
-            time_delta_ms,
+            time_delta_secs,
             self.bandwidth_smoothing_factor
         );
     }
 
-    // Calculate the received bandwidth given the bytes received and the time_delta_ms.
+    // Calculate the received bandwidth given the bytes received over time_delta_secs.
     pub(crate) fn calculate_receive_bandwidth(
         &mut self,
         bytes_received: usize,
-        time_delta_ms: f64,
+        time_delta_secs: f64,
     ) {
         calc_bandwidth!(
             self.received_bandwidth_kbps,
             bytes_received,
-            time_delta_ms,
+            time_delta_secs,
             self.bandwidth_smoothing_factor
         );
     }
 
-    // Calculate the acked bandwidth given the bytes acked and the time_delta_ms.
-    pub(crate) fn calculate_acked_bandwidth(&mut self, bytes_acked: usize, time_delta_ms: f64) {
+    // Calculate the acked bandwidth given the bytes acked over time_delta_secs.
+    pub(crate) fn calculate_acked_bandwidth(&mut self, bytes_acked: usize, time_delta_secs: f64) {
         calc_bandwidth!(
             self.acked_bandwidth_kbps,
             bytes_acked,
-            time_delta_ms,
+            time_delta_secs,
             self.bandwidth_smoothing_factor
         );
     }
@@ -101,10 +260,51 @@ impl Metrics {
 
 impl fmt::Display for Metrics {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Do useful thing here")
+        for &data_point in DataPoint::ALL.iter() {
+            writeln!(
+                f,
+                "{}: {}",
+                data_point.name().replace('_', " "),
+                self.get_count(data_point)
+            )?;
+        }
+        writeln!(f, "packet loss: {:.2}%", self.packet_loss * 100.0)?;
+        writeln!(
+            f,
+            "rtt: {}ms (variance {}ms), rto: {}ms",
+            self.rtt, self.rtt_variance, self.rto
+        )?;
+        writeln!(f, "congestion window: {} segments", self.congestion_window)?;
+        write!(
+            f,
+            "bandwidth: sent {:.1} kbps, received {:.1} kbps, acked {:.1} kbps",
+            self.sent_bandwidth_kbps, self.received_bandwidth_kbps, self.acked_bandwidth_kbps
+        )
     }
 }
 
+/// A copy of the values in `Metrics` at one point in time, e.g. for a debug HUD or to export
+/// them. Serializable with the `serde` feature.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct MetricsSnapshot {
+    /// Every counter, keyed by `DataPoint::name`.
+    pub counters: BTreeMap<&'static str, u64>,
+    /// Percentage (0-1) of the recent segment transmissions which were retransmissions.
+    pub packet_loss: f32,
+    pub sent_bandwidth_kbps: f32,
+    pub received_bandwidth_kbps: f32,
+    pub acked_bandwidth_kbps: f32,
+    /// Smoothed round trip time in millisec.
+    pub rtt: u32,
+    /// Smoothed deviation of the round trip time in millisec.
+    pub rtt_variance: u32,
+    /// Retransmission timeout in millisec.
+    pub rto: u32,
+    /// Congestion window in segments.
+    pub congestion_window: u32,
+}
+
 #[derive(Copy, Clone, Debug, PartialOrd, PartialEq)]
 pub enum DataPoint {
     PacketsSent = 0,
@@ -116,7 +316,62 @@ pub enum DataPoint {
     FragmentsSent = 6,
     FragmentsReceived = 7,
     FragmentsInvalid = 8,
-    Length = 9,
+    MessagesDropped = 9,
+    PacketsRecovered = 10,
+    BytesSent = 11,
+    BytesReceived = 12,
+    BytesAcked = 13,
+    SegmentsRetransmitted = 14,
+    SegmentsFastRetransmitted = 15,
+    SegmentsSent = 16,
+    Length = 17,
+}
+
+impl DataPoint {
+    /// Every data point which is counted.
+    pub const ALL: [DataPoint; DataPoint::Length as usize] = [
+        DataPoint::PacketsSent,
+        DataPoint::PacketsReceived,
+        DataPoint::PacketsAcked,
+        DataPoint::PacketsStale,
+        DataPoint::PacketsInvalid,
+        DataPoint::PacketsTooLargeToSend,
+        DataPoint::FragmentsSent,
+        DataPoint::FragmentsReceived,
+        DataPoint::FragmentsInvalid,
+        DataPoint::MessagesDropped,
+        DataPoint::PacketsRecovered,
+        DataPoint::BytesSent,
+        DataPoint::BytesReceived,
+        DataPoint::BytesAcked,
+        DataPoint::SegmentsRetransmitted,
+        DataPoint::SegmentsFastRetransmitted,
+        DataPoint::SegmentsSent,
+    ];
+
+    /// Returns the snake case name of the data point used by the exporters.
+    pub fn name(self) -> &'static str {
+        match self {
+            DataPoint::PacketsSent => "packets_sent",
+            DataPoint::PacketsReceived => "packets_received",
+            DataPoint::PacketsAcked => "packets_acked",
+            DataPoint::PacketsStale => "packets_stale",
+            DataPoint::PacketsInvalid => "packets_invalid",
+            DataPoint::PacketsTooLargeToSend => "packets_too_large_to_send",
+            DataPoint::FragmentsSent => "fragments_sent",
+            DataPoint::FragmentsReceived => "fragments_received",
+            DataPoint::FragmentsInvalid => "fragments_invalid",
+            DataPoint::MessagesDropped => "messages_dropped",
+            DataPoint::PacketsRecovered => "packets_recovered",
+            DataPoint::BytesSent => "bytes_sent",
+            DataPoint::BytesReceived => "bytes_received",
+            DataPoint::BytesAcked => "bytes_acked",
+            DataPoint::SegmentsRetransmitted => "segments_retransmitted",
+            DataPoint::SegmentsFastRetransmitted => "segments_fast_retransmitted",
+            DataPoint::SegmentsSent => "segments_sent",
+            DataPoint::Length => "length",
+        }
+    }
 }
 
 #[cfg(test)]
@@ -171,4 +426,70 @@ mod test {
 
         assert_eq!(metrics.acked_bandwidth_kbps(), 8.0)
     }
+
+    #[test]
+    fn test_tick_calculates_bandwidth_from_recorded_bytes() {
+        let mut metrics = Metrics::new(1.0);
+        metrics.record_sent(500);
+        metrics.record_sent(500);
+        metrics.record_received(250);
+        metrics.tick(500);
+        assert_eq!(metrics.sent_bandwidth_kbps(), 16.0);
+        assert_eq!(metrics.received_bandwidth_kbps(), 4.0);
+        assert_eq!(metrics.get_count(DataPoint::PacketsSent), 2);
+        assert_eq!(metrics.get_count(DataPoint::BytesSent), 1000);
+
+        // Nothing recorded since the last tick
+        metrics.tick(500);
+        assert_eq!(metrics.sent_bandwidth_kbps(), 0.0);
+    }
+
+    #[test]
+    fn test_display_lists_counters_and_rates() {
+        let mut metrics = Metrics::new(0.1);
+        metrics.record_sent(100);
+        metrics.record_transmission(true);
+        metrics.set_rtt(40, 5, 200);
+        let display = metrics.to_string();
+        assert!(display.contains("packets sent: 1\n"));
+        assert!(display.contains("bytes sent: 100\n"));
+        assert!(display.contains("packet loss: 100.00%\n"));
+        assert!(display.contains("rtt: 40ms (variance 5ms), rto: 200ms\n"));
+        assert!(!display.ends_with('\n'));
+    }
+
+    #[test]
+    fn test_snapshot_names_every_counter() {
+        let mut metrics = Metrics::new(0.1);
+        metrics.increment(DataPoint::MessagesDropped);
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.counters.len(), DataPoint::Length as usize);
+        assert_eq!(snapshot.counters["messages_dropped"], 1);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_snapshot_serializes_to_json() {
+        let mut metrics = Metrics::new(0.1);
+        metrics.set_rtt(40, 5, 200);
+        let json = serde_json::to_value(metrics.snapshot()).unwrap();
+        assert_eq!(json["rtt"], 40);
+        assert_eq!(json["counters"]["packets_sent"], 0);
+    }
+
+    #[test]
+    fn test_packet_loss_over_sliding_window() {
+        let mut metrics = Metrics::new(0.1);
+        for i in 0..100 {
+            metrics.record_transmission(i % 4 == 0);
+        }
+        assert_eq!(metrics.packet_loss(), 0.25);
+
+        // Old retransmissions fall out of the window
+        for _ in 0..super::LOSS_WINDOW_SIZE {
+            metrics.record_transmission(false);
+        }
+        assert_eq!(metrics.packet_loss(), 0.0);
+        assert_eq!(metrics.get_count(DataPoint::SegmentsRetransmitted), 25);
+    }
 }
@@ -0,0 +1,321 @@
+use crate::capture::{CaptureKind, CaptureRecord, PacketRecorder};
+use bytes::{BufMut, BytesMut};
+use std::{
+    io::{self, Write},
+    net::SocketAddrV4,
+    time::Duration,
+};
+
+// Packets start with their IPv4 header, no link layer
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65_535;
+const IPV4_HEADER_SIZE: usize = 20;
+const UDP_HEADER_SIZE: usize = 8;
+const IP_PROTOCOL_UDP: u8 = 17;
+const TTL: u8 = 64;
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_HEADER_SIZE: usize = 24;
+// ts seconds, ts microseconds, captured length, original length
+const PCAP_RECORD_HEADER_SIZE: usize = 16;
+// interface id, ts high, ts low, captured length, original length
+const EPB_HEADER_SIZE: usize = 20;
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const PCAPNG_ENHANCED_PACKET: u32 = 0x0000_0006;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+
+/// The file format written by `PcapWriter`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PcapFormat {
+    /// The classic libpcap format.
+    Pcap,
+    /// The pcapng format.
+    PcapNg,
+}
+
+/// Writes packets as a pcap or pcapng file which Wireshark can open.
+///
+/// Every packet is wrapped in synthesized IPv4 and UDP headers between the local and remote
+/// addresses, in the direction it travelled. The UDP payload is the packet exactly as it was on
+/// the wire, so the packets of a `ReliableConnection` are a sequence of segments laid out as
+/// session id (u32), cmd (u8), frg (u8), wnd (u16), ts (u32), sn (u32), una (u32) and len (u32),
+/// followed by `len` bytes of data. The header fields are big endian, or little endian for a
+/// connection using `WireFormat::Kcp` (see `CaptureReader::config`).
+///
+/// Captured timestamps are millisec on the clock passed to `update`. They are placed after the
+/// Unix epoch unless `with_start_time` says when that clock started.
+pub struct PcapWriter<W: Write> {
+    writer: W,
+    format: PcapFormat,
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    start_time: Duration,
+    next_ip_id: u16,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Writes the file header to `writer`.
+    pub fn new(
+        mut writer: W,
+        format: PcapFormat,
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+    ) -> io::Result<Self> {
+        // pcapng blocks reserve their own room in `put_block`
+        let mut header = BytesMut::with_capacity(PCAP_HEADER_SIZE);
+        match format {
+            PcapFormat::Pcap => {
+                header.put_u32_be(PCAP_MAGIC);
+                header.put_u16_be(2);
+                header.put_u16_be(4);
+                // Timezone offset and timestamp accuracy
+                header.put_u32_be(0);
+                header.put_u32_be(0);
+                header.put_u32_be(SNAPLEN);
+                header.put_u32_be(LINKTYPE_RAW);
+            }
+            PcapFormat::PcapNg => {
+                let mut section = BytesMut::with_capacity(16);
+                section.put_u32_be(PCAPNG_BYTE_ORDER_MAGIC);
+                section.put_u16_be(1);
+                section.put_u16_be(0);
+                // Section length isn't known up front
+                section.put_i64_be(-1);
+                put_block(&mut header, PCAPNG_SECTION_HEADER, &section);
+
+                let mut interface = BytesMut::with_capacity(8);
+                interface.put_u16_be(LINKTYPE_RAW as u16);
+                interface.put_u16_be(0);
+                interface.put_u32_be(SNAPLEN);
+                put_block(&mut header, PCAPNG_INTERFACE_DESCRIPTION, &interface);
+            }
+        }
+        writer.write_all(&header)?;
+        Ok(Self {
+            writer,
+            format,
+            local,
+            remote,
+            start_time: Duration::from_secs(0),
+            next_ip_id: 0,
+        })
+    }
+
+    /// Sets the time since the Unix epoch at which the captured clock read 0.
+    pub fn with_start_time(mut self, start_time: Duration) -> Self {
+        self.start_time = start_time;
+        self
+    }
+
+    /// Writes a packet sent (`outbound`) or received at `timestamp`.
+    pub fn write_packet(
+        &mut self,
+        timestamp: u32,
+        outbound: bool,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let len = IPV4_HEADER_SIZE + UDP_HEADER_SIZE + payload.len();
+        if len > usize::from(u16::MAX) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "packet too large for a UDP datagram",
+            ));
+        }
+        let (source, destination) = if outbound {
+            (self.local, self.remote)
+        } else {
+            (self.remote, self.local)
+        };
+
+        let mut packet = BytesMut::with_capacity(len);
+        let mut ip_header = [0; IPV4_HEADER_SIZE];
+        ip_header[0] = 0x45;
+        ip_header[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        ip_header[4..6].copy_from_slice(&self.next_ip_id.to_be_bytes());
+        ip_header[8] = TTL;
+        ip_header[9] = IP_PROTOCOL_UDP;
+        ip_header[12..16].copy_from_slice(&source.ip().octets());
+        ip_header[16..20].copy_from_slice(&destination.ip().octets());
+        let checksum = ipv4_checksum(&ip_header);
+        ip_header[10..12].copy_from_slice(&checksum.to_be_bytes());
+        packet.put_slice(&ip_header);
+        self.next_ip_id = self.next_ip_id.wrapping_add(1);
+
+        packet.put_u16_be(source.port());
+        packet.put_u16_be(destination.port());
+        packet.put_u16_be((UDP_HEADER_SIZE + payload.len()) as u16);
+        // The checksum is optional over IPv4
+        packet.put_u16_be(0);
+        packet.put_slice(payload);
+
+        let time = self.start_time + Duration::from_millis(u64::from(timestamp));
+        let mut record = BytesMut::with_capacity(PCAP_RECORD_HEADER_SIZE + len);
+        match self.format {
+            PcapFormat::Pcap => {
+                record.put_u32_be(time.as_secs() as u32);
+                record.put_u32_be(time.subsec_micros());
+                record.put_u32_be(len as u32);
+                record.put_u32_be(len as u32);
+                record.put_slice(&packet);
+            }
+            PcapFormat::PcapNg => {
+                let micros = time.as_micros() as u64;
+                let mut body = BytesMut::with_capacity(EPB_HEADER_SIZE + len);
+                // Interface id
+                body.put_u32_be(0);
+                body.put_u32_be((micros >> 32) as u32);
+                body.put_u32_be(micros as u32);
+                body.put_u32_be(len as u32);
+                body.put_u32_be(len as u32);
+                body.put_slice(&packet);
+                put_block(&mut record, PCAPNG_ENHANCED_PACKET, &body);
+            }
+        }
+        self.writer.write_all(&record)
+    }
+
+    /// Writes the packets of a capture, skipping its other records.
+    pub fn write_capture<I>(&mut self, records: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = CaptureRecord>,
+    {
+        for record in records {
+            self.write_record(record.timestamp, record.kind, &record.data)?;
+        }
+        Ok(())
+    }
+
+    fn write_record(&mut self, timestamp: u32, kind: CaptureKind, data: &[u8]) -> io::Result<()> {
+        match kind {
+            CaptureKind::Inbound => self.write_packet(timestamp, false, data),
+            CaptureKind::Outbound => self.write_packet(timestamp, true, data),
+            _ => Ok(()),
+        }
+    }
+
+    /// Flushes and returns the underlying writer.
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write + Send> PacketRecorder for PcapWriter<W> {
+    fn record(&mut self, timestamp: u32, kind: CaptureKind, data: &[u8]) -> io::Result<()> {
+        self.write_record(timestamp, kind, data)
+    }
+}
+
+// Appends a pcapng block, padding its body to 32 bits.
+fn put_block(out: &mut BytesMut, block_type: u32, body: &[u8]) {
+    let padding = (4 - body.len() % 4) % 4;
+    let len = (12 + body.len() + padding) as u32;
+    out.reserve(len as usize);
+    out.put_u32_be(block_type);
+    out.put_u32_be(len);
+    out.put_slice(body);
+    out.put_slice(&[0; 3][..padding]);
+    out.put_u32_be(len);
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum = header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ipv4_checksum, PcapFormat, PcapWriter};
+    use crate::capture::{CaptureKind, CaptureRecord};
+    use bytes::Bytes;
+    use std::{net::SocketAddrV4, time::Duration};
+
+    fn addresses() -> (SocketAddrV4, SocketAddrV4) {
+        (
+            "10.0.0.1:4000".parse().unwrap(),
+            "10.0.0.2:5000".parse().unwrap(),
+        )
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes([
+            bytes[offset],
+            bytes[offset + 1],
+            bytes[offset + 2],
+            bytes[offset + 3],
+        ])
+    }
+
+    #[test]
+    fn test_pcap_record_has_udp_ip_headers() {
+        let (local, remote) = addresses();
+        let mut writer = PcapWriter::new(Vec::new(), PcapFormat::Pcap, local, remote)
+            .unwrap()
+            .with_start_time(Duration::from_secs(1_000));
+        writer.write_packet(1_500, false, b"segments").unwrap();
+        let file = writer.into_inner().unwrap();
+
+        assert_eq!(u32_at(&file, 0), 0xa1b2_c3d4);
+        assert_eq!(u32_at(&file, 20), 101);
+        let record = &file[24..];
+        assert_eq!(u32_at(record, 0), 1_001);
+        assert_eq!(u32_at(record, 4), 500_000);
+        assert_eq!(u32_at(record, 8), 36);
+        let packet = &record[16..];
+        assert_eq!(packet.len(), 36);
+        assert_eq!(ipv4_checksum(&packet[..20]), 0);
+        // Received, so from the remote to the local address
+        assert_eq!(&packet[12..16], &[10, 0, 0, 2]);
+        assert_eq!(&packet[16..20], &[10, 0, 0, 1]);
+        assert_eq!(&packet[20..24], &[0x13, 0x88, 0x0f, 0xa0]);
+        assert_eq!(&packet[28..], b"segments");
+    }
+
+    #[test]
+    fn test_pcapng_blocks_are_well_formed() {
+        let (local, remote) = addresses();
+        let mut writer = PcapWriter::new(Vec::new(), PcapFormat::PcapNg, local, remote).unwrap();
+        writer
+            .write_capture(vec![
+                CaptureRecord {
+                    timestamp: 0,
+                    kind: CaptureKind::Update,
+                    data: Bytes::new(),
+                },
+                CaptureRecord {
+                    timestamp: 2,
+                    kind: CaptureKind::Outbound,
+                    data: Bytes::from_static(b"odd"),
+                },
+            ])
+            .unwrap();
+        let file = writer.into_inner().unwrap();
+
+        let mut blocks = Vec::new();
+        let mut offset = 0;
+        while offset < file.len() {
+            let len = u32_at(&file, offset + 4) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(u32_at(&file, offset + len - 4) as usize, len);
+            blocks.push((u32_at(&file, offset), &file[offset..offset + len]));
+            offset += len;
+        }
+        assert_eq!(offset, file.len());
+        assert_eq!(
+            blocks.iter().map(|(kind, _)| *kind).collect::<Vec<_>>(),
+            vec![0x0a0d_0d0a, 1, 6]
+        );
+        let packet = blocks[2].1;
+        assert_eq!(u32_at(packet, 16), 2_000);
+        assert_eq!(u32_at(packet, 20), 31);
+        assert_eq!(&packet[28 + 12..28 + 16], &[10, 0, 0, 1]);
+        assert_eq!(&packet[28 + 28..28 + 31], b"odd");
+    }
+}
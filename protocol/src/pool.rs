@@ -0,0 +1,86 @@
+use bytes::BytesMut;
+
+/// Keeps the data buffers of segments which were acked or received so that new segments reuse
+/// them instead of allocating.
+pub(crate) struct BufferPool {
+    buffers: Vec<BytesMut>,
+    // Capacity of the buffers handed out, the maximum segment size
+    buffer_size: usize,
+    // Maximum number of idle buffers kept around
+    limit: usize,
+}
+
+impl BufferPool {
+    pub fn new(buffer_size: usize, limit: usize) -> Self {
+        Self {
+            buffers: Vec::with_capacity(limit),
+            buffer_size,
+            limit,
+        }
+    }
+
+    /// Returns an empty buffer which can hold a whole segment without growing.
+    pub fn take(&mut self) -> BytesMut {
+        self.buffers
+            .pop()
+            .unwrap_or_else(|| BytesMut::with_capacity(self.buffer_size))
+    }
+
+    /// Hands a buffer back for reuse. Buffers too small to hold a segment are dropped, as are
+    /// the ones past the limit.
+    pub fn put(&mut self, mut buffer: BytesMut) {
+        if buffer.capacity() < self.buffer_size || self.buffers.len() >= self.limit {
+            return;
+        }
+        buffer.clear();
+        self.buffers.push(buffer);
+    }
+
+    /// Changes the capacity of the buffers handed out, dropping the idle ones which are too small.
+    pub fn set_buffer_size(&mut self, buffer_size: usize) {
+        self.buffer_size = buffer_size;
+        self.buffers
+            .retain(|buffer| buffer.capacity() >= buffer_size);
+    }
+
+    /// Changes the maximum number of idle buffers kept around.
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        self.buffers.truncate(limit);
+        self.buffers
+            .reserve(limit.saturating_sub(self.buffers.len()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BufferPool;
+    use bytes::BytesMut;
+
+    #[test]
+    fn test_buffers_are_reused() {
+        let mut pool = BufferPool::new(64, 2);
+        let mut buffer = pool.take();
+        assert!(buffer.capacity() >= 64);
+        buffer.extend_from_slice(b"payload");
+        let address = buffer.as_ptr();
+        pool.put(buffer);
+
+        let buffer = pool.take();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.as_ptr(), address);
+    }
+
+    #[test]
+    fn test_small_and_excess_buffers_are_dropped() {
+        let mut pool = BufferPool::new(64, 1);
+        pool.put(BytesMut::with_capacity(8));
+        assert!(pool.buffers.is_empty());
+        pool.put(BytesMut::with_capacity(64));
+        pool.put(BytesMut::with_capacity(64));
+        assert_eq!(pool.buffers.len(), 1);
+
+        pool.set_buffer_size(128);
+        assert!(pool.buffers.is_empty());
+    }
+}
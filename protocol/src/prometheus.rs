@@ -0,0 +1,99 @@
+use crate::metrics::{DataPoint, Metrics};
+use std::fmt::{self, Write};
+
+const PREFIX: &str = "mercury";
+
+// Name (including the unit) and accessor of every gauge
+type Gauge = (&'static str, fn(&Metrics) -> f64);
+
+const GAUGES: [Gauge; 8] = [
+    ("packet_loss_ratio", |metrics| {
+        f64::from(metrics.packet_loss())
+    }),
+    ("sent_bandwidth_kbps", |metrics| {
+        f64::from(metrics.sent_bandwidth_kbps())
+    }),
+    ("received_bandwidth_kbps", |metrics| {
+        f64::from(metrics.received_bandwidth_kbps())
+    }),
+    ("acked_bandwidth_kbps", |metrics| {
+        f64::from(metrics.acked_bandwidth_kbps())
+    }),
+    ("rtt_milliseconds", |metrics| f64::from(metrics.rtt())),
+    ("rtt_variance_milliseconds", |metrics| {
+        f64::from(metrics.rtt_variance())
+    }),
+    ("rto_milliseconds", |metrics| f64::from(metrics.rto())),
+    ("congestion_window_segments", |metrics| {
+        f64::from(metrics.congestion_window())
+    }),
+];
+
+/// Writes the metrics of every connection in the Prometheus text exposition format. The samples
+/// of each connection are labelled with `connection="<name>"` so a whole server can be exposed on
+/// a single scrape endpoint.
+pub fn write_prometheus<W: Write>(out: &mut W, connections: &[(&str, &Metrics)]) -> fmt::Result {
+    for &data_point in DataPoint::ALL.iter() {
+        let name = format!("{}_{}_total", PREFIX, data_point.name());
+        writeln!(out, "# TYPE {} counter", name)?;
+        for (connection, metrics) in connections {
+            write_sample(out, &name, connection, metrics.get_count(data_point))?;
+        }
+    }
+    for (gauge, value) in GAUGES.iter() {
+        let name = format!("{}_{}", PREFIX, gauge);
+        writeln!(out, "# TYPE {} gauge", name)?;
+        for (connection, metrics) in connections {
+            write_sample(out, &name, connection, value(metrics))?;
+        }
+    }
+    Ok(())
+}
+
+fn write_sample<W: Write, V: fmt::Display>(
+    out: &mut W,
+    name: &str,
+    connection: &str,
+    value: V,
+) -> fmt::Result {
+    write!(out, "{}{{connection=\"", name)?;
+    for c in connection.chars() {
+        match c {
+            '\\' => out.write_str("\\\\")?,
+            '"' => out.write_str("\\\"")?,
+            '\n' => out.write_str("\\n")?,
+            c => out.write_char(c)?,
+        }
+    }
+    writeln!(out, "\"}} {}", value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::write_prometheus;
+    use crate::metrics::Metrics;
+
+    #[test]
+    fn test_samples_labelled_per_connection() {
+        let mut first = Metrics::new(0.1);
+        first.record_sent(100);
+        first.set_rtt(40, 5, 200);
+        let second = Metrics::new(0.1);
+
+        let mut out = String::new();
+        write_prometheus(&mut out, &[("10.0.0.1:4000", &first), ("a\"b", &second)]).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert_eq!(lines[0], "# TYPE mercury_packets_sent_total counter");
+        assert_eq!(
+            lines[1],
+            "mercury_packets_sent_total{connection=\"10.0.0.1:4000\"} 1"
+        );
+        assert_eq!(
+            lines[2],
+            "mercury_packets_sent_total{connection=\"a\\\"b\"} 0"
+        );
+        assert!(lines.contains(&"# TYPE mercury_rtt_milliseconds gauge"));
+        assert!(lines.contains(&"mercury_rtt_milliseconds{connection=\"10.0.0.1:4000\"} 40"));
+    }
+}
@@ -0,0 +1,421 @@
+use crate::{
+    capture::{self, CaptureKind, CaptureRecord, PacketRecorder},
+    config::Config,
+    connection::ReliableConnection,
+    transfer::TransferId,
+    ProtocolResult,
+};
+use bytes::Bytes;
+use std::{
+    collections::VecDeque,
+    io::{self, Read},
+    sync::{Arc, Mutex},
+};
+
+/// Reproduces a session captured from a `ReliableConnection` (a `CaptureLayer::Connection`
+/// capture) on a fresh connection. The recorded timestamps drive its clock, and the messages the
+/// application sent and received and the settings it changed are applied again, so the connection
+/// goes through the same states as the captured one and can be inspected between steps.
+///
+/// Captures of an `Endpoint` can't be replayed: its packets carry the headers of the endpoint on
+/// top of the segments and what it sends depends on its handshake and scheduling. They can still
+/// be inspected with `dump_packet`.
+///
+/// The packets the replayed connection sends are compared to the recorded ones, the first one
+/// which differs is reported by `divergence`.
+pub struct Replay<I> {
+    records: I,
+    connection: ReliableConnection<io::Sink>,
+    sent: Arc<Mutex<VecDeque<Bytes>>>,
+    position: usize,
+    divergence: Option<usize>,
+}
+
+// Collects the packets sent by the replayed connection.
+struct SentPackets(Arc<Mutex<VecDeque<Bytes>>>);
+
+impl PacketRecorder for SentPackets {
+    fn record(&mut self, _timestamp: u32, kind: CaptureKind, data: &[u8]) -> io::Result<()> {
+        if kind == CaptureKind::Outbound {
+            let mut sent = self.0.lock().expect("replay lock poisoned");
+            sent.push_back(Bytes::from(data));
+        }
+        Ok(())
+    }
+}
+
+impl<I> Replay<I>
+where
+    I: Iterator<Item = CaptureRecord>,
+{
+    /// Replays a capture of a connection created with the default `Config`.
+    pub fn new<T>(session_id: u32, records: T) -> Self
+    where
+        T: IntoIterator<Item = CaptureRecord, IntoIter = I>,
+    {
+        Self::start(ReliableConnection::new(session_id, io::sink()), records)
+    }
+
+    /// Replays a capture of a connection created with `config`, usually the one stored in the
+    /// capture (see `CaptureReader::config`). Fails if the config doesn't validate.
+    pub fn with_config<T>(session_id: u32, config: &Config, records: T) -> ProtocolResult<Self>
+    where
+        T: IntoIterator<Item = CaptureRecord, IntoIter = I>,
+    {
+        let connection = ReliableConnection::with_config(session_id, io::sink(), config)?;
+        Ok(Self::start(connection, records))
+    }
+
+    fn start<T>(mut connection: ReliableConnection<io::Sink>, records: T) -> Self
+    where
+        T: IntoIterator<Item = CaptureRecord, IntoIter = I>,
+    {
+        let sent = Arc::new(Mutex::new(VecDeque::new()));
+        connection.set_recorder(SentPackets(Arc::clone(&sent)));
+        Self {
+            records: records.into_iter(),
+            connection,
+            sent,
+            position: 0,
+            divergence: None,
+        }
+    }
+
+    /// Returns the replayed connection.
+    pub fn connection(&self) -> &ReliableConnection<io::Sink> {
+        &self.connection
+    }
+
+    /// Returns the replayed connection, e.g. to read the messages it received between steps.
+    /// Anything changed through it which the captured connection didn't do makes the replay
+    /// diverge.
+    pub fn connection_mut(&mut self) -> &mut ReliableConnection<io::Sink> {
+        &mut self.connection
+    }
+
+    /// Applies the next record to the connection and returns it, or `None` once the capture is
+    /// exhausted.
+    pub fn step(&mut self) -> Option<CaptureRecord> {
+        let record = self.records.next()?;
+        // Errors are part of what is being reproduced, the captured connection ran into them too.
+        // Records too short for their kind are skipped.
+        let data = &record.data;
+        match record.kind {
+            CaptureKind::Update => {
+                let _ = self.connection.update(record.timestamp);
+            }
+            CaptureKind::Inbound => {
+                let _ = self.connection.input(&record.data);
+            }
+            CaptureKind::Send => {
+                let _ = self.connection.send(&record.data);
+            }
+            CaptureKind::SendWithTtl => {
+                if let Some(ttl) = read_u32(data, 0) {
+                    let _ = self.connection.send_with_ttl(&data[4..], ttl);
+                }
+            }
+            CaptureKind::Recv => {
+                let _ = self.connection.recv_fragments();
+            }
+            CaptureKind::Read => {
+                let mut buffer = vec![0; data.len()];
+                let _ = self.connection.read(&mut buffer);
+            }
+            CaptureKind::SendLarge => {
+                let _ = self.connection.send_large(data.clone());
+            }
+            CaptureKind::CancelTransfer => {
+                if let Some(id) = read_u32(data, 0) {
+                    self.connection.cancel_transfer(TransferId(id));
+                }
+            }
+            CaptureKind::SetMtu => {
+                if let Some(mtu) = read_u32(data, 0) {
+                    let _ = self.connection.set_mtu(mtu as usize);
+                }
+            }
+            CaptureKind::SetWindowSizes => {
+                if let (Some(send_size), Some(recv_size)) = (read_u32(data, 0), read_u32(data, 4)) {
+                    let _ = self
+                        .connection
+                        .set_window_sizes(send_size as usize, recv_size as usize);
+                }
+            }
+            CaptureKind::SetRecvWindowAutoTuning => {
+                if let Some(max_size) = read_u32(data, 1) {
+                    let max_size = Some(max_size as usize).filter(|_| data[0] != 0);
+                    let _ = self.connection.set_recv_window_auto_tuning(max_size);
+                }
+            }
+            CaptureKind::SetKeepalive => {
+                if let Some(interval) = read_u32(data, 0) {
+                    let _ = self.connection.set_keepalive(interval);
+                }
+            }
+            CaptureKind::SetIdleTimeout => {
+                if let Some(timeout) = read_u32(data, 0) {
+                    let _ = self.connection.set_idle_timeout(timeout);
+                }
+            }
+            CaptureKind::SetStreamingMode => {
+                if let Some(&enabled) = data.first() {
+                    self.connection.set_streaming_mode(enabled != 0);
+                }
+            }
+            CaptureKind::Nodelay => {
+                if let (Some(nodelay), Some(interval), Some(resend), Some(&congestion_control)) = (
+                    read_u32(data, 0),
+                    read_u32(data, 4),
+                    read_u32(data, 8),
+                    data.get(12),
+                ) {
+                    #[allow(deprecated)]
+                    self.connection.nodelay(
+                        nodelay as i32,
+                        interval as i32,
+                        resend as i32,
+                        congestion_control != 0,
+                    );
+                }
+            }
+            CaptureKind::Reconfigure => {
+                if let Some(config) = capture::decode_config(data) {
+                    let _ = self.connection.reconfigure(&config);
+                }
+            }
+            CaptureKind::Outbound => {
+                let sent = self.sent.lock().expect("replay lock poisoned").pop_front();
+                if self.divergence.is_none() && sent.as_ref() != Some(&record.data) {
+                    self.divergence = Some(self.position);
+                }
+            }
+        }
+        self.position += 1;
+        Some(record)
+    }
+
+    /// Applies every remaining record.
+    pub fn run(&mut self) {
+        while self.step().is_some() {}
+    }
+
+    /// Returns the index of the first outbound record which the replayed connection didn't send
+    /// identically, if any.
+    pub fn divergence(&self) -> Option<usize> {
+        self.divergence
+    }
+}
+
+// Reads the big-endian u32 at `offset` of the data of a record, if it's long enough.
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::Replay;
+    use crate::{
+        capture::{
+            CaptureKind, CaptureLayer, CaptureReader, CaptureRecord, CaptureWriter, PacketRecorder,
+        },
+        Config, DataPoint, Preset, ReliableConnection,
+    };
+    use bytes::Bytes;
+    use std::{
+        io::{self, Cursor, Read, Write},
+        sync::{Arc, Mutex},
+    };
+
+    // A capture file shared with the test while the connection holds the writer.
+    #[derive(Clone, Default)]
+    struct SharedFile(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn capture_session() -> Vec<u8> {
+        let file = SharedFile::default();
+        let mut client = ReliableConnection::new(1, Vec::new());
+        let mut server = ReliableConnection::new(1, Vec::new());
+        server.set_recorder(
+            CaptureWriter::new(file.clone(), CaptureLayer::Connection, &Config::default()).unwrap(),
+        );
+
+        server.update(0).unwrap();
+        client.update(0).unwrap();
+        server.send(b"state").unwrap();
+        server.send_with_ttl(b"snapshot", 50).unwrap();
+        for time in (100..=1000).step_by(100) {
+            client.update(time).unwrap();
+            server.update(time).unwrap();
+            // Drop every other flush of the server to force retransmissions
+            if time % 200 == 0 {
+                client.input(&server.output_mut().split_off(0)).ok();
+            } else {
+                server.output_mut().clear();
+            }
+            let packets = client.output_mut().split_off(0);
+            if !packets.is_empty() {
+                server.input(&packets).unwrap();
+            }
+        }
+        let file = file.0.lock().unwrap().clone();
+        file
+    }
+
+    #[test]
+    fn test_replay_reproduces_captured_session() {
+        let reader = CaptureReader::new(Cursor::new(capture_session())).unwrap();
+        assert_eq!(reader.layer(), CaptureLayer::Connection);
+        let records = reader.collect::<io::Result<Vec<_>>>().unwrap();
+        assert!(records
+            .iter()
+            .any(|record| record.kind == CaptureKind::Inbound));
+        let outbound = records
+            .iter()
+            .filter(|record| record.kind == CaptureKind::Outbound)
+            .count();
+        assert!(outbound >= 2);
+
+        let mut replay = Replay::new(1, records);
+        replay.run();
+        assert_eq!(replay.divergence(), None);
+        assert_eq!(replay.connection().num_segments_awaiting_send(), 0);
+        let metrics = replay.connection().metrics();
+        assert!(metrics.get_count(DataPoint::PacketsAcked) > 0);
+    }
+
+    // Passes the packets written by each connection to the other one.
+    fn exchange(
+        client: &mut ReliableConnection<Vec<u8>>,
+        server: &mut ReliableConnection<Vec<u8>>,
+    ) {
+        let packets = client.output_mut().split_off(0);
+        if !packets.is_empty() {
+            server.input(&packets).unwrap();
+        }
+        let packets = server.output_mut().split_off(0);
+        if !packets.is_empty() {
+            client.input(&packets).unwrap();
+        }
+    }
+
+    fn read_capture(file: SharedFile) -> (Config, Vec<CaptureRecord>) {
+        let file = file.0.lock().unwrap().clone();
+        let reader = CaptureReader::new(Cursor::new(file)).unwrap();
+        let config = reader.config().clone();
+        (config, reader.collect::<io::Result<Vec<_>>>().unwrap())
+    }
+
+    #[test]
+    fn test_replay_reproduces_reads_and_setting_changes() {
+        let config = Config::default().with_window_sizes(16, 4);
+        let file = SharedFile::default();
+        let mut client = ReliableConnection::new(1, Vec::new());
+        let mut server = ReliableConnection::with_config(1, Vec::new(), &config).unwrap();
+        server.set_recorder(
+            CaptureWriter::new(file.clone(), CaptureLayer::Connection, &config).unwrap(),
+        );
+
+        for i in 0..12 {
+            client.send(&[i; 10]).unwrap();
+        }
+        server.send_large(Bytes::from(vec![7; 5_000])).unwrap();
+        let mut buffer = [0; 16];
+        let mut received = 0;
+        for time in (0..=3000).step_by(100) {
+            client.update(time).unwrap();
+            server.update(time).unwrap();
+            exchange(&mut client, &mut server);
+            // The application reads at its own pace, which changes the window the server
+            // advertises to the client
+            if time % 300 == 0 && server.recv(&mut buffer).is_ok() {
+                received += 1;
+            }
+            match time {
+                500 => server.set_mtu(600).unwrap(),
+                800 => server
+                    .reconfigure(&config.clone().with_preset(Preset::Fast))
+                    .unwrap(),
+                1200 => server.set_window_sizes(8, 8).unwrap(),
+                _ => {}
+            }
+        }
+        assert!(received > 0);
+        assert!(client.recv_large().is_some());
+
+        let (config, records) = read_capture(file);
+        for kind in &[
+            CaptureKind::Recv,
+            CaptureKind::SendLarge,
+            CaptureKind::SetMtu,
+            CaptureKind::Reconfigure,
+            CaptureKind::SetWindowSizes,
+        ] {
+            assert!(records.iter().any(|record| record.kind == *kind));
+        }
+
+        let mut replay = Replay::with_config(1, &config, records).unwrap();
+        replay.run();
+        assert_eq!(replay.divergence(), None);
+        assert_eq!(replay.connection().recv_window_size(), 8);
+    }
+
+    #[test]
+    fn test_replay_reproduces_stream_reads() {
+        let file = SharedFile::default();
+        let mut client = ReliableConnection::new(1, Vec::new());
+        let mut server = ReliableConnection::new(1, Vec::new());
+        server.set_recorder(
+            CaptureWriter::new(file.clone(), CaptureLayer::Connection, &Config::default()).unwrap(),
+        );
+        client.set_streaming_mode(true);
+        server.set_streaming_mode(true);
+
+        client.write_all(&[1; 40_000]).unwrap();
+        let mut buffer = [0; 1_000];
+        let mut read = 0;
+        for time in (0..=2000).step_by(100) {
+            client.update(time).unwrap();
+            server.update(time).unwrap();
+            exchange(&mut client, &mut server);
+            read += server.read(&mut buffer).unwrap_or(0);
+        }
+        assert!(read > 0);
+
+        let (config, records) = read_capture(file);
+        assert!(records
+            .iter()
+            .any(|record| record.kind == CaptureKind::Read));
+        let mut replay = Replay::with_config(1, &config, records).unwrap();
+        replay.run();
+        assert_eq!(replay.divergence(), None);
+    }
+
+    #[test]
+    fn test_replay_reports_divergence() {
+        let mut writer =
+            CaptureWriter::new(Vec::new(), CaptureLayer::Connection, &Config::default()).unwrap();
+        writer.record(0, CaptureKind::Update, &[]).unwrap();
+        writer
+            .record(0, CaptureKind::Outbound, b"never sent")
+            .unwrap();
+        let file = writer.into_inner().unwrap();
+        let records = CaptureReader::new(Cursor::new(file))
+            .unwrap()
+            .map(Result::unwrap);
+
+        let mut replay = Replay::new(1, records);
+        replay.run();
+        assert_eq!(replay.divergence(), Some(1));
+    }
+}
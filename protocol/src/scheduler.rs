@@ -0,0 +1,514 @@
+use crate::{
+    config::{Config, StreamSettings},
+    connection::ReliableConnection,
+    guarantees::DeliveryGuarantee,
+    message::Message,
+    ProtocolResult, PACKET_UNRELIABLE,
+};
+use bytes::{BufMut, Bytes, BytesMut};
+use log::debug;
+use std::{cmp, collections::VecDeque, io::Write};
+
+/// Decides which of the queued messages go out on each `Endpoint` update.
+///
+/// Messages are queued per stream and streams are drained from the highest priority down, so a
+/// large transfer on a low priority stream can never starve the streams above it. Each stream can
+/// have its own bandwidth budget on top of the connection wide budget. Reliable messages which
+/// don't fit in the budget are deferred to a later update while unreliable ones are dropped.
+pub struct Scheduler {
+    // Sorted from the highest priority to the lowest
+    channels: Vec<Channel>,
+    bandwidth: TokenBucket,
+}
+
+/// The result of a single call to `Scheduler::schedule`.
+#[derive(Default)]
+pub struct Scheduled {
+    /// Packets of unreliable messages ready to be sent, along with the highest redundancy ratio
+    /// of the streams in each of them.
+    pub packets: Vec<(Bytes, f32)>,
+    /// Number of unreliable messages dropped because a bandwidth budget was exceeded.
+    pub dropped: usize,
+    /// Highest redundancy ratio of the streams which handed reliable messages to the connection.
+    pub reliable_redundancy: f32,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self {
+            channels: Vec::new(),
+            bandwidth: TokenBucket::new(None),
+        }
+    }
+
+    /// Queues a message on the channel for its stream.
+    pub fn push(&mut self, message: Message, settings: StreamSettings) {
+        let index = match self
+            .channels
+            .iter()
+            .position(|channel| channel.stream_id == message.stream_id)
+        {
+            Some(index) => index,
+            None => {
+                // Keep channels with equal priorities in the order they were first used.
+                let index = self
+                    .channels
+                    .iter()
+                    .position(|channel| channel.priority < settings.priority)
+                    .unwrap_or(self.channels.len());
+                self.channels
+                    .insert(index, Channel::new(message.stream_id, settings));
+                index
+            }
+        };
+        self.channels[index].queue.push_back(message);
+    }
+
+    /// Refills the bandwidth budgets with the bytes allowed over the elapsed millisec.
+    /// `estimated_bandwidth` is the number of bytes per second the connection is able to send.
+    pub fn refill(&mut self, elapsed: u32, estimated_bandwidth: usize) {
+        self.bandwidth.set_rate(estimated_bandwidth as u32);
+        self.bandwidth.refill(elapsed);
+        for channel in self.channels.iter_mut() {
+            channel.bandwidth.refill(elapsed);
+        }
+    }
+
+    /// Hands reliable messages to `connection` and packs unreliable messages into packets of at
+    /// most `mtu` bytes, highest priority first.
+    pub fn schedule<W: Write>(
+        &mut self,
+        connection: &mut ReliableConnection<W>,
+        mtu: usize,
+    ) -> ProtocolResult<Scheduled> {
+        let mut scheduled = Scheduled::default();
+        let mut packet = new_unreliable_packet(mtu);
+        let mut packet_redundancy = 0.0f32;
+
+        for channel in self.channels.iter_mut() {
+            while let Some(message) = channel.queue.front() {
+                let size = message.encoded_len();
+                let within_budget = self.bandwidth.has_tokens() && channel.bandwidth.has_tokens();
+
+                match message.delivery {
+                    DeliveryGuarantee::Reliable => {
+                        // Don't queue more than a window of segments in the connection so that
+                        // messages on higher priority streams can still jump ahead.
+                        if !within_budget
+                            || connection.num_segments_awaiting_send()
+                                >= connection.send_window_size()
+                        {
+                            break;
+                        }
+                        let mut buf = BytesMut::with_capacity(size);
+                        message.encode(&mut buf)?;
+                        connection.send(&buf)?;
+                        scheduled.reliable_redundancy =
+                            scheduled.reliable_redundancy.max(channel.redundancy);
+                    }
+                    DeliveryGuarantee::Unreliable => {
+                        if !within_budget {
+                            debug!(
+                                "Dropping unreliable message on stream {} over budget",
+                                channel.stream_id
+                            );
+                            channel.queue.pop_front();
+                            scheduled.dropped += 1;
+                            continue;
+                        }
+                        if packet.len() + size > mtu {
+                            scheduled.packets.push((packet.freeze(), packet_redundancy));
+                            packet = new_unreliable_packet(mtu);
+                            packet_redundancy = 0.0;
+                        }
+                        message.encode(&mut packet)?;
+                        packet_redundancy = packet_redundancy.max(channel.redundancy);
+                    }
+                    DeliveryGuarantee::Redundant => {
+                        // The history is sent once with every redundant message queued in a row
+                        while let Some(message) = channel.queue.pop_front() {
+                            if message.delivery != DeliveryGuarantee::Redundant {
+                                channel.queue.push_front(message);
+                                break;
+                            }
+                            channel.remember(message);
+                        }
+                        if !within_budget {
+                            // The next packet of the stream still carries it
+                            debug!(
+                                "Deferring redundant message on stream {} over budget",
+                                channel.stream_id
+                            );
+                            continue;
+                        }
+                        let size = channel.history_len(mtu - 1);
+                        if packet.len() + size > mtu {
+                            scheduled.packets.push((packet.freeze(), packet_redundancy));
+                            packet = new_unreliable_packet(mtu);
+                            packet_redundancy = 0.0;
+                        }
+                        for message in channel.history.iter() {
+                            message.encode(&mut packet)?;
+                        }
+                        packet_redundancy = packet_redundancy.max(channel.redundancy);
+                        self.bandwidth.consume(size);
+                        channel.bandwidth.consume(size);
+                        continue;
+                    }
+                }
+
+                self.bandwidth.consume(size);
+                channel.bandwidth.consume(size);
+                channel.queue.pop_front();
+            }
+        }
+
+        if packet.len() > 1 {
+            scheduled.packets.push((packet.freeze(), packet_redundancy));
+        }
+
+        Ok(scheduled)
+    }
+
+    /// Applies the stream settings of `config` to the streams already in use and drops the queued
+    /// unreliable messages which no longer fit in a packet of `mtu` bytes. Returns the number of
+    /// messages dropped.
+    pub fn reconfigure(&mut self, config: &Config, mtu: usize) -> usize {
+        let max_size = mtu - 1;
+        let mut dropped = 0;
+        for channel in self.channels.iter_mut() {
+            channel.apply(config.stream_settings(usize::from(channel.stream_id)));
+            let fits = |message: &Message| {
+                message.delivery == DeliveryGuarantee::Reliable || message.encoded_len() <= max_size
+            };
+            let queued = channel.queue.len();
+            channel.queue.retain(fits);
+            channel.history.retain(fits);
+            dropped += queued - channel.queue.len();
+        }
+        // Stable, so channels with equal priorities stay in the order they were first used
+        self.channels
+            .sort_by_key(|channel| cmp::Reverse(channel.priority));
+        dropped
+    }
+
+    /// Stops resending the messages of a redundant stream up to and including `sequence_num`,
+    /// which the remote acknowledged.
+    pub fn acknowledge(&mut self, stream_id: u8, sequence_num: u16) {
+        if let Some(channel) = self
+            .channels
+            .iter_mut()
+            .find(|channel| channel.stream_id == stream_id)
+        {
+            channel
+                .history
+                .retain(|message| (message.sequence_num.wrapping_sub(sequence_num) as i16) > 0);
+        }
+    }
+}
+
+struct Channel {
+    stream_id: u8,
+    priority: u8,
+    redundancy: f32,
+    bandwidth: TokenBucket,
+    queue: VecDeque<Message>,
+    // Newest unacknowledged messages of a redundant stream, oldest first
+    history: VecDeque<Message>,
+    max_history: usize,
+}
+
+impl Channel {
+    fn new(stream_id: u8, settings: StreamSettings) -> Self {
+        Self {
+            stream_id,
+            priority: settings.priority,
+            redundancy: settings.redundancy,
+            bandwidth: TokenBucket::new(settings.bandwidth_budget),
+            queue: VecDeque::new(),
+            history: VecDeque::new(),
+            max_history: settings.redundant_history.max(1),
+        }
+    }
+
+    fn apply(&mut self, settings: StreamSettings) {
+        self.priority = settings.priority;
+        self.redundancy = settings.redundancy;
+        match settings.bandwidth_budget {
+            Some(rate) => self.bandwidth.set_rate(rate),
+            None => self.bandwidth = TokenBucket::new(None),
+        }
+        self.max_history = settings.redundant_history.max(1);
+        while self.history.len() > self.max_history {
+            self.history.pop_front();
+        }
+    }
+
+    fn remember(&mut self, message: Message) {
+        self.history.push_back(message);
+        if self.history.len() > self.max_history {
+            self.history.pop_front();
+        }
+    }
+
+    // Returns the encoded size of the history, forgetting the oldest messages until it fits in
+    // `max_size` bytes.
+    fn history_len(&mut self, max_size: usize) -> usize {
+        let mut size: usize = self.history.iter().map(Message::encoded_len).sum();
+        while size > max_size && self.history.len() > 1 {
+            if let Some(message) = self.history.pop_front() {
+                size -= message.encoded_len();
+            }
+        }
+        size
+    }
+}
+
+// Tracks the number of bytes which may still be sent. Tokens are refilled at `rate` bytes per
+// second up to one second worth of data. A message may be sent as long as there are any tokens
+// left, which lets messages larger than the rate through at the cost of going into debt.
+struct TokenBucket {
+    rate: Option<u32>,
+    tokens: i64,
+}
+
+impl TokenBucket {
+    fn new(rate: Option<u32>) -> Self {
+        Self {
+            rate,
+            tokens: rate.map_or(0, i64::from),
+        }
+    }
+
+    fn set_rate(&mut self, rate: u32) {
+        if self.rate.is_none() {
+            self.tokens = i64::from(rate);
+        }
+        self.rate = Some(rate);
+    }
+
+    fn refill(&mut self, elapsed: u32) {
+        if let Some(rate) = self.rate {
+            let rate = i64::from(rate);
+            self.tokens = (self.tokens + rate * i64::from(elapsed) / 1000).min(rate);
+        }
+    }
+
+    #[inline]
+    fn has_tokens(&self) -> bool {
+        self.rate.is_none() || self.tokens > 0
+    }
+
+    #[inline]
+    fn consume(&mut self, bytes: usize) {
+        if self.rate.is_some() {
+            self.tokens -= bytes as i64;
+        }
+    }
+}
+
+fn new_unreliable_packet(mtu: usize) -> BytesMut {
+    let mut packet = BytesMut::with_capacity(mtu);
+    packet.put_u8(PACKET_UNRELIABLE);
+    packet
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Scheduler, TokenBucket};
+    use crate::{
+        config::{Config, StreamSettings},
+        connection::ReliableConnection,
+        guarantees::{DeliveryGuarantee, OrderingGuarantee},
+        message::Message,
+        DEFAULT_MTU,
+    };
+    use bytes::Bytes;
+    use std::io;
+
+    fn settings(priority: u8, bandwidth_budget: Option<u32>) -> StreamSettings {
+        StreamSettings {
+            priority,
+            bandwidth_budget,
+            ..StreamSettings::default()
+        }
+    }
+
+    fn message(stream_id: u8, delivery: DeliveryGuarantee, payload: &'static [u8]) -> Message {
+        Message {
+            stream_id,
+            delivery,
+            ordering: OrderingGuarantee::None,
+            sequence_num: 0,
+            payload: Bytes::from_static(payload),
+        }
+    }
+
+    fn unreliable(stream_id: u8, payload: &'static [u8]) -> Message {
+        message(stream_id, DeliveryGuarantee::Unreliable, payload)
+    }
+
+    fn reliable(stream_id: u8, payload: &'static [u8]) -> Message {
+        message(stream_id, DeliveryGuarantee::Reliable, payload)
+    }
+
+    fn connection() -> ReliableConnection<io::Sink> {
+        ReliableConnection::new(0, io::sink())
+    }
+
+    fn num_pending(scheduler: &Scheduler) -> usize {
+        scheduler
+            .channels
+            .iter()
+            .map(|channel| channel.queue.len())
+            .sum()
+    }
+
+    #[test]
+    fn test_highest_priority_packed_first() {
+        let mut scheduler = Scheduler::new();
+        scheduler.push(unreliable(0, b"chat"), settings(0, None));
+        scheduler.push(unreliable(1, b"state"), settings(10, None));
+        scheduler.push(unreliable(2, b"input"), settings(5, None));
+
+        let scheduled = scheduler.schedule(&mut connection(), DEFAULT_MTU).unwrap();
+        assert_eq!(scheduled.packets.len(), 1);
+        let packet = &scheduled.packets[0].0;
+        assert_eq!(packet[1], 1);
+        assert_eq!(&packet[7..12], b"state");
+        assert_eq!(packet[12], 2);
+        assert_eq!(&packet[18..23], b"input");
+        assert_eq!(packet[23], 0);
+        assert_eq!(&packet[29..33], b"chat");
+    }
+
+    #[test]
+    fn test_reconfigure_reorders_streams_and_drops_oversized() {
+        let mut scheduler = Scheduler::new();
+        scheduler.push(unreliable(0, &[0; 200]), settings(0, None));
+        scheduler.push(unreliable(0, b"small"), settings(0, None));
+        scheduler.push(reliable(1, &[1; 200]), settings(0, None));
+
+        let config = Config::default().with_mtu(100).with_stream_priority(1, 10);
+        assert_eq!(scheduler.reconfigure(&config, config.mtu()), 1);
+        assert_eq!(num_pending(&scheduler), 2);
+        assert_eq!(scheduler.channels[0].stream_id, 1);
+        assert_eq!(scheduler.channels[0].priority, 10);
+    }
+
+    #[test]
+    fn test_redundant_messages_queued_together_sent_once() {
+        let mut scheduler = Scheduler::new();
+        for payload in [b"1", b"2", b"3"].iter() {
+            scheduler.push(
+                message(0, DeliveryGuarantee::Redundant, &payload[..]),
+                settings(0, None),
+            );
+        }
+        let scheduled = scheduler.schedule(&mut connection(), DEFAULT_MTU).unwrap();
+        assert_eq!(scheduled.packets.len(), 1);
+        let packet = &scheduled.packets[0].0;
+        // Each message of 7 bytes appears exactly once, oldest first
+        assert_eq!(packet.len(), 1 + 3 * 7);
+        assert_eq!([packet[7], packet[14], packet[21]], [b'1', b'2', b'3']);
+        assert_eq!(num_pending(&scheduler), 0);
+    }
+
+    #[test]
+    fn test_splits_packets_at_mtu() {
+        let mut scheduler = Scheduler::new();
+        for _ in 0..3 {
+            scheduler.push(unreliable(0, b"0123456789"), settings(0, None));
+        }
+        // Room for two messages of 16 bytes after the packet type
+        let scheduled = scheduler.schedule(&mut connection(), 33).unwrap();
+        assert_eq!(scheduled.packets.len(), 2);
+        assert_eq!(scheduled.packets[0].0.len(), 33);
+        assert_eq!(scheduled.packets[1].0.len(), 17);
+    }
+
+    #[test]
+    fn test_unreliable_dropped_over_stream_budget() {
+        let mut scheduler = Scheduler::new();
+        scheduler.push(unreliable(0, b"0123456789"), settings(0, Some(10)));
+        scheduler.push(unreliable(0, b"0123456789"), settings(0, Some(10)));
+        scheduler.push(unreliable(1, b"0123456789"), settings(0, None));
+
+        let scheduled = scheduler.schedule(&mut connection(), DEFAULT_MTU).unwrap();
+        assert_eq!(scheduled.dropped, 1);
+        assert_eq!(scheduled.packets[0].0.len(), 33);
+        assert_eq!(num_pending(&scheduler), 0);
+    }
+
+    #[test]
+    fn test_reliable_deferred_over_stream_budget() {
+        let mut scheduler = Scheduler::new();
+        let mut connection = connection();
+        scheduler.push(reliable(0, b"0123456789"), settings(0, Some(10)));
+        scheduler.push(reliable(0, b"0123456789"), settings(0, Some(10)));
+
+        scheduler.schedule(&mut connection, DEFAULT_MTU).unwrap();
+        assert_eq!(connection.num_segments_awaiting_send(), 1);
+        assert_eq!(num_pending(&scheduler), 1);
+
+        // 700ms at 10 bytes per second pays back the 6 bytes of debt with some to spare.
+        scheduler.refill(700, 1_000_000);
+        scheduler.schedule(&mut connection, DEFAULT_MTU).unwrap();
+        assert_eq!(connection.num_segments_awaiting_send(), 2);
+        assert_eq!(num_pending(&scheduler), 0);
+    }
+
+    #[test]
+    fn test_low_priority_dropped_when_bandwidth_exceeded() {
+        let mut scheduler = Scheduler::new();
+        scheduler.refill(0, 20);
+        scheduler.push(unreliable(0, b"chat"), settings(0, None));
+        scheduler.push(unreliable(1, b"0123456789"), settings(10, None));
+        scheduler.push(unreliable(1, b"0123456789"), settings(10, None));
+
+        let scheduled = scheduler.schedule(&mut connection(), DEFAULT_MTU).unwrap();
+        assert_eq!(scheduled.dropped, 1);
+        assert_eq!(scheduled.packets[0].0.len(), 33);
+    }
+
+    #[test]
+    fn test_packet_redundancy_is_highest_of_its_streams() {
+        let mut scheduler = Scheduler::new();
+        let protected = StreamSettings {
+            redundancy: 0.5,
+            ..StreamSettings::default()
+        };
+        scheduler.push(unreliable(0, b"0123456789"), protected);
+        scheduler.push(unreliable(1, b"0123456789"), settings(0, None));
+        scheduler.push(unreliable(1, b"0123456789"), settings(0, None));
+
+        // Room for two messages of 16 bytes after the packet type
+        let scheduled = scheduler.schedule(&mut connection(), 33).unwrap();
+        assert_eq!(scheduled.packets[0].1, 0.5);
+        assert_eq!(scheduled.packets[1].1, 0.0);
+    }
+
+    #[test]
+    fn test_reliable_held_back_while_connection_is_backed_up() {
+        let mut scheduler = Scheduler::new();
+        let mut connection = connection();
+        let window = connection.send_window_size();
+        for _ in 0..window + 1 {
+            scheduler.push(reliable(0, b"asset"), settings(0, None));
+        }
+        scheduler.schedule(&mut connection, DEFAULT_MTU).unwrap();
+        assert_eq!(connection.num_segments_awaiting_send(), window);
+        assert_eq!(num_pending(&scheduler), 1);
+    }
+
+    #[test]
+    fn test_token_bucket_refill_is_capped() {
+        let mut bucket = TokenBucket::new(Some(100));
+        bucket.consume(150);
+        assert!(!bucket.has_tokens());
+        bucket.refill(1000);
+        assert_eq!(bucket.tokens, 50);
+        bucket.refill(10_000);
+        assert_eq!(bucket.tokens, 100);
+    }
+}
@@ -1,4 +1,6 @@
-use bytes::{BufMut, BytesMut};
+use crate::config::WireFormat;
+use bytes::{Buf, BufMut, BytesMut};
+use std::io::Cursor;
 
 pub struct Segment {
     pub(crate) session_id: u32,
@@ -12,6 +14,8 @@ pub struct Segment {
     pub(crate) rto: u32,
     pub(crate) fastack: u32,
     pub(crate) xmit: u32,
+    // Time after which the segment is no longer worth delivering
+    pub(crate) expire_time: Option<u32>,
     pub(crate) data: BytesMut,
 }
 
@@ -35,19 +39,133 @@ impl Segment {
             rto: 0,
             fastack: 0,
             xmit: 0,
+            expire_time: None,
             data,
         }
     }
 
-    pub fn encode(&self, buf: &mut BytesMut) {
-        buf.put_u32_be(self.session_id);
-        buf.put_u8(self.command);
-        buf.put_u8(self.fragment_id);
-        buf.put_u16_be(self.window_size);
-        buf.put_u32_be(self.timestamp);
-        buf.put_u32_be(self.sequence_num);
-        buf.put_u32_be(self.unacked_sequence_num);
-        buf.put_u32_be(self.data.len() as u32);
+    pub fn encode(&self, buf: &mut BytesMut, format: WireFormat) {
+        match format {
+            WireFormat::Mercury => {
+                buf.put_u32_be(self.session_id);
+                buf.put_u8(self.command);
+                buf.put_u8(self.fragment_id);
+                buf.put_u16_be(self.window_size);
+                buf.put_u32_be(self.timestamp);
+                buf.put_u32_be(self.sequence_num);
+                buf.put_u32_be(self.unacked_sequence_num);
+                buf.put_u32_be(self.data.len() as u32);
+            }
+            // ikcp_encode_seg
+            WireFormat::Kcp => {
+                buf.put_u32_le(self.session_id);
+                buf.put_u8(self.command);
+                buf.put_u8(self.fragment_id);
+                buf.put_u16_le(self.window_size);
+                buf.put_u32_le(self.timestamp);
+                buf.put_u32_le(self.sequence_num);
+                buf.put_u32_le(self.unacked_sequence_num);
+                buf.put_u32_le(self.data.len() as u32);
+            }
+        }
         buf.put_slice(&self.data);
     }
 }
+
+/// The header of a segment read off the wire, before its data is split from the packet.
+pub(crate) struct SegmentHeader {
+    pub session_id: u32,
+    pub command: u8,
+    pub fragment_id: u8,
+    pub window_size: u16,
+    pub timestamp: u32,
+    pub sequence_num: u32,
+    pub unacked_sequence_num: u32,
+    pub len: usize,
+}
+
+impl SegmentHeader {
+    /// Reads the header from the first PROTOCOL_OVERHEAD bytes of `buf`.
+    pub fn decode(buf: &[u8], format: WireFormat) -> Self {
+        let mut cursor = Cursor::new(buf);
+        // Fields are read in the order they're written
+        match format {
+            WireFormat::Mercury => Self {
+                session_id: cursor.get_u32_be(),
+                command: cursor.get_u8(),
+                fragment_id: cursor.get_u8(),
+                window_size: cursor.get_u16_be(),
+                timestamp: cursor.get_u32_be(),
+                sequence_num: cursor.get_u32_be(),
+                unacked_sequence_num: cursor.get_u32_be(),
+                len: cursor.get_u32_be() as usize,
+            },
+            WireFormat::Kcp => Self {
+                session_id: cursor.get_u32_le(),
+                command: cursor.get_u8(),
+                fragment_id: cursor.get_u8(),
+                window_size: cursor.get_u16_le(),
+                timestamp: cursor.get_u32_le(),
+                sequence_num: cursor.get_u32_le(),
+                unacked_sequence_num: cursor.get_u32_le(),
+                len: cursor.get_u32_le() as usize,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Segment, SegmentHeader};
+    use crate::{WireFormat, CMD_PUSH};
+    use bytes::BytesMut;
+
+    fn segment() -> Segment {
+        Segment {
+            session_id: 0x0102_0304,
+            command: CMD_PUSH,
+            fragment_id: 2,
+            window_size: 0x0080,
+            timestamp: 0x0A0B_0C0D,
+            sequence_num: 7,
+            unacked_sequence_num: 5,
+            ..Segment::new(BytesMut::from(&b"hi"[..]))
+        }
+    }
+
+    // Bytes written by ikcp_encode_seg for the same segment
+    const KCP_SEGMENT: [u8; 26] = [
+        0x04, 0x03, 0x02, 0x01, 81, 2, 0x80, 0x00, 0x0D, 0x0C, 0x0B, 0x0A, 7, 0, 0, 0, 5, 0, 0, 0,
+        2, 0, 0, 0, b'h', b'i',
+    ];
+
+    #[test]
+    fn test_encode_matches_kcp() {
+        let mut buf = BytesMut::with_capacity(64);
+        segment().encode(&mut buf, WireFormat::Kcp);
+        assert_eq!(&buf[..], &KCP_SEGMENT[..]);
+
+        let header = SegmentHeader::decode(&KCP_SEGMENT, WireFormat::Kcp);
+        assert_eq!(header.session_id, 0x0102_0304);
+        assert_eq!(header.window_size, 0x0080);
+        assert_eq!(header.timestamp, 0x0A0B_0C0D);
+        assert_eq!(header.sequence_num, 7);
+        assert_eq!(header.len, 2);
+    }
+
+    #[test]
+    fn test_mercury_format_is_big_endian() {
+        let mut buf = BytesMut::with_capacity(64);
+        segment().encode(&mut buf, WireFormat::Mercury);
+        assert_eq!(
+            &buf[..],
+            &[
+                0x01, 0x02, 0x03, 0x04, 81, 2, 0x00, 0x80, 0x0A, 0x0B, 0x0C, 0x0D, 0, 0, 0, 7, 0,
+                0, 0, 5, 0, 0, 0, 2, b'h', b'i',
+            ][..]
+        );
+        let header = SegmentHeader::decode(&buf, WireFormat::Mercury);
+        assert_eq!(header.unacked_sequence_num, 5);
+        assert_eq!(header.fragment_id, 2);
+    }
+}
@@ -7,15 +7,76 @@ impl OrderedStream {
     pub fn new() -> Self {
         Self { sequence_num: 0 }
     }
+
+    /// Returns the sequence number for the next message sent on this stream.
+    pub fn next_sequence_num(&mut self) -> u16 {
+        let sequence_num = self.sequence_num;
+        self.sequence_num = self.sequence_num.wrapping_add(1);
+        sequence_num
+    }
 }
 
 #[derive(Clone)]
 pub struct SequencedStream {
     sequence_num: u16,
+    // Newest sequence number received on this stream
+    last_received: Option<u16>,
 }
 
 impl SequencedStream {
     pub fn new() -> Self {
-        Self { sequence_num: 0 }
+        Self {
+            sequence_num: 0,
+            last_received: None,
+        }
+    }
+
+    /// Returns whether a received message is newer than every message received before it, in
+    /// which case it becomes the newest.
+    pub fn accept(&mut self, sequence_num: u16) -> bool {
+        if let Some(last_received) = self.last_received {
+            // Compare within half the sequence space to handle wrapping
+            if sequence_num.wrapping_sub(last_received).wrapping_sub(1) >= u16::MAX / 2 {
+                return false;
+            }
+        }
+        self.last_received = Some(sequence_num);
+        true
+    }
+
+    /// Returns the newest sequence number received on this stream.
+    pub fn last_received(&self) -> Option<u16> {
+        self.last_received
+    }
+
+    /// Returns the sequence number for the next message sent on this stream.
+    pub fn next_sequence_num(&mut self) -> u16 {
+        let sequence_num = self.sequence_num;
+        self.sequence_num = self.sequence_num.wrapping_add(1);
+        sequence_num
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SequencedStream;
+
+    #[test]
+    fn test_sequenced_accepts_only_newer() {
+        let mut stream = SequencedStream::new();
+        let accepted: Vec<u16> = [1, 4, 3, 2, 4, 5]
+            .iter()
+            .cloned()
+            .filter(|&sequence_num| stream.accept(sequence_num))
+            .collect();
+        assert_eq!(accepted, vec![1, 4, 5]);
+    }
+
+    #[test]
+    fn test_sequenced_accepts_across_wrap() {
+        let mut stream = SequencedStream::new();
+        assert!(stream.accept(u16::MAX - 1));
+        assert!(stream.accept(1));
+        assert!(!stream.accept(u16::MAX));
     }
 }
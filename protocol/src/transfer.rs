@@ -0,0 +1,263 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::io::Cursor;
+
+// transfer id: 4 bytes, total length of the transfer: 4 bytes, 0 when the transfer was cancelled
+pub(crate) const CHUNK_HEADER_SIZE: usize = 8;
+
+/// Identifies a large message handed to `ReliableConnection::send_large`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TransferId(pub(crate) u32);
+
+/// How far along a large message transfer is.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TransferProgress {
+    /// Number of bytes delivered so far. For outgoing transfers only acknowledged bytes count.
+    pub transferred: usize,
+    /// Total size of the message in bytes.
+    pub total: usize,
+}
+
+impl TransferProgress {
+    #[inline]
+    pub fn is_complete(&self) -> bool {
+        self.transferred == self.total
+    }
+}
+
+/// Called with the progress of an outgoing transfer every time more of it is acknowledged.
+pub type ProgressCallback = Box<dyn FnMut(TransferProgress) + Send>;
+
+// A large message which is fed into the send_queue a window at a time.
+pub(crate) struct OutgoingTransfer {
+    pub(crate) id: TransferId,
+    payload: Bytes,
+    // Number of payload bytes queued as chunks so far
+    queued: usize,
+    // Number of payload bytes acknowledged so far
+    pub(crate) acked: usize,
+    reported: usize,
+    on_progress: Option<ProgressCallback>,
+}
+
+impl OutgoingTransfer {
+    pub(crate) fn new(
+        id: TransferId,
+        payload: Bytes,
+        on_progress: Option<ProgressCallback>,
+    ) -> Self {
+        Self {
+            id,
+            payload,
+            queued: 0,
+            acked: 0,
+            reported: 0,
+            on_progress,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn is_started(&self) -> bool {
+        self.queued > 0
+    }
+
+    #[inline]
+    pub(crate) fn is_fully_queued(&self) -> bool {
+        self.queued == self.payload.len()
+    }
+
+    #[inline]
+    pub(crate) fn is_complete(&self) -> bool {
+        self.acked == self.payload.len()
+    }
+
+    pub(crate) fn progress(&self) -> TransferProgress {
+        TransferProgress {
+            transferred: self.acked,
+            total: self.payload.len(),
+        }
+    }
+
+    /// Encodes the next chunk of at most `max_size` bytes (header included).
+    pub(crate) fn next_chunk(&mut self, max_size: usize) -> BytesMut {
+        let len = (max_size - CHUNK_HEADER_SIZE).min(self.payload.len() - self.queued);
+        let mut chunk = BytesMut::with_capacity(CHUNK_HEADER_SIZE + len);
+        chunk.put_u32_be(self.id.0);
+        chunk.put_u32_be(self.payload.len() as u32);
+        chunk.put_slice(&self.payload[self.queued..self.queued + len]);
+        self.queued += len;
+        chunk
+    }
+
+    /// Takes back the last `len` bytes queued, whose chunks were dropped before being sent.
+    pub(crate) fn requeue(&mut self, len: usize) {
+        self.queued -= len;
+    }
+
+    /// Calls the progress callback if anything new was acknowledged since the last report.
+    pub(crate) fn report_progress(&mut self) {
+        if self.acked == self.reported {
+            return;
+        }
+        self.reported = self.acked;
+        let progress = self.progress();
+        if let Some(on_progress) = self.on_progress.as_mut() {
+            on_progress(progress);
+        }
+    }
+}
+
+/// Encodes the chunk telling the remote to throw away the partially received transfer `id`.
+pub(crate) fn cancel_chunk(id: TransferId) -> BytesMut {
+    let mut chunk = BytesMut::with_capacity(CHUNK_HEADER_SIZE);
+    chunk.put_u32_be(id.0);
+    chunk.put_u32_be(0);
+    chunk
+}
+
+// A large message being reassembled from its chunks.
+pub(crate) struct IncomingTransfer {
+    id: TransferId,
+    total: usize,
+    data: BytesMut,
+}
+
+impl IncomingTransfer {
+    pub(crate) fn progress(&self) -> TransferProgress {
+        TransferProgress {
+            transferred: self.data.len(),
+            total: self.total,
+        }
+    }
+}
+
+/// The outcome of feeding a chunk to `receive_chunk`.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Chunk {
+    Partial,
+    Complete(Bytes),
+    Cancelled,
+    Invalid,
+}
+
+/// Appends a received chunk to the transfer in progress, or starts a new transfer if there is
+/// none. Chunks of any other transfer are refused without touching the one in progress, and so
+/// are transfers larger than `max_size` bytes. The buffer grows as the chunks arrive rather than
+/// trusting the total announced by the remote.
+pub(crate) fn receive_chunk(
+    incoming: &mut Option<IncomingTransfer>,
+    chunk: &[u8],
+    max_size: usize,
+) -> Chunk {
+    if chunk.len() < CHUNK_HEADER_SIZE {
+        return Chunk::Invalid;
+    }
+    let mut cursor = Cursor::new(chunk);
+    let id = TransferId(cursor.get_u32_be());
+    let total = cursor.get_u32_be() as usize;
+    let transfer = match incoming {
+        Some(transfer) if transfer.id != id => return Chunk::Invalid,
+        Some(_) if total == 0 => {
+            *incoming = None;
+            return Chunk::Cancelled;
+        }
+        Some(transfer) => transfer,
+        None if total == 0 || total > max_size => return Chunk::Invalid,
+        None => incoming.get_or_insert(IncomingTransfer {
+            id,
+            total,
+            data: BytesMut::new(),
+        }),
+    };
+    let data = &chunk[CHUNK_HEADER_SIZE..];
+    if transfer.total != total || transfer.data.len() + data.len() > total {
+        return Chunk::Invalid;
+    }
+    transfer.data.extend_from_slice(data);
+    if transfer.data.len() < total {
+        return Chunk::Partial;
+    }
+    match incoming.take() {
+        Some(transfer) => Chunk::Complete(transfer.data.freeze()),
+        None => Chunk::Invalid,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{cancel_chunk, receive_chunk, Chunk, OutgoingTransfer, TransferId};
+    use bytes::Bytes;
+
+    #[test]
+    fn test_chunks_reassemble_to_payload() {
+        let payload: Vec<u8> = (0..100).collect();
+        let mut transfer = OutgoingTransfer::new(TransferId(0), Bytes::from(payload.clone()), None);
+        let mut incoming = None;
+        let mut received = None;
+        while !transfer.is_fully_queued() {
+            let chunk = transfer.next_chunk(36);
+            assert!(chunk.len() <= 36);
+            match receive_chunk(&mut incoming, &chunk, 100) {
+                Chunk::Partial => {}
+                Chunk::Complete(data) => received = Some(data),
+                chunk => panic!("unexpected chunk {:?}", chunk),
+            }
+        }
+        assert_eq!(received.unwrap(), Bytes::from(payload));
+        assert!(incoming.is_none());
+    }
+
+    #[test]
+    fn test_chunk_of_other_transfer_keeps_the_one_in_progress() {
+        let mut first = OutgoingTransfer::new(TransferId(0), Bytes::from(vec![0; 100]), None);
+        // Same length, only the id tells them apart
+        let mut second = OutgoingTransfer::new(TransferId(1), Bytes::from(vec![1; 100]), None);
+        let mut incoming = None;
+        assert_eq!(
+            receive_chunk(&mut incoming, &first.next_chunk(60), 100),
+            Chunk::Partial
+        );
+        assert_eq!(
+            receive_chunk(&mut incoming, &second.next_chunk(60), 100),
+            Chunk::Invalid
+        );
+        assert_eq!(
+            receive_chunk(&mut incoming, &cancel_chunk(TransferId(1)), 100),
+            Chunk::Invalid
+        );
+        assert_eq!(incoming.as_ref().unwrap().progress().transferred, 52);
+
+        assert_eq!(
+            receive_chunk(&mut incoming, &first.next_chunk(60), 100),
+            Chunk::Complete(Bytes::from(vec![0; 100]))
+        );
+    }
+
+    #[test]
+    fn test_cancel_chunk_discards_the_transfer() {
+        let mut transfer = OutgoingTransfer::new(TransferId(3), Bytes::from(vec![0; 100]), None);
+        let mut incoming = None;
+        assert_eq!(
+            receive_chunk(&mut incoming, &transfer.next_chunk(60), 100),
+            Chunk::Partial
+        );
+        assert_eq!(
+            receive_chunk(&mut incoming, &cancel_chunk(TransferId(3)), 100),
+            Chunk::Cancelled
+        );
+        assert!(incoming.is_none());
+    }
+
+    #[test]
+    fn test_chunk_of_too_large_transfer_is_invalid() {
+        // A header claiming a 4 GiB transfer
+        let chunk = [0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 1, 2, 3];
+        let mut incoming = None;
+        assert_eq!(receive_chunk(&mut incoming, &chunk, 1_024), Chunk::Invalid);
+        let mut transfer = OutgoingTransfer::new(TransferId(0), Bytes::from(vec![0; 100]), None);
+        assert_eq!(
+            receive_chunk(&mut incoming, &transfer.next_chunk(36), 99),
+            Chunk::Invalid
+        );
+        assert!(incoming.is_none());
+    }
+}
@@ -0,0 +1,217 @@
+use crate::segment::Segment;
+use std::mem;
+
+/// The segments of a send or receive window, stored in a ring indexed by sequence number so that
+/// finding, inserting and removing a segment doesn't depend on the size of the window.
+///
+/// The ring grows when a segment doesn't fit in it, which only happens when the window size is
+/// raised, so its capacity should be the window size up front.
+pub(crate) struct SegmentWindow {
+    slots: Box<[Option<Segment>]>,
+    // Sequence numbers of the first segment and one past the last one, the segments in between
+    // may be missing
+    start: u32,
+    end: u32,
+    len: usize,
+}
+
+impl SegmentWindow {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            slots: empty_slots(capacity.max(1).next_power_of_two()),
+            start: 0,
+            end: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn contains(&self, sequence_num: u32) -> bool {
+        self.get(sequence_num).is_some()
+    }
+
+    pub fn get(&self, sequence_num: u32) -> Option<&Segment> {
+        if !self.in_range(sequence_num) {
+            return None;
+        }
+        self.slots[self.index(sequence_num)].as_ref()
+    }
+
+    /// Returns the segment with the lowest sequence number.
+    pub fn front(&self) -> Option<&Segment> {
+        self.get(self.start)
+    }
+
+    /// Adds a segment, or hands it back if there already is one with its sequence number.
+    pub fn insert(&mut self, segment: Segment) -> Result<(), Segment> {
+        let sequence_num = segment.sequence_num;
+        if self.contains(sequence_num) {
+            return Err(segment);
+        }
+
+        if self.is_empty() {
+            self.start = sequence_num;
+            self.end = sequence_num + 1;
+        } else {
+            let start = self.start.min(sequence_num);
+            let end = self.end.max(sequence_num + 1);
+            let span = (end - start) as usize;
+            if span > self.slots.len() {
+                self.grow(span.next_power_of_two());
+            }
+            self.start = start;
+            self.end = end;
+        }
+
+        let index = self.index(sequence_num);
+        self.slots[index] = Some(segment);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Adds a segment with a higher sequence number than any other.
+    pub fn push_back(&mut self, segment: Segment) {
+        debug_assert!(self.is_empty() || segment.sequence_num >= self.end);
+        let inserted = self.insert(segment).is_ok();
+        debug_assert!(inserted);
+    }
+
+    pub fn remove(&mut self, sequence_num: u32) -> Option<Segment> {
+        if !self.in_range(sequence_num) {
+            return None;
+        }
+        let index = self.index(sequence_num);
+        let segment = self.slots[index].take()?;
+        self.len -= 1;
+
+        if self.is_empty() {
+            self.start = self.end;
+        } else if sequence_num == self.start {
+            while self.slots[self.index(self.start)].is_none() {
+                self.start += 1;
+            }
+        } else if sequence_num + 1 == self.end {
+            while self.slots[self.index(self.end - 1)].is_none() {
+                self.end -= 1;
+            }
+        }
+        Some(segment)
+    }
+
+    pub fn pop_front(&mut self) -> Option<Segment> {
+        if self.is_empty() {
+            return None;
+        }
+        self.remove(self.start)
+    }
+
+    /// Iterates over the segments in sequence number order.
+    pub fn iter(&self) -> impl Iterator<Item = &Segment> {
+        let (wrapped, tail) = self.slots.split_at(self.index(self.start));
+        tail.iter()
+            .chain(wrapped.iter())
+            .take(self.span())
+            .filter_map(Option::as_ref)
+    }
+
+    /// Iterates over the segments in sequence number order.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Segment> {
+        let span = self.span();
+        let first = self.index(self.start);
+        let (wrapped, tail) = self.slots.split_at_mut(first);
+        tail.iter_mut()
+            .chain(wrapped.iter_mut())
+            .take(span)
+            .filter_map(Option::as_mut)
+    }
+
+    fn span(&self) -> usize {
+        (self.end - self.start) as usize
+    }
+
+    fn in_range(&self, sequence_num: u32) -> bool {
+        sequence_num >= self.start && sequence_num < self.end
+    }
+
+    fn index(&self, sequence_num: u32) -> usize {
+        // The capacity is a power of two
+        sequence_num as usize & (self.slots.len() - 1)
+    }
+
+    fn grow(&mut self, capacity: usize) {
+        let old_slots = mem::replace(&mut self.slots, empty_slots(capacity));
+        for segment in old_slots.into_vec().into_iter().flatten() {
+            let index = self.index(segment.sequence_num);
+            self.slots[index] = Some(segment);
+        }
+    }
+}
+
+fn empty_slots(capacity: usize) -> Box<[Option<Segment>]> {
+    (0..capacity).map(|_| None).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::SegmentWindow;
+    use crate::segment::Segment;
+
+    fn segment(sequence_num: u32) -> Segment {
+        Segment {
+            sequence_num,
+            ..Segment::default()
+        }
+    }
+
+    fn sequence_nums(window: &SegmentWindow) -> Vec<u32> {
+        window.iter().map(|segment| segment.sequence_num).collect()
+    }
+
+    #[test]
+    fn test_segments_kept_in_sequence_order() {
+        let mut window = SegmentWindow::new(4);
+        for sequence_num in [6, 4, 5, 7].iter() {
+            assert!(window.insert(segment(*sequence_num)).is_ok());
+        }
+        assert!(window.insert(segment(5)).is_err());
+        assert_eq!(sequence_nums(&window), vec![4, 5, 6, 7]);
+
+        assert_eq!(window.remove(5).unwrap().sequence_num, 5);
+        assert!(window.remove(5).is_none());
+        assert_eq!(window.pop_front().unwrap().sequence_num, 4);
+        assert_eq!(window.front().unwrap().sequence_num, 6);
+        assert_eq!(window.len(), 2);
+
+        // Wraps around the ring
+        window.push_back(segment(8));
+        window.push_back(segment(9));
+        assert_eq!(sequence_nums(&window), vec![6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn test_grows_to_fit_a_larger_window() {
+        let mut window = SegmentWindow::new(2);
+        window.push_back(segment(10));
+        window.push_back(segment(14));
+        assert!(window.insert(segment(11)).is_ok());
+        assert_eq!(sequence_nums(&window), vec![10, 11, 14]);
+        for segment in window.iter_mut() {
+            segment.xmit += 1;
+        }
+        assert_eq!(window.get(14).unwrap().xmit, 1);
+
+        assert!(window.remove(14).is_some());
+        assert!(window.remove(10).is_some());
+        assert_eq!(sequence_nums(&window), vec![11]);
+        assert!(window.pop_front().is_some());
+        assert!(window.is_empty());
+        assert!(window.front().is_none());
+    }
+}
@@ -0,0 +1,134 @@
+//! Checks that a connection doesn't allocate once it reached its steady state. This lives in its
+//! own test binary because it needs to install a global allocator.
+
+use mercury_protocol::ReliableConnection;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    io::{self, Read, Write},
+};
+
+// Counts the allocations made by the current thread.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn count_allocation() {
+    let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+}
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(Cell::get)
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count_allocation();
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count_allocation();
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// The packets written by a connection since they were last delivered. The storage is reused.
+#[derive(Default)]
+struct Wire(Vec<u8>);
+
+impl Write for Wire {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn deliver(from: &mut ReliableConnection<Wire>, to: &mut ReliableConnection<Wire>) {
+    let packets = &mut from.output_mut().0;
+    if !packets.is_empty() {
+        to.input(packets).unwrap();
+        packets.clear();
+    }
+}
+
+#[test]
+fn test_no_allocations_per_tick_after_warmup() {
+    let mut client = ReliableConnection::new(1, Wire::default());
+    let mut server = ReliableConnection::new(1, Wire::default());
+    let payloads: [&[u8]; 3] = [&[1; 16], &[2; 600], &[3; 3_000]];
+    let mut buffer = [0; 4_096];
+    let mut received = 0;
+
+    let mut tick = |step: u32| {
+        for payload in payloads.iter() {
+            client.send(payload).unwrap();
+        }
+        client.update(step * 100).unwrap();
+        deliver(&mut client, &mut server);
+        server.update(step * 100).unwrap();
+        deliver(&mut server, &mut client);
+        while let Ok(len) = server.recv(&mut buffer) {
+            assert_eq!(buffer[len - 1], buffer[0]);
+            received += 1;
+        }
+    };
+
+    for step in 0..100 {
+        tick(step);
+    }
+    let before = allocations();
+    for step in 100..1_100 {
+        tick(step);
+    }
+    assert_eq!(allocations() - before, 0);
+    assert_eq!(received, 3 * 1_100);
+}
+
+#[test]
+fn test_no_allocations_per_tick_when_streaming() {
+    let mut client = ReliableConnection::new(1, Wire::default());
+    let mut server = ReliableConnection::new(1, Wire::default());
+    client.set_streaming_mode(true);
+    server.set_streaming_mode(true);
+    let payloads: [&[u8]; 3] = [&[1; 16], &[2; 600], &[3; 2_384]];
+    // Smaller than what arrives per tick, so that segments are also read partially
+    let mut buffer = [0; 1_000];
+    let mut received = 0;
+
+    let mut tick = |step: u32| {
+        for payload in payloads.iter() {
+            client.write_all(payload).unwrap();
+        }
+        client.update(step * 100).unwrap();
+        deliver(&mut client, &mut server);
+        server.update(step * 100).unwrap();
+        deliver(&mut server, &mut client);
+        while let Ok(len) = server.read(&mut buffer) {
+            received += len;
+        }
+    };
+
+    for step in 0..100 {
+        tick(step);
+    }
+    let before = allocations();
+    for step in 100..1_100 {
+        tick(step);
+    }
+    assert_eq!(allocations() - before, 0);
+    assert_eq!(received, 3_000 * 1_100);
+}